@@ -1,4 +0,0 @@
-#!/bin/bash
-# scripts/generate_master_key.sh
-
-openssl rand -hex 32
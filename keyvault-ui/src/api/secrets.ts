@@ -1,9 +1,14 @@
 import { useAuthStore } from '../stores/useAuthStore';
+import { SearchPage } from '../types/types';
 
 // const API_BASE = import.meta.env.VITE_API_URL || 'http://localhost:4444';
 const API_BASE = 'http://localhost:4444';
 
-export async function searchSecrets(query: string, projectKey?: string) {
+export async function searchSecrets(
+  query: string,
+  projectKey?: string,
+  cursor?: string | null,
+): Promise<SearchPage> {
   const { apiKey, projectKey: defaultKey } = useAuthStore.getState();
   const res = await fetch(`${API_BASE}/search`, {
     method: "POST",
@@ -12,7 +17,8 @@ export async function searchSecrets(query: string, projectKey?: string) {
       "x-api-key": apiKey,
       "x-project-key": projectKey || defaultKey,
     },
-    body: JSON.stringify({ query }),      // <-- sends a string now
+    // values are fetched one at a time, when a secret is opened
+    body: JSON.stringify({ query, cursor, fields: "keys" }),
   });
   if (!res.ok) throw new Error("Failed to search secrets");
   return res.json();
@@ -29,10 +35,23 @@ export async function getSecret(secretKey: string, projectKey?: string | null) {
   });
 
   if (!res.ok) throw new Error('Failed to fetch secret');
-  return await res.json();
+  // the ETag names the version, for saving edits with If-Match
+  return { secret_value: await res.json(), etag: res.headers.get('ETag') };
 }
 
-export async function upsertSecret(secretKey: string, secretValue: any, projectKey?: string) {
+/** Thrown when someone else saved the secret since it was loaded. */
+export class SecretChangedError extends Error {}
+
+/**
+ * Save a secret. With an `etag` the save only succeeds if the secret is
+ * still at that version; without one it only creates.
+ */
+export async function upsertSecret(
+  secretKey: string,
+  secretValue: any,
+  projectKey?: string,
+  etag?: string | null,
+) {
   const { apiKey, projectKey: defaultProjectKey } = useAuthStore.getState();
 
   const response = await fetch(`${API_BASE}/secrets/${secretKey}`, {
@@ -41,10 +60,12 @@ export async function upsertSecret(secretKey: string, secretValue: any, projectK
       "Content-Type": "application/json",
       "x-api-key": apiKey,
       "x-project-key": projectKey || defaultProjectKey,
+      ...(etag ? { "If-Match": etag } : { "If-None-Match": "*" }),
     },
     body: JSON.stringify({ value: secretValue }),
   });
 
+  if (response.status === 412) throw new SecretChangedError();
   if (!response.ok) throw new Error("Failed to upsert secret");
 }
 
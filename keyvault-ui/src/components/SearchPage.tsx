@@ -19,6 +19,7 @@ export function SearchPage({ initialProjectKey }: SearchPageProps) {
   const [adding, setAdding] = useState(false);
   const [query, setQuery] = useState("");
   const [results, setResults] = useState<Secret[]>([]);
+  const [nextCursor, setNextCursor] = useState<string | null>(null);
   const [loading, setLoading] = useState(false);
   const [error, setError] = useState<string | null>(null);
 
@@ -43,8 +44,25 @@ export function SearchPage({ initialProjectKey }: SearchPageProps) {
     setLoading(true);
     setError(null);
     try {
-      const data = await searchSecrets(query || "", projectKey);
-      setResults(data);
+      const page = await searchSecrets(query || "", projectKey);
+      setResults(page.items);
+      setNextCursor(page.next_cursor);
+    } catch (err: any) {
+      console.error(err);
+      setError(err.message || "Server error.");
+    } finally {
+      setLoading(false);
+    }
+  };
+
+  const handleLoadMore = async () => {
+    if (!nextCursor) return;
+    setLoading(true);
+    setError(null);
+    try {
+      const page = await searchSecrets(query || "", projectKey, nextCursor);
+      setResults((prev) => [...prev, ...page.items]);
+      setNextCursor(page.next_cursor);
     } catch (err: any) {
       console.error(err);
       setError(err.message || "Server error.");
@@ -90,6 +108,7 @@ export function SearchPage({ initialProjectKey }: SearchPageProps) {
             projectKey={editingSecret.projectKey ?? projectKey}
             initialSecretKey={editingSecret.secretKey}
             initialSecretValue={JSON.stringify(editingData.secret_value, null, 2)}
+            etag={editingData.etag}
             onSuccess={() => {
               setEditingSecret(null);
               setEditingData(null);
@@ -155,6 +174,14 @@ export function SearchPage({ initialProjectKey }: SearchPageProps) {
               />
             ))}
           </div>
+          {nextCursor && (
+            <button
+              onClick={handleLoadMore}
+              className="border rounded px-4 py-2 hover:bg-gray-100"
+            >
+              Load more
+            </button>
+          )}
           <button
             onClick={() => setAdding(true)}
             className="bg-green-600 text-white rounded px-4 py-2 hover:bg-green-700"
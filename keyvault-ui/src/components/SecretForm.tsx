@@ -1,16 +1,18 @@
 import { useState } from "react";
-import { upsertSecret } from "../api/secrets";
+import { SecretChangedError, upsertSecret } from "../api/secrets";
 
 
 interface SecretFormProps {
   projectKey?: string | null;
   initialSecretKey?: string;
   initialSecretValue?: string;
+  /** ETag of the version being edited */
+  etag?: string | null;
   onSuccess: () => void;
   onCancel: () => void;
 }
 
-export function SecretForm({ projectKey, onSuccess, onCancel, initialSecretKey, initialSecretValue }: SecretFormProps) {
+export function SecretForm({ projectKey, onSuccess, onCancel, initialSecretKey, initialSecretValue, etag }: SecretFormProps) {
   const [localProjectKey, setLocalProjectKey] = useState(projectKey || "");
   const [secretKey, setSecretKey] = useState(initialSecretKey || "");
   const [secretValue, setSecretValue] = useState(initialSecretValue || "{}");
@@ -24,11 +26,17 @@ export function SecretForm({ projectKey, onSuccess, onCancel, initialSecretKey,
     setError(null);
 
     try {
-      await upsertSecret(secretKey, JSON.parse(secretValue), localProjectKey);
+      await upsertSecret(secretKey, JSON.parse(secretValue), localProjectKey, etag);
       onSuccess();
     } catch (err) {
       console.error(err);
-      setError("Invalid input or server error.");
+      if (err instanceof SecretChangedError) {
+        setError(isEdit
+          ? "Someone else changed this secret. Reload it before saving."
+          : "A secret with this key already exists.");
+      } else {
+        setError("Invalid input or server error.");
+      }
     } finally {
       setSaving(false);
     }
@@ -42,7 +50,8 @@ export function SecretForm({ projectKey, onSuccess, onCancel, initialSecretKey,
           placeholder="Project Key"
           value={localProjectKey}
           onChange={(e) => setLocalProjectKey(e.target.value)}
-          className="border rounded p-2 w-full"
+          disabled={isEdit}
+          className="border rounded p-2 w-full disabled:bg-gray-100"
         />
       )}
       <input
@@ -50,7 +59,9 @@ export function SecretForm({ projectKey, onSuccess, onCancel, initialSecretKey,
         placeholder="Secret Key"
         value={secretKey}
         onChange={(e) => setSecretKey(e.target.value)}
-        className="border rounded p-2 w-full"
+        // the etag belongs to this key, so an edit cannot rename it
+        disabled={isEdit}
+        className="border rounded p-2 w-full disabled:bg-gray-100"
       />
       <textarea
         placeholder="Secret Value (JSON)"
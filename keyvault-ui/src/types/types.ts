@@ -2,4 +2,16 @@ export interface Secret {
   secret_key: string;
   project_key?: string | null;
   secret_value?: any;
-}
\ No newline at end of file
+  version?: number;
+  updated_at?: string;
+  expires_at?: string;
+  size?: number;
+  content_type?: string;
+}
+
+/** One page of `/search` results; pass `next_cursor` back for the next. */
+export interface SearchPage {
+  items: Secret[];
+  next_cursor: string | null;
+  total?: number;
+}
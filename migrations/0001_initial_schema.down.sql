@@ -0,0 +1,23 @@
+-- On a database from before version history this migration moved the old
+-- `secrets` table aside as `legacy_secrets`, after `keyvault migrate up`
+-- sealed its values, which the binaries of that time cannot read. There it
+-- is irreversible; restore a backup instead.
+DO $$
+BEGIN
+  IF to_regclass('legacy_secrets') IS NOT NULL THEN
+    RAISE EXCEPTION 'migration 0001 cannot be reverted on a database from before version history'
+      USING HINT = 'Restore the backup taken before `keyvault migrate up`';
+  END IF;
+END
+$$;
+
+-- The group roles are cluster-wide and may serve other databases; they stay.
+REVOKE SELECT ON _sqlx_migrations FROM secrets_reader, secrets_writer;
+REVOKE USAGE ON SCHEMA public FROM secrets_reader, secrets_writer;
+
+DROP TABLE vault_meta;
+DROP TABLE audit_events;
+DROP TABLE tokens;
+DROP VIEW secrets;
+DROP TABLE secret_versions;
+
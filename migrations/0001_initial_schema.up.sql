@@ -0,0 +1,119 @@
+-- Group roles holding the privileges; `keyvault migrate up` grants them to
+-- the configured login users. Roles are cluster-wide, so they may exist.
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = 'secrets_reader') THEN
+    CREATE ROLE secrets_reader;
+  END IF;
+  IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = 'secrets_writer') THEN
+    CREATE ROLE secrets_writer;
+  END IF;
+END
+$$;
+
+-- Databases from before versioning have a `secrets` table of one row per
+-- key. Move it aside; its rows become version 1 below. Plaintext values must
+-- be sealed first, which `keyvault migrate up` does before any migration.
+DO $$
+BEGIN
+  IF EXISTS (SELECT 1 FROM pg_class
+              WHERE oid = to_regclass('secrets') AND relkind = 'r') THEN
+    IF EXISTS (SELECT 1 FROM pg_attribute
+                WHERE attrelid = to_regclass('secrets')
+                  AND attname = 'secret_value' AND NOT attisdropped) THEN
+      RAISE EXCEPTION 'secrets holds plaintext values'
+        USING HINT = 'Run `keyvault migrate up`, which seals them first';
+    END IF;
+    ALTER TABLE secrets RENAME TO legacy_secrets;
+  END IF;
+END
+$$;
+
+-- Every write appends a version; values are sealed (see crypto.rs)
+CREATE TABLE secret_versions (
+    project_key TEXT NOT NULL,
+    secret_key TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    ciphertext BYTEA,
+    wrapped_key BYTEA,
+    key_id TEXT,
+    deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    created_by TEXT NOT NULL,
+    PRIMARY KEY (project_key, secret_key, version)
+);
+
+DO $$
+BEGIN
+  IF to_regclass('legacy_secrets') IS NOT NULL THEN
+    INSERT INTO secret_versions
+                (project_key, secret_key, version, ciphertext, wrapped_key,
+                 key_id, created_by)
+         SELECT project_key, secret_key, 1, ciphertext, wrapped_key, key_id,
+                'keyvault migrate'
+           FROM legacy_secrets;
+  END IF;
+END
+$$;
+
+-- Latest live version of each secret
+CREATE VIEW secrets AS
+SELECT * FROM (
+    SELECT DISTINCT ON (project_key, secret_key) *
+      FROM secret_versions
+     ORDER BY project_key, secret_key, version DESC
+) latest
+WHERE NOT deleted;
+
+CREATE TABLE tokens (
+    id BIGSERIAL PRIMARY KEY,
+    label TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    project_scope TEXT NOT NULL,
+    permissions TEXT[] NOT NULL,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ,
+    rotated_from BIGINT REFERENCES tokens (id)
+);
+
+CREATE TABLE audit_events (
+    id BIGSERIAL PRIMARY KEY,
+    occurred_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    token_id BIGINT,
+    actor TEXT,
+    project_key TEXT,
+    secret_key TEXT,
+    action TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    client_ip TEXT,
+    request_id TEXT
+);
+
+CREATE TABLE vault_meta (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+GRANT USAGE ON SCHEMA public TO secrets_reader, secrets_writer;
+-- both roles check the schema version at startup
+GRANT SELECT ON _sqlx_migrations TO secrets_reader, secrets_writer;
+
+GRANT SELECT ON secrets, secret_versions TO secrets_reader;
+-- history is append-only: no UPDATE or DELETE for the writer
+GRANT SELECT ON secrets TO secrets_writer;
+GRANT SELECT, INSERT ON secret_versions TO secrets_writer;
+
+GRANT SELECT ON tokens TO secrets_reader;
+-- tokens are revoked or expired, never deleted
+GRANT SELECT, INSERT, UPDATE ON tokens TO secrets_writer;
+GRANT USAGE ON SEQUENCE tokens_id_seq TO secrets_writer;
+
+-- the audit log is append-only as well
+GRANT SELECT ON audit_events TO secrets_reader;
+GRANT INSERT ON audit_events TO secrets_writer;
+GRANT USAGE ON SEQUENCE audit_events_id_seq TO secrets_writer;
+
+GRANT SELECT ON vault_meta TO secrets_reader;
+GRANT SELECT, INSERT ON vault_meta TO secrets_writer;
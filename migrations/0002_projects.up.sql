@@ -0,0 +1,17 @@
+-- Project metadata. Writing a secret registers its project, so existing
+-- projects are backfilled from the history.
+CREATE TABLE projects (
+    project_key TEXT PRIMARY KEY,
+    description TEXT NOT NULL DEFAULT '',
+    owner TEXT,
+    labels JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO projects (project_key, created_at)
+     SELECT project_key, MIN(created_at)
+       FROM secret_versions
+      GROUP BY project_key;
+
+GRANT SELECT ON projects TO secrets_reader;
+GRANT SELECT, INSERT, UPDATE, DELETE ON projects TO secrets_writer;
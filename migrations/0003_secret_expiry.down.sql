@@ -0,0 +1,12 @@
+DROP VIEW secrets;
+ALTER TABLE secret_versions DROP COLUMN expires_at;
+
+CREATE VIEW secrets AS
+SELECT * FROM (
+    SELECT DISTINCT ON (project_key, secret_key) *
+      FROM secret_versions
+     ORDER BY project_key, secret_key, version DESC
+) latest
+WHERE NOT deleted;
+
+GRANT SELECT ON secrets TO secrets_reader, secrets_writer;
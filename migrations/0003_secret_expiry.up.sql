@@ -0,0 +1,16 @@
+-- Optional expiry per version. An expired secret stays in `secrets` until
+-- the reaper tombstones it, so reads can tell it apart from a missing one.
+ALTER TABLE secret_versions ADD COLUMN expires_at TIMESTAMPTZ;
+
+-- `*` was expanded when the view was created; this appends the new column
+CREATE OR REPLACE VIEW secrets AS
+SELECT * FROM (
+    SELECT DISTINCT ON (project_key, secret_key) *
+      FROM secret_versions
+     ORDER BY project_key, secret_key, version DESC
+) latest
+WHERE NOT deleted;
+
+CREATE INDEX secret_versions_expires_at
+    ON secret_versions (expires_at)
+ WHERE expires_at IS NOT NULL AND NOT deleted;
@@ -0,0 +1,15 @@
+-- Size and JSON type of each version's value, kept in plaintext so key
+-- listings never decrypt. `keyvault migrate up` fills them in for versions
+-- written before this migration; tombstones have neither.
+ALTER TABLE secret_versions
+    ADD COLUMN value_size INTEGER,
+    ADD COLUMN content_type TEXT;
+
+-- `*` was expanded when the view was created; this appends the new columns
+CREATE OR REPLACE VIEW secrets AS
+SELECT * FROM (
+    SELECT DISTINCT ON (project_key, secret_key) *
+      FROM secret_versions
+     ORDER BY project_key, secret_key, version DESC
+) latest
+WHERE NOT deleted;
@@ -0,0 +1,292 @@
+use axum::{
+  extract::{
+    ConnectInfo, Extension, FromRequestParts, Json, MatchedPath, Query,
+    RawPathParams, Request,
+  },
+  http::{Method, StatusCode, request::Parts},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+  convert::Infallible,
+  net::{IpAddr, SocketAddr},
+  sync::{Arc, Mutex},
+};
+
+use crate::{
+  AdminAuth, AppState,
+  auth::{Principal, glob_to_like},
+};
+
+/// Per-request audit fields. Extractors and handlers fill them in as they
+/// learn who is calling and which secret is touched; [`record`] writes them
+/// once the response status is known. Never put secret values in here.
+#[derive(Clone, Default)]
+pub struct AuditTrail(Arc<Mutex<AuditFields>>);
+
+#[derive(Default)]
+struct AuditFields {
+  token_id: Option<i64>,
+  actor: Option<String>,
+  project: Option<String>,
+  key: Option<String>,
+  /// Replaces the name derived from the route
+  action: Option<String>,
+}
+
+impl AuditTrail {
+  pub fn set_principal(&self, principal: &Principal) {
+    let mut fields = self.0.lock().unwrap();
+    fields.token_id = principal.token_id;
+    fields.actor = Some(principal.label.clone());
+  }
+
+  pub fn set_project(&self, project: &str) {
+    self.0.lock().unwrap().project = Some(project.to_string());
+  }
+
+  pub fn set_key(&self, key: &str) {
+    self.0.lock().unwrap().key = Some(key.to_string());
+  }
+
+  /// For routes that serve more than one action, e.g. copy and move.
+  pub fn set_action(&self, action: &str) {
+    self.0.lock().unwrap().action = Some(action.to_string());
+  }
+}
+
+/// Handlers outside the audited router get a detached trail.
+impl<S> FromRequestParts<S> for AuditTrail
+where
+  S: Send + Sync + 'static,
+{
+  type Rejection = Infallible;
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    _: &S,
+  ) -> Result<Self, Self::Rejection> {
+    Ok(
+      parts
+        .extensions
+        .get::<AuditTrail>()
+        .cloned()
+        .unwrap_or_default(),
+    )
+  }
+}
+
+/// Name recorded for a route, e.g. `read` for `GET /secrets/{*key}`.
+fn action(method: &Method, path: &str) -> String {
+  let name = match (method.as_str(), path) {
+    ("GET", "/secrets/{*key}") => "read",
+    ("PUT", "/secrets/{*key}") | ("POST", "/secrets") => "write",
+    ("GET", "/secrets") => "list",
+    ("DELETE", "/secrets/{*key}") => "delete",
+    ("POST", "/secrets/{*key}") => "rollback",
+    ("POST", "/search") => "search",
+    ("GET", "/tree") | ("GET", "/tree/{*path}") => "list_tree",
+    ("POST", "/tree/{*path}") => "copy_tree",
+    ("DELETE", "/tree/{*path}") => "delete_tree",
+    ("GET", "/projects") => "list_projects",
+    ("POST", "/projects") => "create_project",
+    ("PATCH", "/projects/{project}") => "update_project",
+    ("DELETE", "/projects/{project}") => "delete_project",
+    ("POST", "/projects/{project}/import") => "import",
+    ("GET", "/projects/{project}/export") => "export",
+    ("GET", "/audit") => "audit",
+    ("POST", "/tokens") => "create_token",
+    ("GET", "/tokens") => "list_tokens",
+    ("DELETE", "/tokens/{id}") => "revoke_token",
+    ("POST", "/tokens/{id}/rotate") => "rotate_token",
+    _ => return format!("{} {}", method, path),
+  };
+  name.to_string()
+}
+
+fn outcome(status: StatusCode) -> &'static str {
+  match status.as_u16() {
+    200..=399 => "success",
+    401 => "unauthorized",
+    403 => "forbidden",
+    404 => "not_found",
+    400..=499 => "rejected",
+    _ => "error",
+  }
+}
+
+/// The connecting peer, or, while that is a trusted proxy, the hop it says
+/// it forwarded for. Addresses left of the first untrusted hop are whatever
+/// the client chose to send and are ignored.
+fn client_ip(req: &Request, trusted_proxies: &[IpAddr]) -> Option<String> {
+  let ConnectInfo(peer) = req.extensions().get::<ConnectInfo<SocketAddr>>()?;
+  let mut client = peer.ip();
+  let forwarded = req
+    .headers()
+    .get_all("x-forwarded-for")
+    .iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .collect::<Vec<_>>();
+  for hop in forwarded.iter().rev() {
+    if !trusted_proxies.contains(&client) {
+      break;
+    }
+    match hop.trim().parse() {
+      Ok(ip) => client = ip,
+      Err(_) => break,
+    }
+  }
+  Some(client.to_string())
+}
+
+/// Middleware writing one `audit_events` row per handler call, including
+/// calls rejected by the auth extractors. If the event cannot be stored the
+/// response is replaced by a 500 so no access goes unrecorded.
+pub async fn record(req: Request, next: Next) -> Response {
+  let (Some(path), Some(state)) = (
+    req
+      .extensions()
+      .get::<MatchedPath>()
+      .map(|p| p.as_str().to_owned()),
+    req.extensions().get::<AppState>().cloned(),
+  ) else {
+    return next.run(req).await;
+  };
+
+  let (mut parts, body) = req.into_parts();
+  let trail = AuditTrail::default();
+  if let Some(project) = parts
+    .headers
+    .get("x-project-key")
+    .and_then(|v| v.to_str().ok())
+  {
+    trail.set_project(project);
+  }
+  if let Ok(params) = RawPathParams::from_request_parts(&mut parts, &()).await {
+    for (name, value) in &params {
+      match name {
+        "key" | "path" => trail.set_key(value),
+        "project" => trail.set_project(value),
+        _ => {}
+      }
+    }
+  }
+  parts.extensions.insert(trail.clone());
+  let req = Request::from_parts(parts, body);
+
+  let action = action(req.method(), &path);
+  let client_ip = client_ip(&req, &state.config.trusted_proxies);
+  let request_id = req
+    .headers()
+    .get("x-request-id")
+    .and_then(|v| v.to_str().ok())
+    .map(str::to_string);
+
+  let response = next.run(req).await;
+
+  let status = response.status();
+  let sql = match state.queries.get("insert_audit_event") {
+    Ok(q) => q,
+    Err(err) => {
+      tracing::error!("Audit log unavailable: {}", err);
+      return (StatusCode::INTERNAL_SERVER_ERROR, "Audit log unavailable")
+        .into_response();
+    }
+  };
+  let result = {
+    let fields = trail.0.lock().unwrap();
+    sqlx::query(sql)
+      .bind(fields.token_id)
+      .bind(fields.actor.clone())
+      .bind(fields.project.clone())
+      .bind(fields.key.clone())
+      .bind(fields.action.clone().unwrap_or_else(|| action.clone()))
+      .bind(outcome(status))
+      .bind(i32::from(status.as_u16()))
+      .bind(&client_ip)
+      .bind(&request_id)
+  }
+  .execute(&state.write_pool)
+  .await;
+
+  match result {
+    Ok(_) => response,
+    Err(err) => {
+      tracing::error!("Failed to write audit event: {}", err);
+      (StatusCode::INTERNAL_SERVER_ERROR, "Audit log unavailable")
+        .into_response()
+    }
+  }
+}
+
+#[derive(Deserialize)]
+pub struct AuditFilter {
+  pub project: Option<String>,
+  pub key: Option<String>,
+  pub actor: Option<String>,
+  pub action: Option<String>,
+  pub since: Option<chrono::DateTime<chrono::Utc>>,
+  pub until: Option<chrono::DateTime<chrono::Utc>>,
+  pub limit: Option<i64>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct AuditEvent {
+  pub id: i64,
+  pub occurred_at: chrono::DateTime<chrono::Utc>,
+  pub token_id: Option<i64>,
+  pub actor: Option<String>,
+  pub project_key: Option<String>,
+  pub secret_key: Option<String>,
+  pub action: String,
+  pub outcome: String,
+  pub status: i32,
+  pub client_ip: Option<String>,
+  pub request_id: Option<String>,
+}
+
+// GET /audit
+pub async fn list_audit_events(
+  auth: AdminAuth,
+  Query(filter): Query<AuditFilter>,
+  Extension(state): Extension<AppState>,
+) -> impl IntoResponse {
+  let sql = match state.queries.get("list_audit_events") {
+    Ok(q) => q,
+    Err(err) => {
+      return (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Query error: {}", err),
+      )
+        .into_response();
+    }
+  };
+
+  // A scoped admin only sees events in projects it can access, which leaves
+  // out those with no project at all
+  let scope = match auth.principal.project_scope.as_str() {
+    "*" => None,
+    scope => Some(glob_to_like(scope)),
+  };
+  let result: Result<Vec<AuditEvent>, _> = sqlx::query_as(sql)
+    .bind(&filter.project)
+    .bind(&filter.key)
+    .bind(&filter.actor)
+    .bind(&filter.action)
+    .bind(filter.since)
+    .bind(filter.until)
+    .bind(filter.limit.unwrap_or(100).clamp(1, 1000))
+    .bind(scope)
+    .fetch_all(&state.read_pool)
+    .await;
+
+  match result {
+    Ok(events) => (StatusCode::OK, Json(events)).into_response(),
+    Err(err) => {
+      tracing::error!("Database error listing audit events: {}", err);
+      (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response()
+    }
+  }
+}
@@ -0,0 +1,434 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use argon2::{
+  Argon2,
+  password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+};
+use axum::http::{StatusCode, request::Parts};
+use sha2::{Digest, Sha256};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Mutex,
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
+
+use crate::{AppState, audit::AuditTrail};
+
+/// What a token may do. `Admin` implies every other permission and `Read`
+/// implies `List`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+  /// Key names and metadata, never values
+  List,
+  Read,
+  Write,
+  Admin,
+}
+
+impl Permission {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Permission::List => "list",
+      Permission::Read => "read",
+      Permission::Write => "write",
+      Permission::Admin => "admin",
+    }
+  }
+
+  pub fn parse(s: &str) -> Option<Self> {
+    match s {
+      "list" => Some(Permission::List),
+      "read" => Some(Permission::Read),
+      "write" => Some(Permission::Write),
+      "admin" => Some(Permission::Admin),
+      _ => None,
+    }
+  }
+}
+
+/// The authenticated caller behind an `x-api-key`.
+#[derive(Debug, Clone)]
+pub struct Principal {
+  /// Row id in `tokens`, or `None` for the environment master keys
+  pub token_id: Option<i64>,
+  pub label: String,
+  /// Glob of project keys this token may touch (`*` and `?` wildcards)
+  pub project_scope: String,
+  pub permissions: Vec<Permission>,
+}
+
+impl Principal {
+  pub fn has(&self, permission: Permission) -> bool {
+    self.permissions.iter().any(|p| {
+      *p == permission
+        || *p == Permission::Admin
+        || (*p == Permission::Read && permission == Permission::List)
+    })
+  }
+
+  pub fn can_access(&self, project: &str) -> bool {
+    glob_match(&self.project_scope, project)
+  }
+
+  /// Reject requests for a project outside this token's scope.
+  pub fn check_project(
+    &self,
+    project: &str,
+  ) -> Result<(), (StatusCode, &'static str)> {
+    if self.can_access(project) {
+      Ok(())
+    } else {
+      Err((StatusCode::FORBIDDEN, "Project not in token scope"))
+    }
+  }
+}
+
+/// Tokens are random and high-entropy, so a plain SHA-256 is enough to keep
+/// them out of the database while still allowing an indexed lookup.
+pub fn hash_token(token: &str) -> String {
+  hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Match `text` against a glob where `*` is any run of characters and `?` is
+/// exactly one character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+  glob_match_with(pattern, text, |_| true)
+}
+
+/// The `LIKE` pattern matching what the glob `pattern` matches, for
+/// filtering by scope in SQL.
+pub fn glob_to_like(pattern: &str) -> String {
+  let mut like = String::with_capacity(pattern.len());
+  for c in pattern.chars() {
+    match c {
+      '*' => like.push('%'),
+      '?' => like.push('_'),
+      '%' | '_' | '\\' => {
+        like.push('\\');
+        like.push(c);
+      }
+      c => like.push(c),
+    }
+  }
+  like
+}
+
+/// True when every project matched by the glob `inner` is also matched by
+/// `outer`, i.e. a token scoped to `outer` may hand out `inner`.
+pub fn scope_covers(outer: &str, inner: &str) -> bool {
+  // Treat `inner` as text whose wildcards only an outer `*` (or `?` for a
+  // single `?`) can absorb.
+  glob_match_with(outer, inner, |c| c != '*')
+}
+
+fn glob_match_with(
+  pattern: &str,
+  text: &str,
+  any_char: impl Fn(char) -> bool,
+) -> bool {
+  let p: Vec<char> = pattern.chars().collect();
+  let t: Vec<char> = text.chars().collect();
+  let (mut pi, mut ti) = (0, 0);
+  let mut backtrack: Option<(usize, usize)> = None;
+  while ti < t.len() {
+    if pi < p.len() && ((p[pi] == '?' && any_char(t[ti])) || p[pi] == t[ti]) {
+      pi += 1;
+      ti += 1;
+    } else if pi < p.len() && p[pi] == '*' {
+      backtrack = Some((pi, ti));
+      pi += 1;
+    } else if let Some((star, matched)) = backtrack {
+      pi = star + 1;
+      ti = matched + 1;
+      backtrack = Some((star, matched + 1));
+    } else {
+      return false;
+    }
+  }
+  p[pi..].iter().all(|c| *c == '*')
+}
+
+/// 32 random bytes, hex encoded. Used for API keys and the master key alike.
+pub fn random_key() -> String {
+  let mut bytes = [0u8; 32];
+  OsRng.fill_bytes(&mut bytes);
+  hex::encode(bytes)
+}
+
+/// Marks keys issued by `POST /tokens`, which are looked up in the database
+/// without trying the bootstrap keys first.
+pub const TOKEN_PREFIX: &str = "kv_";
+
+/// A fresh API token: a random key behind [`TOKEN_PREFIX`].
+pub fn generate_token() -> String {
+  format!("{}{}", TOKEN_PREFIX, random_key())
+}
+
+/// How a bootstrap key is configured: a `sha256:<hex>` digest or an Argon2
+/// PHC string, or (deprecated) the plaintext key itself.
+pub enum KeyDigest {
+  Sha256(Vec<u8>),
+  Argon2(String),
+  Plain(String),
+}
+
+impl KeyDigest {
+  pub fn parse_hash(encoded: &str) -> Result<Self, String> {
+    let encoded = encoded.trim();
+    if let Some(digest) = encoded.strip_prefix("sha256:") {
+      let bytes = hex::decode(digest).map_err(|e| e.to_string())?;
+      if bytes.len() != 32 {
+        return Err(format!("expected 32 bytes, got {}", bytes.len()));
+      }
+      Ok(KeyDigest::Sha256(bytes))
+    } else if encoded.starts_with("$argon2") {
+      PasswordHash::new(encoded).map_err(|e| e.to_string())?;
+      Ok(KeyDigest::Argon2(encoded.to_string()))
+    } else {
+      Err("expected 'sha256:<hex>' or an Argon2 hash".to_string())
+    }
+  }
+
+  /// Compare a key's SHA-256 `digest` without leaking how much of it
+  /// matched, or `None` for Argon2 hashes, which need the key itself.
+  fn verify_digest(&self, digest: &[u8; 32]) -> Option<bool> {
+    match self {
+      KeyDigest::Sha256(expected) => {
+        Some(digest.ct_eq(expected.as_slice()).into())
+      }
+      KeyDigest::Plain(expected) => {
+        Some(digest.ct_eq(&Sha256::digest(expected.as_bytes())).into())
+      }
+      KeyDigest::Argon2(_) => None,
+    }
+  }
+
+  /// Check `key` against an Argon2 hash off the async runtime, since one
+  /// verification takes tens of milliseconds of CPU.
+  async fn verify_argon2(&self, key: &str) -> bool {
+    let KeyDigest::Argon2(phc) = self else {
+      return false;
+    };
+    let (phc, key) = (phc.clone(), key.to_string());
+    tokio::task::spawn_blocking(move || {
+      PasswordHash::new(&phc).is_ok_and(|hash| {
+        Argon2::default()
+          .verify_password(key.as_bytes(), &hash)
+          .is_ok()
+      })
+    })
+    .await
+    .unwrap_or(false)
+  }
+}
+
+/// Hash an API key for `API_MASTER_KEY_*_HASH`, as SHA-256 or Argon2id.
+pub fn hash_api_key(key: &str, argon2: bool) -> Result<String, String> {
+  if argon2 {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+      .hash_password(key.as_bytes(), &salt)
+      .map(|hash| hash.to_string())
+      .map_err(|e| e.to_string())
+  } else {
+    Ok(format!("sha256:{}", hash_token(key)))
+  }
+}
+
+/// Argon2 checks that may run at once, across all requests
+const MAX_ARGON2_CHECKS: usize = 2;
+
+/// Keys remembered as failing every Argon2 hash before the memory is reset
+const MAX_REJECTED_KEYS: usize = 4096;
+
+/// The `API_MASTER_KEY_*` bootstrap keys. They predate per-project tokens and
+/// stay valid as unscoped credentials.
+pub struct MasterApiKeys {
+  pub admin: Option<KeyDigest>,
+  pub write: Option<KeyDigest>,
+  pub read: Option<KeyDigest>,
+  /// SHA-256 of keys that passed an Argon2 check, with the index of the
+  /// entry they matched, so each key pays for Argon2 only once
+  verified: Mutex<HashMap<[u8; 32], usize>>,
+  /// SHA-256 of keys that failed every Argon2 check, so a wrong key is not
+  /// hashed again
+  rejected: Mutex<HashSet<[u8; 32]>>,
+  /// Bounds the CPU that keys which are not tokens can make us spend
+  argon2_checks: Semaphore,
+}
+
+impl MasterApiKeys {
+  pub fn new(
+    admin: Option<KeyDigest>,
+    write: Option<KeyDigest>,
+    read: Option<KeyDigest>,
+  ) -> Self {
+    MasterApiKeys {
+      admin,
+      write,
+      read,
+      verified: Mutex::default(),
+      rejected: Mutex::default(),
+      argon2_checks: Semaphore::new(MAX_ARGON2_CHECKS),
+    }
+  }
+
+  /// Strongest first, with the label and permissions each key grants.
+  fn entries(&self) -> [(&'static str, &Option<KeyDigest>, &[Permission]); 3] {
+    [
+      ("API_MASTER_KEY_ADMIN", &self.admin, &[Permission::Admin]),
+      (
+        "API_MASTER_KEY_WRITE",
+        &self.write,
+        &[Permission::Read, Permission::Write],
+      ),
+      ("API_MASTER_KEY_READ", &self.read, &[Permission::Read]),
+    ]
+  }
+
+  /// The principal of entry `index` in [`Self::entries`].
+  fn grant(&self, index: usize) -> Principal {
+    let (label, _, permissions) = self.entries()[index];
+    Principal {
+      token_id: None,
+      label: label.to_string(),
+      project_scope: "*".to_string(),
+      permissions: permissions.to_vec(),
+    }
+  }
+
+  /// What the caches know of the key with this SHA-256: `Some(None)` when it
+  /// failed Argon2 before.
+  fn cached(&self, digest: &[u8; 32]) -> Option<Option<Principal>> {
+    if let Some(&index) = self.verified.lock().unwrap().get(digest) {
+      return Some(Some(self.grant(index)));
+    }
+    self
+      .rejected
+      .lock()
+      .unwrap()
+      .contains(digest)
+      .then_some(None)
+  }
+
+  /// The bootstrap key matching `key`. Digests are compared before any
+  /// Argon2 hash is tried, keys seen before are found in the caches, and only
+  /// [`MAX_ARGON2_CHECKS`] keys are hashed at a time.
+  async fn principal(&self, key: &str) -> Option<Principal> {
+    let digest: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+    if let Some(principal) = self.cached(&digest) {
+      return principal;
+    }
+    let entries = self.entries();
+    let configured = entries
+      .iter()
+      .enumerate()
+      .filter_map(|(index, (_, digest, _))| Some((index, digest.as_ref()?)));
+    for (index, expected) in configured.clone() {
+      if expected.verify_digest(&digest) == Some(true) {
+        return Some(self.grant(index));
+      }
+    }
+    let argon2: Vec<_> = configured
+      .filter(|entry| matches!(entry.1, KeyDigest::Argon2(_)))
+      .collect();
+    if argon2.is_empty() {
+      return None;
+    }
+    let _permit = self.argon2_checks.acquire().await.ok()?;
+    // Another request may have checked the same key while this one waited
+    if let Some(principal) = self.cached(&digest) {
+      return principal;
+    }
+    for (index, expected) in argon2 {
+      if expected.verify_argon2(key).await {
+        self.verified.lock().unwrap().insert(digest, index);
+        return Some(self.grant(index));
+      }
+    }
+    let mut rejected = self.rejected.lock().unwrap();
+    if rejected.len() >= MAX_REJECTED_KEYS {
+      rejected.clear();
+    }
+    rejected.insert(digest);
+    None
+  }
+}
+
+/// Resolve the `x-api-key` header to a principal holding `permission`, and
+/// check the `x-project-key` header (when present) against its scope.
+pub async fn authorize(
+  parts: &Parts,
+  permission: Permission,
+  invalid: &'static str,
+) -> Result<Principal, (StatusCode, &'static str)> {
+  let key = parts
+    .headers
+    .get("x-api-key")
+    .and_then(|v| v.to_str().ok())
+    .ok_or((StatusCode::UNAUTHORIZED, invalid))?;
+
+  let state = parts
+    .extensions
+    .get::<AppState>()
+    .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Missing app state"))?;
+
+  // Issued tokens never match a bootstrap key, so they skip the hashing
+  let bootstrap = if key.starts_with(TOKEN_PREFIX) {
+    None
+  } else {
+    state.config.keys.api.principal(key).await
+  };
+  let principal = match bootstrap {
+    Some(principal) => principal,
+    None => lookup_token(state, key)
+      .await?
+      .ok_or((StatusCode::UNAUTHORIZED, invalid))?,
+  };
+  if let Some(trail) = parts.extensions.get::<AuditTrail>() {
+    trail.set_principal(&principal);
+  }
+  if !principal.has(permission) {
+    return Err((StatusCode::FORBIDDEN, "Permission not granted to token"));
+  }
+
+  if let Some(project) = parts
+    .headers
+    .get("x-project-key")
+    .and_then(|v| v.to_str().ok())
+  {
+    principal.check_project(project)?;
+  }
+  Ok(principal)
+}
+
+async fn lookup_token(
+  state: &AppState,
+  key: &str,
+) -> Result<Option<Principal>, (StatusCode, &'static str)> {
+  let sql = state
+    .queries
+    .get("get_token")
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Query error"))?;
+
+  let row: Option<(i64, String, String, Vec<String>)> = sqlx::query_as(sql)
+    .bind(hash_token(key))
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|err| {
+      tracing::error!("Database error looking up token: {}", err);
+      (StatusCode::INTERNAL_SERVER_ERROR, "DB error")
+    })?;
+
+  Ok(row.map(|(id, label, project_scope, permissions)| {
+    Principal {
+      token_id: Some(id),
+      label,
+      project_scope,
+      permissions: permissions
+        .iter()
+        .filter_map(|p| Permission::parse(p))
+        .collect(),
+    }
+  }))
+}
@@ -0,0 +1,127 @@
+use clap::{Parser, Subcommand};
+use serde_json::Value;
+use std::io::Read;
+
+use keyvault::client::{
+  ClientError, ConnectionArgs, OutputFormat, format_entries, format_value,
+};
+
+/// Command-line client for the keyvault API
+///
+/// Exit codes: 0 success, 1 configuration error, 2 usage error, 3 not
+/// found, 4 authentication or scope failure, 5 server error or unreachable,
+/// 6 request rejected.
+#[derive(Parser)]
+#[command(name = "keyvault-cli", version)]
+struct Cli {
+  #[command(flatten)]
+  connection: ConnectionArgs,
+
+  /// Output format (default: shell for export, raw otherwise)
+  #[arg(long, short, global = true, value_enum)]
+  output: Option<OutputFormat>,
+
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Print a secret's value
+  Get { key: String },
+  /// Store a value; `-` reads it from stdin
+  Put {
+    key: String,
+    value: String,
+    /// Parse the value as JSON instead of storing it as a string
+    #[arg(long)]
+    json: bool,
+  },
+  /// Delete a secret
+  Delete { key: String },
+  /// Print secrets matching a Lucene query
+  Search { query: String },
+  /// Print the project's secret keys; needs only the list permission
+  List {
+    /// Only keys starting with this
+    #[arg(long)]
+    prefix: Option<String>,
+  },
+  /// Print every secret of the project
+  Export,
+}
+
+#[tokio::main]
+async fn main() {
+  let cli = Cli::parse();
+  if let Err(err) = run(cli).await {
+    eprintln!("keyvault-cli: {}", err);
+    std::process::exit(err.exit_code());
+  }
+}
+
+async fn run(cli: Cli) -> Result<(), ClientError> {
+  let client = cli.connection.client()?;
+  let output = cli.output.unwrap_or(OutputFormat::Raw);
+
+  match cli.command {
+    Command::Get { key } => {
+      let value = client.get(&key).await?;
+      println!("{}", format_value(output, &key, &value));
+    }
+    Command::Put { key, value, json } => {
+      let value = read_value(value, json)?;
+      client.put(&key, &value).await?;
+    }
+    Command::Delete { key } => client.delete(&key).await?,
+    Command::Search { query } => {
+      let entries = client.search(Some(&query)).await?;
+      print_lines(&format_entries(output, &entries));
+    }
+    Command::List { prefix } => {
+      let keys = client.list_keys(prefix.as_deref()).await?;
+      if output == OutputFormat::Json {
+        println!(
+          "{}",
+          serde_json::to_string_pretty(&keys).unwrap_or_default()
+        );
+      } else {
+        keys.iter().for_each(|key| println!("{}", key));
+      }
+    }
+    Command::Export => {
+      let entries = client.search(None).await?;
+      let output = cli.output.unwrap_or(OutputFormat::Shell);
+      print_lines(&format_entries(output, &entries));
+    }
+  }
+  Ok(())
+}
+
+/// The value to store: read from stdin for `-`, parsed as JSON with `--json`.
+fn read_value(arg: String, json: bool) -> Result<Value, ClientError> {
+  let text = if arg == "-" {
+    let mut input = String::new();
+    std::io::stdin()
+      .read_to_string(&mut input)
+      .map_err(|e| ClientError::Config(format!("Cannot read stdin: {}", e)))?;
+    input
+      .strip_suffix('\n')
+      .map(str::to_string)
+      .unwrap_or(input)
+  } else {
+    arg
+  };
+  if json {
+    serde_json::from_str(&text)
+      .map_err(|e| ClientError::Config(format!("Invalid JSON value: {}", e)))
+  } else {
+    Ok(Value::String(text))
+  }
+}
+
+fn print_lines(text: &str) {
+  if !text.is_empty() {
+    println!("{}", text);
+  }
+}
@@ -0,0 +1,396 @@
+use axum::{
+  extract::{Extension, Path, Query},
+  http::{HeaderMap, StatusCode, header},
+  response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+
+use crate::{
+  AppState, ReadAuth, WriteAuth, db_error, expiry::is_expired,
+  open_project_secrets, query_error, search::value_metadata,
+};
+
+/// Document formats accepted by import and produced by export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+  /// `KEY=value` lines; values are strings
+  Dotenv,
+  /// One object mapping keys to values
+  Json,
+  /// One or more mappings of keys to values
+  Yaml,
+}
+
+/// What to do with imported keys that already exist
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+  /// Keep the stored value
+  Skip,
+  /// Write the imported value as a new version
+  Overwrite,
+  /// Reject the whole import
+  #[default]
+  Fail,
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+  /// Falls back to the Content-Type
+  pub format: Option<Format>,
+  #[serde(default)]
+  pub on_conflict: ConflictPolicy,
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+  pub format: Option<Format>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportSummary {
+  pub created: Vec<String>,
+  pub updated: Vec<String>,
+  pub skipped: Vec<String>,
+}
+
+fn bad_request(message: String) -> Response {
+  (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+fn format_from_content_type(headers: &HeaderMap) -> Option<Format> {
+  let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+  let mime = content_type.split(';').next()?.trim();
+  match mime {
+    "application/json" => Some(Format::Json),
+    "application/yaml" | "application/x-yaml" | "text/yaml" => {
+      Some(Format::Yaml)
+    }
+    "text/x-dotenv" => Some(Format::Dotenv),
+    _ => None,
+  }
+}
+
+/// Parse an import body into `(key, value)` pairs in document order.
+/// A key given twice is an error rather than silently keeping one.
+pub fn parse_document(
+  format: Format,
+  body: &str,
+) -> Result<Vec<(String, Value)>, String> {
+  let entries = match format {
+    Format::Dotenv => parse_dotenv(body)?
+      .into_iter()
+      .map(|(key, value)| (key, Value::String(value)))
+      .collect(),
+    Format::Json => match serde_json::from_str(body) {
+      Ok(Value::Object(object)) => object.into_iter().collect(),
+      Ok(_) => return Err("Expected a JSON object".to_string()),
+      Err(err) => return Err(format!("Invalid JSON: {}", err)),
+    },
+    Format::Yaml => {
+      let mut entries = Vec::new();
+      for document in serde_yaml::Deserializer::from_str(body) {
+        match Value::deserialize(document) {
+          Ok(Value::Object(object)) => entries.extend(object),
+          Ok(Value::Null) => {}
+          Ok(_) => return Err("Expected YAML mappings".to_string()),
+          Err(err) => return Err(format!("Invalid YAML: {}", err)),
+        }
+      }
+      entries
+    }
+  };
+
+  let mut seen = HashSet::new();
+  for (key, _) in &entries {
+    crate::tree::check_key(key)?;
+    if !seen.insert(key.as_str()) {
+      return Err(format!("Duplicate key '{}'", key));
+    }
+  }
+  Ok(entries)
+}
+
+/// Parse `.env` text: `KEY=value` lines with optional `export`, `#`
+/// comments, and single-quoted (literal) or double-quoted (escaped,
+/// possibly multi-line) values.
+pub fn parse_dotenv(text: &str) -> Result<Vec<(String, String)>, String> {
+  let mut entries = Vec::new();
+  let mut lines = text.lines().enumerate();
+  while let Some((index, line)) = lines.next() {
+    let line_no = index + 1;
+    let line = line.trim_start();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, rest) = line
+      .split_once('=')
+      .ok_or_else(|| format!("line {}: expected KEY=value", line_no))?;
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+      return Err(format!("line {}: invalid key '{}'", line_no, key));
+    }
+    let rest = rest.trim_start();
+
+    let (value, after) = match rest.chars().next() {
+      Some(quote @ ('"' | '\'')) => {
+        // a quoted value may continue over the following lines
+        let mut raw = rest[1..].to_string();
+        let end = loop {
+          if let Some(end) = closing_quote(&raw, quote) {
+            break end;
+          }
+          match lines.next() {
+            Some((_, next)) => {
+              raw.push('\n');
+              raw.push_str(next);
+            }
+            None => {
+              return Err(format!("line {}: unterminated quote", line_no));
+            }
+          }
+        };
+        let value = if quote == '"' {
+          unescape(&raw[..end])
+        } else {
+          raw[..end].to_string()
+        };
+        (value, raw[end + 1..].to_string())
+      }
+      _ => {
+        // an unquoted value ends at a comment
+        let value = match rest.find(" #") {
+          Some(i) => &rest[..i],
+          None => rest,
+        };
+        (value.trim_end().to_string(), String::new())
+      }
+    };
+    let after = after.trim();
+    if !(after.is_empty() || after.starts_with('#')) {
+      return Err(format!(
+        "line {}: unexpected text after the value of '{}'",
+        line_no, key
+      ));
+    }
+    entries.push((key.to_string(), value));
+  }
+  Ok(entries)
+}
+
+/// Byte index of the first unescaped `quote` in `s`.
+fn closing_quote(s: &str, quote: char) -> Option<usize> {
+  let mut escaped = false;
+  for (i, c) in s.char_indices() {
+    match c {
+      '\\' if quote == '"' && !escaped => escaped = true,
+      c if c == quote && !escaped => return Some(i),
+      _ => escaped = false,
+    }
+  }
+  None
+}
+
+fn unescape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  let mut chars = s.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      out.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('n') => out.push('\n'),
+      Some('r') => out.push('\r'),
+      Some('t') => out.push('\t'),
+      Some(other) => out.push(other),
+      None => out.push('\\'),
+    }
+  }
+  out
+}
+
+/// Render secrets as a document. Dotenv values that are not strings are
+/// written as JSON text, so only JSON and YAML round-trip every value.
+pub fn render_document(
+  format: Format,
+  secrets: &[(String, Value)],
+) -> Result<String, String> {
+  match format {
+    Format::Json => {
+      let object: Map<String, Value> = secrets.iter().cloned().collect();
+      serde_json::to_string_pretty(&object).map_err(|e| e.to_string())
+    }
+    Format::Yaml => {
+      let object: Map<String, Value> = secrets.iter().cloned().collect();
+      serde_yaml::to_string(&object).map_err(|e| e.to_string())
+    }
+    Format::Dotenv => {
+      let mut out = String::new();
+      for (key, value) in secrets {
+        if key.contains(|c: char| c == '=' || c == '#' || c.is_whitespace()) {
+          return Err(format!("Key '{}' cannot be written as dotenv", key));
+        }
+        let text = match value {
+          Value::String(s) => s.clone(),
+          other => other.to_string(),
+        };
+        out.push_str(&format!("{}=\"{}\"\n", key, escape(&text)));
+      }
+      Ok(out)
+    }
+  }
+}
+
+fn escape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\\' => out.push_str("\\\\"),
+      '"' => out.push_str("\\\""),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      c => out.push(c),
+    }
+  }
+  out
+}
+
+// POST /projects/:project/import[?format=..&on_conflict=skip|overwrite|fail]
+pub async fn import_secrets(
+  auth: WriteAuth,
+  Path(project): Path<String>,
+  Query(params): Query<ImportParams>,
+  Extension(state): Extension<AppState>,
+  headers: HeaderMap,
+  body: String,
+) -> Result<Json<ImportSummary>, Response> {
+  auth
+    .principal
+    .check_project(&project)
+    .map_err(IntoResponse::into_response)?;
+  let format = params
+    .format
+    .or_else(|| format_from_content_type(&headers))
+    .ok_or_else(|| {
+      bad_request(
+        "Unknown import format; pass format=dotenv|json|yaml".to_string(),
+      )
+    })?;
+  let entries = parse_document(format, &body).map_err(bad_request)?;
+
+  let keys_sql = state.queries.get("list_secret_keys").map_err(query_error)?;
+  let upsert_sql = state.queries.get("upsert_secret").map_err(query_error)?;
+
+  // All or nothing: the existence check and every write share one
+  // transaction
+  let mut tx = state
+    .write_pool
+    .begin()
+    .await
+    .map_err(|err| db_error("starting import", err))?;
+  let existing: HashSet<String> = sqlx::query_scalar(keys_sql)
+    .bind(&project)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|err| db_error("listing keys", err))?
+    .into_iter()
+    .collect();
+
+  if params.on_conflict == ConflictPolicy::Fail {
+    let conflicts: Vec<&str> = entries
+      .iter()
+      .map(|(key, _)| key.as_str())
+      .filter(|key| existing.contains(*key))
+      .collect();
+    if !conflicts.is_empty() {
+      return Err(
+        (
+          StatusCode::CONFLICT,
+          format!("Keys already exist: {}", conflicts.join(", ")),
+        )
+          .into_response(),
+      );
+    }
+  }
+
+  let mut summary = ImportSummary::default();
+  for (key, value) in entries {
+    let exists = existing.contains(&key);
+    if exists && params.on_conflict == ConflictPolicy::Skip {
+      summary.skipped.push(key);
+      continue;
+    }
+    let sealed =
+      state
+        .master_key
+        .seal(&project, &key, &value)
+        .map_err(|err| {
+          tracing::error!("Failed to seal secret: {}", err);
+          (StatusCode::INTERNAL_SERVER_ERROR, "Encryption error")
+            .into_response()
+        })?;
+    let (size, content_type) = value_metadata(&value);
+    sqlx::query(upsert_sql)
+      .bind(&project)
+      .bind(&key)
+      .bind(&sealed.ciphertext)
+      .bind(&sealed.wrapped_key)
+      .bind(&sealed.key_id)
+      .bind(&auth.principal.label)
+      .bind(None::<chrono::DateTime<chrono::Utc>>)
+      .bind(None::<i32>)
+      .bind(size)
+      .bind(content_type)
+      .execute(&mut *tx)
+      .await
+      .map_err(|err| match err {
+        sqlx::Error::Database(err) if err.is_unique_violation() => {
+          (StatusCode::CONFLICT, "Concurrent write, retry").into_response()
+        }
+        err => db_error("importing secret", err),
+      })?;
+    if exists {
+      summary.updated.push(key);
+    } else {
+      summary.created.push(key);
+    }
+  }
+
+  tx.commit()
+    .await
+    .map_err(|err| db_error("committing import", err))?;
+  Ok(Json(summary))
+}
+
+// GET /projects/:project/export[?format=dotenv|json|yaml]
+pub async fn export_secrets(
+  auth: ReadAuth,
+  Path(project): Path<String>,
+  Query(params): Query<ExportParams>,
+  Extension(state): Extension<AppState>,
+) -> Result<Response, Response> {
+  auth
+    .principal
+    .check_project(&project)
+    .map_err(IntoResponse::into_response)?;
+  let format = params.format.unwrap_or(Format::Json);
+  let secrets: Vec<(String, Value)> = open_project_secrets(&state, &project)
+    .await?
+    .into_iter()
+    .filter(|secret| !is_expired(secret.expires_at))
+    .map(|secret| (secret.key, secret.value))
+    .collect();
+  let document = render_document(format, &secrets)
+    .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err).into_response())?;
+  let content_type = match format {
+    Format::Dotenv => "text/plain; charset=utf-8",
+    Format::Json => "application/json",
+    Format::Yaml => "application/yaml",
+  };
+  Ok(([(header::CONTENT_TYPE, content_type)], document).into_response())
+}
@@ -0,0 +1,511 @@
+use reqwest::{Response, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, error::Error, fmt, path::PathBuf};
+
+/// Failures of a client call, grouped by what a script needs to tell apart.
+#[derive(Debug)]
+pub enum ClientError {
+  /// Missing or invalid profile settings
+  Config(String),
+  /// The secret does not exist (404, 410)
+  NotFound(String),
+  /// The key was rejected or lacks access to the project (401, 403)
+  Unauthorized(String),
+  /// Any other 4xx: the request itself was invalid
+  Rejected(String),
+  /// 5xx responses
+  Server(String),
+  /// The server could not be reached or sent an unreadable response
+  Transport(reqwest::Error),
+  /// A command to run could not be started
+  Exec(String),
+}
+
+impl ClientError {
+  /// Process exit code; 2 is left to usage errors.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      ClientError::Config(_) => 1,
+      ClientError::NotFound(_) => 3,
+      ClientError::Unauthorized(_) => 4,
+      ClientError::Server(_) | ClientError::Transport(_) => 5,
+      ClientError::Rejected(_) => 6,
+      // as a shell reports a command it cannot run
+      ClientError::Exec(_) => 127,
+    }
+  }
+}
+
+impl fmt::Display for ClientError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ClientError::Config(msg) => write!(f, "{}", msg),
+      ClientError::NotFound(msg) => write!(f, "Not found: {}", msg),
+      ClientError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+      ClientError::Rejected(msg) => write!(f, "Request rejected: {}", msg),
+      ClientError::Server(msg) => write!(f, "Server error: {}", msg),
+      ClientError::Transport(err) => write!(f, "Request failed: {}", err),
+      ClientError::Exec(msg) => write!(f, "Cannot run {}", msg),
+    }
+  }
+}
+
+impl Error for ClientError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ClientError::Transport(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<reqwest::Error> for ClientError {
+  fn from(err: reqwest::Error) -> Self {
+    ClientError::Transport(err)
+  }
+}
+
+/// Where to connect and as whom. Profiles are sections of a TOML file:
+///
+/// ```toml
+/// [default]
+/// endpoint = "http://localhost:4444"
+/// api_key = "kv_..."
+/// project = "billing"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+  pub endpoint: Option<String>,
+  pub api_key: Option<String>,
+  pub project: Option<String>,
+}
+
+impl Profile {
+  /// `KEYVAULT_PROFILES`, else `profiles.toml` in the user's config dir.
+  pub fn default_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("KEYVAULT_PROFILES") {
+      return Some(PathBuf::from(path));
+    }
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .or_else(|| {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+      })?;
+    Some(config_dir.join("keyvault").join("profiles.toml"))
+  }
+
+  /// Load profile `name` (default `KEYVAULT_PROFILE`, else `default`) from
+  /// the profiles file, if any, then apply the environment.
+  pub fn load(name: Option<&str>) -> Result<Profile, ClientError> {
+    let file = match Self::default_path() {
+      Some(path) if path.exists() => {
+        Some(std::fs::read_to_string(&path).map_err(|e| {
+          ClientError::Config(format!("Cannot read {}: {}", path.display(), e))
+        })?)
+      }
+      _ => None,
+    };
+    let name = name
+      .map(str::to_string)
+      .or_else(|| std::env::var("KEYVAULT_PROFILE").ok())
+      .unwrap_or_else(|| "default".to_string());
+    Self::from_sources(file.as_deref(), &name, |var| std::env::var(var).ok())
+  }
+
+  /// Profile `name` from TOML text, overridden by `KEYVAULT_ENDPOINT`,
+  /// `KEYVAULT_API_KEY` and `KEYVAULT_PROJECT`.
+  pub fn from_sources(
+    file: Option<&str>,
+    name: &str,
+    env: impl Fn(&str) -> Option<String>,
+  ) -> Result<Profile, ClientError> {
+    let mut profiles: HashMap<String, Profile> = match file {
+      Some(text) => toml::from_str(text)
+        .map_err(|e| ClientError::Config(format!("Invalid profiles: {}", e)))?,
+      None => HashMap::new(),
+    };
+    let explicit = name != "default";
+    let profile = match profiles.remove(name) {
+      Some(profile) => profile,
+      None if explicit => {
+        return Err(ClientError::Config(format!("Unknown profile '{}'", name)));
+      }
+      None => Profile::default(),
+    };
+    let var = |name: &str| env(name).filter(|v| !v.is_empty());
+    Ok(Profile {
+      endpoint: var("KEYVAULT_ENDPOINT").or(profile.endpoint),
+      api_key: var("KEYVAULT_API_KEY").or(profile.api_key),
+      project: var("KEYVAULT_PROJECT").or(profile.project),
+    })
+  }
+}
+
+/// Connection options shared by the command-line tools.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ConnectionArgs {
+  /// Profile from the profiles file (default: KEYVAULT_PROFILE or "default")
+  #[arg(long, global = true)]
+  pub profile: Option<String>,
+
+  /// API base URL, overriding the profile
+  #[arg(long, global = true)]
+  pub endpoint: Option<String>,
+
+  /// Project key, overriding the profile
+  #[arg(long, short, global = true)]
+  pub project: Option<String>,
+}
+
+impl ConnectionArgs {
+  /// The selected profile with these options applied on top.
+  pub fn client(&self) -> Result<Client, ClientError> {
+    let mut profile = Profile::load(self.profile.as_deref())?;
+    if self.endpoint.is_some() {
+      profile.endpoint = self.endpoint.clone();
+    }
+    if self.project.is_some() {
+      profile.project = self.project.clone();
+    }
+    Client::new(&profile)
+  }
+}
+
+/// Results requested per search call.
+const SEARCH_PAGE_SIZE: i64 = 500;
+
+/// Calls the vault API on behalf of one project.
+pub struct Client {
+  http: reqwest::Client,
+  endpoint: Url,
+  api_key: String,
+  project: String,
+}
+
+impl Client {
+  pub fn new(profile: &Profile) -> Result<Client, ClientError> {
+    let missing = |what: &str, var: &str| {
+      ClientError::Config(format!("No {} configured (set {})", what, var))
+    };
+    let endpoint = profile
+      .endpoint
+      .as_deref()
+      .ok_or_else(|| missing("endpoint", "KEYVAULT_ENDPOINT"))?;
+    let endpoint = Url::parse(endpoint).map_err(|e| {
+      ClientError::Config(format!("Invalid endpoint '{}': {}", endpoint, e))
+    })?;
+    if endpoint.cannot_be_a_base() {
+      return Err(ClientError::Config(format!(
+        "Invalid endpoint '{}'",
+        endpoint
+      )));
+    }
+    Ok(Client {
+      http: reqwest::Client::new(),
+      endpoint,
+      api_key: profile
+        .api_key
+        .clone()
+        .ok_or_else(|| missing("API key", "KEYVAULT_API_KEY"))?,
+      project: profile
+        .project
+        .clone()
+        .ok_or_else(|| missing("project", "KEYVAULT_PROJECT"))?,
+    })
+  }
+
+  pub fn project(&self) -> &str {
+    &self.project
+  }
+
+  /// `endpoint` with `segments` appended, each percent-encoded.
+  fn url(&self, segments: &[&str]) -> Url {
+    let mut url = self.endpoint.clone();
+    if let Ok(mut path) = url.path_segments_mut() {
+      path.pop_if_empty().extend(segments);
+    }
+    url
+  }
+
+  fn request(
+    &self,
+    method: reqwest::Method,
+    url: Url,
+  ) -> reqwest::RequestBuilder {
+    self
+      .http
+      .request(method, url)
+      .header("x-api-key", &self.api_key)
+      .header("x-project-key", &self.project)
+  }
+
+  pub async fn get(&self, key: &str) -> Result<Value, ClientError> {
+    let url = self.url(&["secrets", key]);
+    let res = self.request(reqwest::Method::GET, url).send().await?;
+    Ok(check(res).await?.json().await?)
+  }
+
+  pub async fn put(&self, key: &str, value: &Value) -> Result<(), ClientError> {
+    let url = self.url(&["secrets", key]);
+    let res = self
+      .request(reqwest::Method::PUT, url)
+      .json(&serde_json::json!({ "value": value }))
+      .send()
+      .await?;
+    check(res).await?;
+    Ok(())
+  }
+
+  pub async fn delete(&self, key: &str) -> Result<(), ClientError> {
+    let url = self.url(&["secrets", key]);
+    let res = self.request(reqwest::Method::DELETE, url).send().await?;
+    check(res).await?;
+    Ok(())
+  }
+
+  /// Secrets matching a Lucene `query` (all of them when `None`), sorted by
+  /// key. Follows the result pages to the end.
+  pub async fn search(
+    &self,
+    query: Option<&str>,
+  ) -> Result<Vec<(String, Value)>, ClientError> {
+    #[derive(Deserialize)]
+    struct Hit {
+      secret_key: String,
+      secret_value: Value,
+    }
+    #[derive(Deserialize)]
+    struct Page {
+      items: Vec<Hit>,
+      next_cursor: Option<String>,
+    }
+
+    let mut entries = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+      let url = self.url(&["search"]);
+      let res = self
+        .request(reqwest::Method::POST, url)
+        .json(&serde_json::json!({
+          "query": query,
+          "limit": SEARCH_PAGE_SIZE,
+          "cursor": cursor,
+        }))
+        .send()
+        .await?;
+      let page: Page = check(res).await?.json().await?;
+      entries.extend(
+        page
+          .items
+          .into_iter()
+          .map(|hit| (hit.secret_key, hit.secret_value)),
+      );
+      cursor = page.next_cursor;
+      if cursor.is_none() {
+        break;
+      }
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+  }
+
+  /// Keys starting with `prefix` (all when `None`), without their values.
+  pub async fn list_keys(
+    &self,
+    prefix: Option<&str>,
+  ) -> Result<Vec<String>, ClientError> {
+    #[derive(Deserialize)]
+    struct Item {
+      secret_key: String,
+    }
+    #[derive(Deserialize)]
+    struct Page {
+      items: Vec<Item>,
+      next_cursor: Option<String>,
+    }
+
+    let mut keys = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+      let mut url = self.url(&["secrets"]);
+      {
+        let mut params = url.query_pairs_mut();
+        params.append_pair("limit", &SEARCH_PAGE_SIZE.to_string());
+        if let Some(prefix) = prefix {
+          params.append_pair("prefix", prefix);
+        }
+        if let Some(cursor) = &cursor {
+          params.append_pair("cursor", cursor);
+        }
+      }
+      let res = self.request(reqwest::Method::GET, url).send().await?;
+      let page: Page = check(res).await?.json().await?;
+      keys.extend(page.items.into_iter().map(|item| item.secret_key));
+      cursor = page.next_cursor;
+      if cursor.is_none() {
+        break;
+      }
+    }
+    Ok(keys)
+  }
+}
+
+/// Turn error statuses into the matching [`ClientError`].
+async fn check(res: Response) -> Result<Response, ClientError> {
+  let status = res.status();
+  if status.is_success() {
+    return Ok(res);
+  }
+  let body = res.text().await.unwrap_or_default();
+  let message = if body.is_empty() {
+    status.to_string()
+  } else {
+    body
+  };
+  Err(match status {
+    StatusCode::NOT_FOUND | StatusCode::GONE => ClientError::NotFound(message),
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+      ClientError::Unauthorized(message)
+    }
+    s if s.is_client_error() => ClientError::Rejected(message),
+    _ => ClientError::Server(message),
+  })
+}
+
+/// How values are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+  /// Strings as-is, other values as compact JSON
+  Raw,
+  /// Pretty-printed JSON
+  Json,
+  /// `export NAME='value'` lines for a POSIX shell
+  Shell,
+}
+
+/// Environment variable name for a secret key: `db/password` becomes
+/// `DB_PASSWORD`.
+pub fn env_var_name(key: &str) -> String {
+  let mut name: String = key
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() {
+        c.to_ascii_uppercase()
+      } else {
+        '_'
+      }
+    })
+    .collect();
+  if name.starts_with(|c: char| c.is_ascii_digit()) {
+    name.insert(0, '_');
+  }
+  name
+}
+
+/// Quote `s` for a POSIX shell.
+pub fn shell_quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Strings unquoted, anything else as compact JSON.
+pub fn raw_value(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.clone(),
+    other => other.to_string(),
+  }
+}
+
+/// Render a single secret.
+pub fn format_value(format: OutputFormat, key: &str, value: &Value) -> String {
+  match format {
+    OutputFormat::Raw => raw_value(value),
+    OutputFormat::Json => {
+      serde_json::to_string_pretty(value).unwrap_or_default()
+    }
+    OutputFormat::Shell => format!(
+      "export {}={}",
+      env_var_name(key),
+      shell_quote(&raw_value(value))
+    ),
+  }
+}
+
+/// Render several secrets: tab-separated lines, a JSON object or `export`
+/// lines.
+pub fn format_entries(
+  format: OutputFormat,
+  entries: &[(String, Value)],
+) -> String {
+  match format {
+    OutputFormat::Json => {
+      let object: serde_json::Map<String, Value> =
+        entries.iter().cloned().collect();
+      serde_json::to_string_pretty(&object).unwrap_or_default()
+    }
+    OutputFormat::Raw => entries
+      .iter()
+      .map(|(key, value)| format!("{}\t{}", key, raw_value(value)))
+      .collect::<Vec<_>>()
+      .join("\n"),
+    OutputFormat::Shell => entries
+      .iter()
+      .map(|(key, value)| format_value(format, key, value))
+      .collect::<Vec<_>>()
+      .join("\n"),
+  }
+}
+
+/// Map secrets to environment variables named `prefix` + [`env_var_name`].
+/// With `flatten`, object values become one variable per leaf
+/// (`db = {"user": ..}` sets `DB_USER`); other values are set as
+/// [`raw_value`]. Two secrets mapping to the same name are an error.
+pub fn secrets_to_env(
+  entries: &[(String, Value)],
+  prefix: &str,
+  flatten: bool,
+) -> Result<Vec<(String, String)>, String> {
+  fn add(
+    vars: &mut Vec<(String, String)>,
+    sources: &mut HashMap<String, String>,
+    name: String,
+    source: String,
+    value: &Value,
+    flatten: bool,
+  ) -> Result<(), String> {
+    match value {
+      Value::Object(fields) if flatten && !fields.is_empty() => {
+        for (field, value) in fields {
+          add(
+            vars,
+            sources,
+            format!("{}_{}", name, env_var_name(field)),
+            format!("{}.{}", source, field),
+            value,
+            flatten,
+          )?;
+        }
+        Ok(())
+      }
+      _ => {
+        if let Some(other) = sources.insert(name.clone(), source.clone()) {
+          return Err(format!(
+            "Both '{}' and '{}' map to {}",
+            other, source, name
+          ));
+        }
+        vars.push((name, raw_value(value)));
+        Ok(())
+      }
+    }
+  }
+
+  let mut vars = Vec::new();
+  let mut sources = HashMap::new();
+  for (key, value) in entries {
+    let name = format!("{}{}", prefix, env_var_name(key));
+    add(&mut vars, &mut sources, name, key.clone(), value, flatten)?;
+  }
+  Ok(vars)
+}
@@ -0,0 +1,386 @@
+use axum::http::HeaderValue;
+use serde::Deserialize;
+use std::{
+  error::Error,
+  fmt,
+  net::{IpAddr, SocketAddr},
+  path::{Path, PathBuf},
+  str::FromStr,
+  time::Duration,
+};
+
+use crate::{
+  auth::{KeyDigest, MasterApiKeys},
+  crypto::MasterKey,
+};
+
+/// Every problem found while loading the configuration, reported together so
+/// a deployment can be fixed in one pass.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Invalid configuration:")?;
+    for problem in &self.0 {
+      write!(f, "\n  - {}", problem)?;
+    }
+    Ok(())
+  }
+}
+
+impl Error for ConfigError {}
+
+/// Settings for the two connection pools
+pub struct DatabaseConfig {
+  pub host: String,
+  pub name: String,
+  pub read_user: String,
+  pub read_password: String,
+  pub write_user: String,
+  pub write_password: String,
+  /// Owner credentials used only by `keyvault migrate`
+  pub admin_user: Option<String>,
+  pub admin_password: Option<String>,
+  pub read_pool_size: u32,
+  pub write_pool_size: u32,
+  /// How long to wait for a pooled connection
+  pub connect_timeout: Duration,
+}
+
+impl DatabaseConfig {
+  pub fn read_url(&self) -> String {
+    format!(
+      "postgres://{}:{}@{}/{}",
+      self.read_user, self.read_password, self.host, self.name
+    )
+  }
+
+  pub fn write_url(&self) -> String {
+    format!(
+      "postgres://{}:{}@{}/{}",
+      self.write_user, self.write_password, self.host, self.name
+    )
+  }
+
+  pub fn admin_url(&self) -> Result<String, String> {
+    let user = self
+      .admin_user
+      .as_ref()
+      .ok_or("POSTGRES_USER is not set; migrations need an admin connection")?;
+    Ok(match &self.admin_password {
+      Some(password) => {
+        format!(
+          "postgres://{}:{}@{}/{}",
+          user, password, self.host, self.name
+        )
+      }
+      None => format!("postgres://{}@{}/{}", user, self.host, self.name),
+    })
+  }
+}
+
+/// Key material: the key-encryption key and the bootstrap API keys
+pub struct KeyConfig {
+  pub master_key: MasterKey,
+  pub api: MasterApiKeys,
+}
+
+/// Server configuration, loaded once at startup from the environment and an
+/// optional TOML file. Environment variables take precedence over the file.
+pub struct Config {
+  pub listen_addr: SocketAddr,
+  /// Overrides for the embedded query catalog
+  pub query_file: Option<PathBuf>,
+  /// Allowed CORS origins; `["*"]` allows any origin
+  pub cors_origins: Vec<String>,
+  /// Peers whose `X-Forwarded-For` is believed for the audit log's client IP
+  pub trusted_proxies: Vec<IpAddr>,
+  pub request_timeout: Duration,
+  /// How often expired secrets are tombstoned; `None` turns the reaper off
+  pub expiry_reap_interval: Option<Duration>,
+  /// Most secrets one `/search` request tests against its query
+  pub search_scan_limit: u32,
+  pub database: DatabaseConfig,
+  pub keys: KeyConfig,
+}
+
+// Shape of the optional TOML file; every field is optional
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+  listen_addr: Option<SocketAddr>,
+  query_file: Option<String>,
+  cors_origins: Option<Vec<String>>,
+  trusted_proxies: Option<Vec<IpAddr>>,
+  request_timeout_secs: Option<u64>,
+  expiry_reap_interval_secs: Option<u64>,
+  search_scan_limit: Option<u32>,
+  #[serde(default)]
+  database: FileDatabase,
+  #[serde(default)]
+  keys: FileKeys,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileDatabase {
+  host: Option<String>,
+  name: Option<String>,
+  read_user: Option<String>,
+  read_password: Option<String>,
+  write_user: Option<String>,
+  write_password: Option<String>,
+  admin_user: Option<String>,
+  admin_password: Option<String>,
+  read_pool_size: Option<u32>,
+  write_pool_size: Option<u32>,
+  connect_timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileKeys {
+  master_key: Option<String>,
+  api_key_admin_hash: Option<String>,
+  api_key_write_hash: Option<String>,
+  api_key_read_hash: Option<String>,
+}
+
+/// Resolves each setting from the environment, then the file, then a
+/// default, collecting problems instead of stopping at the first one.
+struct Loader<E> {
+  env: E,
+  errors: Vec<String>,
+}
+
+impl<E: Fn(&str) -> Option<String>> Loader<E> {
+  /// An environment variable, treating empty values as unset (compose passes
+  /// unset variables through as empty strings).
+  fn var(&self, name: &str) -> Option<String> {
+    (self.env)(name).filter(|v| !v.is_empty())
+  }
+
+  fn string(
+    &mut self,
+    name: &str,
+    file: Option<String>,
+    default: Option<&str>,
+  ) -> String {
+    match self
+      .var(name)
+      .or(file)
+      .or_else(|| default.map(str::to_string))
+    {
+      Some(value) => value,
+      None => {
+        self.errors.push(format!("{} is not set", name));
+        String::new()
+      }
+    }
+  }
+
+  fn parsed<T>(&mut self, name: &str, file: Option<T>, default: T) -> T
+  where
+    T: FromStr,
+    T::Err: fmt::Display,
+  {
+    match self.var(name) {
+      Some(raw) => raw.trim().parse().unwrap_or_else(|err| {
+        self
+          .errors
+          .push(format!("{}: invalid value '{}': {}", name, raw, err));
+        default
+      }),
+      None => file.unwrap_or(default),
+    }
+  }
+
+  fn positive<T: PartialOrd + Default>(&mut self, name: &str, value: T) -> T {
+    if value <= T::default() {
+      self
+        .errors
+        .push(format!("{} must be greater than zero", name));
+    }
+    value
+  }
+
+  /// `<name>_HASH` from the environment or file, falling back to the
+  /// deprecated plaintext `<name>` variable.
+  fn api_key(&mut self, name: &str, file: Option<String>) -> Option<KeyDigest> {
+    let hash_name = format!("{}_HASH", name);
+    if let Some(hash) = self.var(&hash_name).or(file) {
+      return match KeyDigest::parse_hash(&hash) {
+        Ok(digest) => Some(digest),
+        Err(err) => {
+          self.errors.push(format!("{}: {}", hash_name, err));
+          None
+        }
+      };
+    }
+    let plain = self.var(name)?;
+    tracing::warn!("{} is set in plaintext; use {} instead", name, hash_name);
+    Some(KeyDigest::Plain(plain))
+  }
+}
+
+impl Config {
+  /// Load from the process environment and, if given, a TOML file.
+  pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+    let file = match path {
+      Some(path) => Some(std::fs::read_to_string(path).map_err(|err| {
+        ConfigError(vec![format!("Cannot read {}: {}", path.display(), err)])
+      })?),
+      None => None,
+    };
+    Self::from_sources(file.as_deref(), |name| std::env::var(name).ok())
+  }
+
+  /// Load from TOML text and an environment lookup.
+  pub fn from_sources(
+    file: Option<&str>,
+    env: impl Fn(&str) -> Option<String>,
+  ) -> Result<Config, ConfigError> {
+    let file: FileConfig = match file.map(toml::from_str).transpose() {
+      Ok(file) => file.unwrap_or_default(),
+      Err(err) => {
+        return Err(ConfigError(vec![format!("Config file: {}", err)]));
+      }
+    };
+    let mut load = Loader { env, errors: Vec::new() };
+
+    let listen_addr = load.parsed(
+      "KEYVAULT_LISTEN_ADDR",
+      file.listen_addr,
+      SocketAddr::from(([0, 0, 0, 0], 3000)),
+    );
+    let query_file = load
+      .var("KEYVAULT_QUERY_FILE")
+      .or(file.query_file)
+      .map(PathBuf::from);
+    let cors_origins = match load.var("KEYVAULT_CORS_ORIGINS") {
+      Some(origins) => {
+        origins.split(',').map(|o| o.trim().to_string()).collect()
+      }
+      None => file.cors_origins.unwrap_or_else(|| vec!["*".to_string()]),
+    };
+    for origin in &cors_origins {
+      if origin == "*" && cors_origins.len() > 1 {
+        load
+          .errors
+          .push("KEYVAULT_CORS_ORIGINS: '*' cannot be combined".to_string());
+      } else if origin != "*" && HeaderValue::from_str(origin).is_err() {
+        load.errors.push(format!(
+          "KEYVAULT_CORS_ORIGINS: invalid origin '{}'",
+          origin
+        ));
+      }
+    }
+    let trusted_proxies = match load.var("KEYVAULT_TRUSTED_PROXIES") {
+      Some(proxies) => proxies
+        .split(',')
+        .filter_map(|proxy| match proxy.trim().parse() {
+          Ok(ip) => Some(ip),
+          Err(_) => {
+            load.errors.push(format!(
+              "KEYVAULT_TRUSTED_PROXIES: invalid address '{}'",
+              proxy.trim()
+            ));
+            None
+          }
+        })
+        .collect(),
+      None => file.trusted_proxies.unwrap_or_default(),
+    };
+    let request_timeout_secs = load.parsed(
+      "KEYVAULT_REQUEST_TIMEOUT_SECS",
+      file.request_timeout_secs,
+      30,
+    );
+    let request_timeout_secs =
+      load.positive("KEYVAULT_REQUEST_TIMEOUT_SECS", request_timeout_secs);
+    // 0 turns the reaper off
+    let expiry_reap_interval_secs = load.parsed(
+      "KEYVAULT_EXPIRY_REAP_INTERVAL_SECS",
+      file.expiry_reap_interval_secs,
+      60,
+    );
+    let search_scan_limit =
+      load.parsed("KEYVAULT_SEARCH_SCAN_LIMIT", file.search_scan_limit, 10_000);
+    let search_scan_limit =
+      load.positive("KEYVAULT_SEARCH_SCAN_LIMIT", search_scan_limit);
+
+    let db = file.database;
+    let database = DatabaseConfig {
+      host: load.string("PG_HOST", db.host, Some("postgres")),
+      name: load.string("POSTGRES_DB", db.name, None),
+      read_user: load.string("SECRETS_READ_USER", db.read_user, None),
+      read_password: load.string(
+        "SECRETS_READ_PASSWORD",
+        db.read_password,
+        None,
+      ),
+      write_user: load.string("SECRETS_WRITE_USER", db.write_user, None),
+      write_password: load.string(
+        "SECRETS_WRITE_PASSWORD",
+        db.write_password,
+        None,
+      ),
+      admin_user: load.var("POSTGRES_USER").or(db.admin_user),
+      admin_password: load.var("POSTGRES_PASSWORD").or(db.admin_password),
+      read_pool_size: {
+        let size = load.parsed("KEYVAULT_READ_POOL_SIZE", db.read_pool_size, 5);
+        load.positive("KEYVAULT_READ_POOL_SIZE", size)
+      },
+      write_pool_size: {
+        let size =
+          load.parsed("KEYVAULT_WRITE_POOL_SIZE", db.write_pool_size, 5);
+        load.positive("KEYVAULT_WRITE_POOL_SIZE", size)
+      },
+      connect_timeout: {
+        let secs = load.parsed(
+          "KEYVAULT_DB_CONNECT_TIMEOUT_SECS",
+          db.connect_timeout_secs,
+          30,
+        );
+        Duration::from_secs(
+          load.positive("KEYVAULT_DB_CONNECT_TIMEOUT_SECS", secs),
+        )
+      },
+    };
+
+    let keys = file.keys;
+    let master_key = match load.var("SECRETS_MASTER_KEY").or(keys.master_key) {
+      Some(hex) => MasterKey::from_hex(&hex)
+        .map_err(|err| load.errors.push(format!("SECRETS_MASTER_KEY: {}", err)))
+        .ok(),
+      None => {
+        load
+          .errors
+          .push("SECRETS_MASTER_KEY is not set".to_string());
+        None
+      }
+    };
+    let api = MasterApiKeys::new(
+      load.api_key("API_MASTER_KEY_ADMIN", keys.api_key_admin_hash),
+      load.api_key("API_MASTER_KEY_WRITE", keys.api_key_write_hash),
+      load.api_key("API_MASTER_KEY_READ", keys.api_key_read_hash),
+    );
+
+    match master_key {
+      Some(master_key) if load.errors.is_empty() => Ok(Config {
+        listen_addr,
+        query_file,
+        cors_origins,
+        trusted_proxies,
+        request_timeout: Duration::from_secs(request_timeout_secs),
+        expiry_reap_interval: (expiry_reap_interval_secs > 0)
+          .then(|| Duration::from_secs(expiry_reap_interval_secs)),
+        search_scan_limit,
+        database,
+        keys: KeyConfig { master_key, api },
+      }),
+      _ => Err(ConfigError(load.errors)),
+    }
+  }
+}
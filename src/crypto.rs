@@ -0,0 +1,229 @@
+use aes_gcm::{
+  Aes256Gcm, Key, Nonce,
+  aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::{error::Error, fmt};
+
+use crate::Queries;
+
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// Associated data used when wrapping a data key with the master key.
+const DATA_KEY_AAD: &[u8] = b"keyvault:data-key";
+
+/// Errors raised while sealing or opening secret values
+#[derive(Debug)]
+pub enum CryptoError {
+  InvalidMasterKey(String),
+  UnknownKeyId(String),
+  Encryption,
+  Decryption,
+  Serialization(serde_json::Error),
+}
+
+impl fmt::Display for CryptoError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CryptoError::InvalidMasterKey(msg) => {
+        write!(f, "Invalid master key: {}", msg)
+      }
+      CryptoError::UnknownKeyId(id) => {
+        write!(f, "Value was sealed with unknown master key '{}'", id)
+      }
+      CryptoError::Encryption => write!(f, "Encryption failed"),
+      CryptoError::Decryption => write!(f, "Decryption failed"),
+      CryptoError::Serialization(err) => {
+        write!(f, "Value serialization failed: {}", err)
+      }
+    }
+  }
+}
+
+impl Error for CryptoError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      CryptoError::Serialization(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<serde_json::Error> for CryptoError {
+  fn from(err: serde_json::Error) -> Self {
+    CryptoError::Serialization(err)
+  }
+}
+
+/// A secret value as stored at rest: the JSON value encrypted with a fresh
+/// data key, and that data key wrapped by the master key identified by
+/// `key_id`. Both blobs are `nonce || ciphertext`.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SealedValue {
+  pub ciphertext: Vec<u8>,
+  pub wrapped_key: Vec<u8>,
+  pub key_id: String,
+}
+
+/// The key-encryption key loaded at startup.
+#[derive(Clone)]
+pub struct MasterKey {
+  cipher: Aes256Gcm,
+  fingerprint: String,
+}
+
+impl fmt::Debug for MasterKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("MasterKey")
+      .field("fingerprint", &self.fingerprint)
+      .finish_non_exhaustive()
+  }
+}
+
+impl MasterKey {
+  /// Build a master key from 32 raw bytes.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, CryptoError> {
+    if bytes.len() != KEY_LEN {
+      return Err(CryptoError::InvalidMasterKey(format!(
+        "expected {} bytes, got {}",
+        KEY_LEN,
+        bytes.len()
+      )));
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(b"keyvault:master-key-fingerprint:");
+    hasher.update(bytes);
+    let fingerprint = hex::encode(&hasher.finalize()[..16]);
+    Ok(MasterKey {
+      cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(bytes)),
+      fingerprint,
+    })
+  }
+
+  /// Parse a hex-encoded master key, as printed by `keyvault gen-key`.
+  pub fn from_hex(encoded: &str) -> Result<Self, CryptoError> {
+    let bytes = hex::decode(encoded.trim())
+      .map_err(|e| CryptoError::InvalidMasterKey(e.to_string()))?;
+    Self::from_bytes(&bytes)
+  }
+
+  /// Stable identifier of this key, safe to store and log.
+  pub fn fingerprint(&self) -> &str {
+    &self.fingerprint
+  }
+
+  /// Encrypt `value` under a new data key bound to `project` and `key`.
+  pub fn seal(
+    &self,
+    project: &str,
+    key: &str,
+    value: &serde_json::Value,
+  ) -> Result<SealedValue, CryptoError> {
+    let plaintext = serde_json::to_vec(value)?;
+    let data_key = Aes256Gcm::generate_key(OsRng);
+    let ciphertext = encrypt(
+      &Aes256Gcm::new(&data_key),
+      &plaintext,
+      &value_aad(project, key),
+    )?;
+    let wrapped_key = encrypt(&self.cipher, data_key.as_slice(), DATA_KEY_AAD)?;
+    Ok(SealedValue {
+      ciphertext,
+      wrapped_key,
+      key_id: self.fingerprint.clone(),
+    })
+  }
+
+  /// Decrypt a value previously produced by [`MasterKey::seal`] for the same
+  /// `project` and `key`.
+  pub fn open(
+    &self,
+    project: &str,
+    key: &str,
+    sealed: &SealedValue,
+  ) -> Result<serde_json::Value, CryptoError> {
+    if sealed.key_id != self.fingerprint {
+      return Err(CryptoError::UnknownKeyId(sealed.key_id.clone()));
+    }
+    let data_key = decrypt(&self.cipher, &sealed.wrapped_key, DATA_KEY_AAD)?;
+    if data_key.len() != KEY_LEN {
+      return Err(CryptoError::Decryption);
+    }
+    let plaintext = decrypt(
+      &Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key)),
+      &sealed.ciphertext,
+      &value_aad(project, key),
+    )?;
+    Ok(serde_json::from_slice(&plaintext)?)
+  }
+}
+
+/// Bind a ciphertext to its row so it cannot be swapped under another key.
+fn value_aad(project: &str, key: &str) -> Vec<u8> {
+  let mut aad = Vec::with_capacity(project.len() + key.len() + 1);
+  aad.extend_from_slice(project.as_bytes());
+  aad.push(0);
+  aad.extend_from_slice(key.as_bytes());
+  aad
+}
+
+fn encrypt(
+  cipher: &Aes256Gcm,
+  msg: &[u8],
+  aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+  let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+  let sealed = cipher
+    .encrypt(&nonce, Payload { msg, aad })
+    .map_err(|_| CryptoError::Encryption)?;
+  let mut out = Vec::with_capacity(NONCE_LEN + sealed.len());
+  out.extend_from_slice(&nonce);
+  out.extend_from_slice(&sealed);
+  Ok(out)
+}
+
+fn decrypt(
+  cipher: &Aes256Gcm,
+  data: &[u8],
+  aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+  if data.len() < NONCE_LEN {
+    return Err(CryptoError::Decryption);
+  }
+  let (nonce, msg) = data.split_at(NONCE_LEN);
+  cipher
+    .decrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+    .map_err(|_| CryptoError::Decryption)
+}
+
+/// Compare the master key against the fingerprint recorded in the database,
+/// recording it on first start. Returns an error describing why the server
+/// must not serve requests with this key.
+pub async fn check_master_key(
+  pool: &PgPool,
+  queries: &Queries,
+  master_key: &MasterKey,
+) -> Result<(), String> {
+  sqlx::query(queries.get("init_key_fingerprint")?)
+    .bind(master_key.fingerprint())
+    .execute(pool)
+    .await
+    .map_err(|e| format!("Failed to record master key fingerprint: {}", e))?;
+
+  let (stored,): (String,) =
+    sqlx::query_as(queries.get("get_key_fingerprint")?)
+      .fetch_one(pool)
+      .await
+      .map_err(|e| format!("Failed to read master key fingerprint: {}", e))?;
+
+  if stored != master_key.fingerprint() {
+    return Err(format!(
+      "Master key fingerprint {} does not match the database ({})",
+      master_key.fingerprint(),
+      stored
+    ));
+  }
+  Ok(())
+}
@@ -0,0 +1,102 @@
+//! Entity tags and conditional writes. A secret's ETag is its version
+//! number, quoted: `"3"`.
+
+use axum::http::{HeaderMap, HeaderValue, header};
+
+/// The ETag of a secret at `version`.
+pub fn etag(version: i32) -> HeaderValue {
+  HeaderValue::from_str(&format!("\"{}\"", version))
+    .expect("a quoted number is a valid header value")
+}
+
+/// An entity tag named by a condition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+  pub version: i32,
+  /// `W/"3"`; the tags we send are always strong
+  pub weak: bool,
+}
+
+/// One `If-Match` or `If-None-Match` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+  /// `*`: any current version
+  Any,
+  /// The listed tags; tags that are not ours never match
+  Tags(Vec<Tag>),
+}
+
+impl Condition {
+  pub fn parse(value: &str) -> Condition {
+    if value.trim() == "*" {
+      return Condition::Any;
+    }
+    Condition::Tags(
+      value
+        .split(',')
+        .filter_map(|tag| {
+          let tag = tag.trim();
+          let (weak, tag) = match tag.strip_prefix("W/") {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+          };
+          let version =
+            tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok()?;
+          Some(Tag { version, weak })
+        })
+        .collect(),
+    )
+  }
+
+  /// Whether the condition names `current`. The strong comparison of
+  /// `If-Match` never matches a weak tag; the weak one of `If-None-Match`
+  /// ignores the flag.
+  fn matches(&self, current: Option<i32>, strong: bool) -> bool {
+    match (self, current) {
+      (_, None) => false,
+      (Condition::Any, Some(_)) => true,
+      (Condition::Tags(tags), Some(v)) => tags
+        .iter()
+        .any(|tag| tag.version == v && !(strong && tag.weak)),
+    }
+  }
+}
+
+/// The conditional headers of a write.
+#[derive(Debug, Default)]
+pub struct Preconditions {
+  pub if_match: Option<Condition>,
+  pub if_none_match: Option<Condition>,
+}
+
+impl Preconditions {
+  pub fn from_headers(headers: &HeaderMap) -> Preconditions {
+    let get = |name| {
+      headers
+        .get(name)
+        .and_then(|v: &HeaderValue| v.to_str().ok())
+        .map(Condition::parse)
+    };
+    Preconditions {
+      if_match: get(header::IF_MATCH),
+      if_none_match: get(header::IF_NONE_MATCH),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.if_match.is_none() && self.if_none_match.is_none()
+  }
+
+  /// Whether a write may go ahead when the live version is `current`
+  /// (`None` if the secret does not exist).
+  pub fn allow(&self, current: Option<i32>) -> bool {
+    self
+      .if_match
+      .as_ref()
+      .is_none_or(|c| c.matches(current, true))
+      && self
+        .if_none_match
+        .as_ref()
+        .is_none_or(|c| !c.matches(current, false))
+  }
+}
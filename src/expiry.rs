@@ -0,0 +1,74 @@
+//! Secret expiry: the `expires_at` and `ttl` write options, and the reaper
+//! that tombstones secrets once they expire.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::AppState;
+
+/// Author of the tombstones the reaper writes
+pub const REAPER: &str = "keyvault-reaper";
+
+/// When a written value stops being served; at most one field may be set.
+#[derive(Deserialize, Default)]
+pub struct Expiry {
+  pub expires_at: Option<DateTime<Utc>>,
+  /// Seconds from the time of the write
+  pub ttl: Option<u64>,
+}
+
+impl Expiry {
+  /// The absolute expiry time, which must lie after `now`.
+  pub fn resolve(
+    &self,
+    now: DateTime<Utc>,
+  ) -> Result<Option<DateTime<Utc>>, String> {
+    let expires_at = match (self.expires_at, self.ttl) {
+      (Some(_), Some(_)) => {
+        return Err("Give either expires_at or ttl, not both".to_string());
+      }
+      (Some(at), None) => at,
+      (None, Some(ttl)) => i64::try_from(ttl)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|ttl| now.checked_add_signed(ttl))
+        .ok_or_else(|| format!("ttl {} is too large", ttl))?,
+      (None, None) => return Ok(None),
+    };
+    if expires_at <= now {
+      return Err("The expiry must lie in the future".to_string());
+    }
+    Ok(Some(expires_at))
+  }
+}
+
+/// Whether a value with this expiry is no longer served.
+pub fn is_expired(expires_at: Option<DateTime<Utc>>) -> bool {
+  expires_at.is_some_and(|at| at <= Utc::now())
+}
+
+/// Tombstone every expired secret, returning how many there were.
+pub async fn reap_expired(state: &AppState) -> Result<u64, String> {
+  let sql = state.queries.get("reap_expired_secrets")?;
+  let result = sqlx::query(sql)
+    .bind(REAPER)
+    .execute(&state.write_pool)
+    .await
+    .map_err(|err| err.to_string())?;
+  Ok(result.rows_affected())
+}
+
+/// Reap every `interval`, for as long as the server runs. Failures are
+/// logged and retried on the next tick.
+pub async fn run_reaper(state: AppState, interval: Duration) {
+  let mut ticks = tokio::time::interval(interval);
+  loop {
+    ticks.tick().await;
+    match reap_expired(&state).await {
+      Ok(0) => {}
+      Ok(count) => tracing::info!("Tombstoned {} expired secrets", count),
+      Err(err) => tracing::error!("Reaping expired secrets failed: {}", err),
+    }
+  }
+}
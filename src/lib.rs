@@ -1,29 +1,46 @@
 use axum::{
-  extract::{Extension, FromRequestParts, Json, Path},
-  http::{StatusCode, request::Parts},
-  response::IntoResponse,
+  Router,
+  extract::{
+    Extension, FromRequest, FromRequestParts, Json, Path, Query, Request,
+  },
+  http::{HeaderMap, StatusCode, header, request::Parts},
+  middleware,
+  response::{IntoResponse, Response},
+  routing::{delete, get, patch, post},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
-use std::collections::HashMap;
+use std::sync::Arc;
+use tower_http::{
+  request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+  timeout::TimeoutLayer,
+};
 
+pub mod audit;
+pub mod auth;
+pub mod bulk;
+pub mod client;
+pub mod config;
+pub mod crypto;
+pub mod etag;
+pub mod expiry;
+pub mod lucene_filter;
 pub mod lucene_parser;
-use crate::lucene_parser::query_to_sql;
-
-
-// Load SQL queries from queries.yaml
-#[derive(Debug, Deserialize, Clone)]
-pub struct Queries(pub HashMap<String, String>);
+pub mod migrate;
+pub mod projects;
+pub mod queries;
+pub mod search;
+pub mod template;
+pub mod tokens;
+pub mod tree;
+use crate::audit::AuditTrail;
+use crate::auth::{Permission, Principal};
+use crate::config::Config;
+use crate::crypto::{MasterKey, SealedValue};
+use crate::etag::{Preconditions, etag};
+use crate::expiry::Expiry;
+pub use crate::queries::Queries;
 
-impl Queries {
-  pub fn get(&self, key: &str) -> Result<&str, String> {
-    self
-      .0
-      .get(key)
-      .map(|s| s.as_str())
-      .ok_or_else(|| format!("Missing query '{}'", key))
-  }
-}
 
 // Shared application state
 #[derive(Clone)]
@@ -31,6 +48,61 @@ pub struct AppState {
   pub read_pool: PgPool,
   pub write_pool: PgPool,
   pub queries: Queries,
+  pub master_key: MasterKey,
+  pub config: Arc<Config>,
+}
+
+/// Every API route, wrapped with request ids, audit logging, the request
+/// timeout and the shared state. Transport concerns such as CORS are left to
+/// the caller.
+pub fn router(state: AppState) -> Router {
+  // Inside the audit layer, so a request cut short is still recorded
+  let timeout = TimeoutLayer::with_status_code(
+    StatusCode::REQUEST_TIMEOUT,
+    state.config.request_timeout,
+  );
+  Router::new()
+    .route(
+      "/secrets/{*key}",
+      get(read_secret_path)
+        .put(upsert_secret_by_path)
+        .delete(delete_secret)
+        .post(rollback_secret_path),
+    )
+    .route(
+      "/secrets",
+      get(search::list_secret_keys).post(upsert_secret),
+    )
+    .route("/search", post(search::search_secrets))
+    .route("/tree", get(tree::list_tree))
+    .route(
+      "/tree/{*path}",
+      get(tree::list_tree)
+        .post(tree::copy_tree)
+        .delete(tree::delete_tree),
+    )
+    .route(
+      "/projects",
+      get(projects::list_projects).post(projects::create_project),
+    )
+    .route(
+      "/projects/{project}",
+      patch(projects::update_project).delete(projects::delete_project),
+    )
+    .route("/projects/{project}/import", post(bulk::import_secrets))
+    .route("/projects/{project}/export", get(bulk::export_secrets))
+    .route("/audit", get(audit::list_audit_events))
+    .route(
+      "/tokens",
+      get(tokens::list_tokens).post(tokens::create_token),
+    )
+    .route("/tokens/{id}", delete(tokens::revoke_token))
+    .route("/tokens/{id}/rotate", post(tokens::rotate_token))
+    .layer(timeout)
+    .layer(middleware::from_fn(audit::record))
+    .layer(Extension(state))
+    .layer(PropagateRequestIdLayer::x_request_id())
+    .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
 }
 
 // Request payloads
@@ -38,24 +110,71 @@ pub struct AppState {
 pub struct SecretInput {
   pub key: String,
   pub value: serde_json::Value,
+  #[serde(flatten)]
+  pub expiry: Expiry,
+  /// Only write if this is still the live version; 0 to only create
+  pub expected_version: Option<i32>,
 }
 
 #[derive(Deserialize)]
 pub struct SecretValueOnly {
   pub value: serde_json::Value,
+  #[serde(flatten)]
+  pub expiry: Expiry,
 }
 
 #[derive(Deserialize)]
-pub struct SearchInput {
-  pub query: Option<String>,
+pub struct VersionQuery {
+  pub version: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct RollbackInput {
+  pub version: i32,
+}
+
+// Response payloads
+#[derive(Serialize, sqlx::FromRow)]
+pub struct SecretVersion {
+  pub version: i32,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub created_by: String,
+  pub deleted: bool,
 }
 
 // Extracted headers and auth types
 pub struct ProjectKey(pub String);
-pub struct ReadAuth;
-pub struct WriteAuth;
+pub struct ListAuth {
+  pub principal: Principal,
+}
+pub struct ReadAuth {
+  pub principal: Principal,
+}
+pub struct WriteAuth {
+  /// Recorded as the author of every version this request writes
+  pub principal: Principal,
+}
+pub struct AdminAuth {
+  pub principal: Principal,
+}
 
 // Implement Axum extractors for authentication and project scoping
+impl<S> FromRequestParts<S> for ListAuth
+where
+  S: Send + Sync + 'static,
+{
+  type Rejection = (StatusCode, &'static str);
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    _: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let principal =
+      auth::authorize(parts, Permission::List, "List key invalid").await?;
+    Ok(ListAuth { principal })
+  }
+}
+
 impl<S> FromRequestParts<S> for ReadAuth
 where
   S: Send + Sync + 'static,
@@ -66,15 +185,9 @@ where
     parts: &mut Parts,
     _: &S,
   ) -> Result<Self, Self::Rejection> {
-    let header = parts.headers.get("x-api-key").and_then(|v| v.to_str().ok());
-    let read = std::env::var("API_MASTER_KEY_READ")
-      .expect("API_MASTER_KEY_READ missing");
-    let write = std::env::var("API_MASTER_KEY_WRITE")
-      .expect("API_MASTER_KEY_WRITE missing");
-    match header {
-      Some(key) if key == read || key == write => Ok(ReadAuth),
-      _ => Err((StatusCode::UNAUTHORIZED, "Read key invalid")),
-    }
+    let principal =
+      auth::authorize(parts, Permission::Read, "Read key invalid").await?;
+    Ok(ReadAuth { principal })
   }
 }
 
@@ -88,16 +201,25 @@ where
     parts: &mut Parts,
     _: &S,
   ) -> Result<Self, Self::Rejection> {
-    match parts.headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
-      Some(key)
-        if key
-          == std::env::var("API_MASTER_KEY_WRITE")
-            .expect("API_MASTER_KEY_WRITE missing") =>
-      {
-        Ok(WriteAuth)
-      }
-      _ => Err((StatusCode::UNAUTHORIZED, "Write key invalid")),
-    }
+    let principal =
+      auth::authorize(parts, Permission::Write, "Write key invalid").await?;
+    Ok(WriteAuth { principal })
+  }
+}
+
+impl<S> FromRequestParts<S> for AdminAuth
+where
+  S: Send + Sync + 'static,
+{
+  type Rejection = (StatusCode, &'static str);
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    _: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let principal =
+      auth::authorize(parts, Permission::Admin, "Admin key invalid").await?;
+    Ok(AdminAuth { principal })
   }
 }
 
@@ -120,14 +242,159 @@ where
   }
 }
 
-// GET /secrets/:key
+pub(crate) fn query_error(err: String) -> Response {
+  (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    format!("Query error: {}", err),
+  )
+    .into_response()
+}
+
+pub(crate) fn db_error(context: &str, err: sqlx::Error) -> Response {
+  tracing::error!("Database error {}: {}", context, err);
+  (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response()
+}
+
+/// A live secret, decrypted.
+pub(crate) struct OpenedSecret {
+  pub key: String,
+  pub value: serde_json::Value,
+  pub version: i32,
+  pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(sqlx::FromRow)]
+pub(crate) struct StoredSecret {
+  secret_key: String,
+  ciphertext: Vec<u8>,
+  wrapped_key: Vec<u8>,
+  key_id: String,
+  version: i32,
+  expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A sealed value as `get_secret` loads it
+#[derive(sqlx::FromRow)]
+struct StoredValue {
+  #[sqlx(flatten)]
+  sealed: SealedValue,
+  version: i32,
+  expired: bool,
+}
+
+/// Decrypt every live secret of `project`, ordered by key.
+pub(crate) async fn open_project_secrets(
+  state: &AppState,
+  project: &str,
+) -> Result<Vec<OpenedSecret>, Response> {
+  let sql = state.queries.get("list_secrets").map_err(query_error)?;
+  let rows: Vec<StoredSecret> = sqlx::query_as(sql)
+    .bind(project)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|err| db_error("loading secrets", err))?;
+  open_secrets(state, project, rows).map_err(IntoResponse::into_response)
+}
+
+/// Decrypt rows of `project` loaded with a `list_secrets`-shaped query.
+pub(crate) fn open_secrets(
+  state: &AppState,
+  project: &str,
+  rows: Vec<StoredSecret>,
+) -> Result<Vec<OpenedSecret>, (StatusCode, &'static str)> {
+  let mut secrets = Vec::with_capacity(rows.len());
+  for row in rows {
+    let sealed = SealedValue {
+      ciphertext: row.ciphertext,
+      wrapped_key: row.wrapped_key,
+      key_id: row.key_id,
+    };
+    match state.master_key.open(project, &row.secret_key, &sealed) {
+      Ok(value) => secrets.push(OpenedSecret {
+        key: row.secret_key,
+        value,
+        version: row.version,
+        expires_at: row.expires_at,
+      }),
+      Err(err) => {
+        tracing::error!("Failed to open secret '{}': {}", row.secret_key, err);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Decryption error"));
+      }
+    }
+  }
+  Ok(secrets)
+}
+
+/// `GET /secrets/*key`: a trailing `/versions` segment asks for the key's
+/// history instead of its value.
+pub async fn read_secret_path(
+  auth: ReadAuth,
+  project: ProjectKey,
+  audit: AuditTrail,
+  Path(key): Path<String>,
+  params: Query<VersionQuery>,
+  state: Extension<AppState>,
+) -> Response {
+  match key.strip_suffix(tree::VERSIONS_SUFFIX) {
+    Some(key) => {
+      audit.set_action("list_versions");
+      audit.set_key(key);
+      list_secret_versions(auth, project, Path(key.to_string()), state)
+        .await
+        .into_response()
+    }
+    None => get_secret(auth, project, Path(key), params, state)
+      .await
+      .into_response(),
+  }
+}
+
+/// `POST /secrets/*key`: only `.../rollback` is a route. The body is only
+/// parsed once the path is known to be one, so other keys get a 405.
+pub async fn rollback_secret_path(
+  auth: WriteAuth,
+  project: ProjectKey,
+  audit: AuditTrail,
+  Path(key): Path<String>,
+  state: Extension<AppState>,
+  request: Request,
+) -> Response {
+  match key.strip_suffix(tree::ROLLBACK_SUFFIX) {
+    Some(key) => {
+      audit.set_key(key);
+      let headers = request.headers().clone();
+      let payload = match Json::from_request(request, &()).await {
+        Ok(payload) => payload,
+        Err(rejection) => return rejection.into_response(),
+      };
+      rollback_secret(
+        auth,
+        project,
+        Path(key.to_string()),
+        state,
+        headers,
+        payload,
+      )
+      .await
+      .into_response()
+    }
+    None => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+  }
+}
+
+// GET /secrets/*key[?version=N]
 pub async fn get_secret(
   _auth: ReadAuth,
   ProjectKey(project): ProjectKey,
   Path(key): Path<String>,
+  Query(params): Query<VersionQuery>,
   Extension(state): Extension<AppState>,
 ) -> impl IntoResponse {
-  let sql = match state.queries.get("get_secret") {
+  let query_name = match params.version {
+    Some(_) => "get_secret_version",
+    None => "get_secret",
+  };
+  let sql = match state.queries.get(query_name) {
     Ok(q) => q,
     Err(err) => {
       return (
@@ -138,14 +405,35 @@ pub async fn get_secret(
     }
   };
 
-  let rec: Result<Option<(serde_json::Value,)>, _> = sqlx::query_as(sql)
-    .bind(&key)
-    .bind(&project)
-    .fetch_optional(&state.read_pool)
-    .await;
+  let mut query = sqlx::query_as(sql).bind(&key).bind(&project);
+  if let Some(version) = params.version {
+    query = query.bind(version);
+  }
+  let rec: Result<Option<StoredValue>, _> =
+    query.fetch_optional(&state.read_pool).await;
 
   match rec {
-    Ok(Some((value,))) => (StatusCode::OK, Json(value)).into_response(),
+    Ok(Some(StoredValue { expired: true, .. })) => {
+      (StatusCode::GONE, "Expired").into_response()
+    }
+    Ok(Some(StoredValue { sealed, version, .. })) => {
+      match state.master_key.open(&project, &key, &sealed) {
+        // An ETag names the live version; handing out a historical one
+        // would let an `If-Match` write check against the wrong state.
+        Ok(value) if params.version.is_some() => {
+          (StatusCode::OK, Json(value)).into_response()
+        }
+        Ok(value) => {
+          (StatusCode::OK, [(header::ETAG, etag(version))], Json(value))
+            .into_response()
+        }
+        Err(err) => {
+          tracing::error!("Failed to open secret '{}': {}", key, err);
+          (StatusCode::INTERNAL_SERVER_ERROR, "Decryption error")
+            .into_response()
+        }
+      }
+    }
     Ok(None) => (StatusCode::NOT_FOUND, "Not found").into_response(),
     Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response(),
   }
@@ -154,44 +442,170 @@ pub async fn get_secret(
 // POST /secrets
 #[axum::debug_handler]
 pub async fn upsert_secret(
-  _auth: WriteAuth,
+  auth: WriteAuth,
   ProjectKey(project): ProjectKey,
+  audit: AuditTrail,
   Extension(state): Extension<AppState>,
   Json(payload): Json<SecretInput>,
 ) -> impl IntoResponse {
+  audit.set_key(&payload.key);
+  if let Err(err) = tree::check_key(&payload.key) {
+    return (StatusCode::BAD_REQUEST, err).into_response();
+  }
+  let expires_at = match payload.expiry.resolve(chrono::Utc::now()) {
+    Ok(expires_at) => expires_at,
+    Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+  };
+  store_secret(
+    &state,
+    &project,
+    &payload.key,
+    &payload.value,
+    expires_at,
+    payload.expected_version,
+    &auth.principal.label,
+  )
+  .await
+}
+
+// PUT /secrets/*key  [If-Match: "N" | If-None-Match: *]
+pub async fn upsert_secret_by_path(
+  auth: WriteAuth,
+  ProjectKey(project): ProjectKey,
+  Path(key): Path<String>,
+  Extension(state): Extension<AppState>,
+  headers: HeaderMap,
+  Json(payload): Json<SecretValueOnly>,
+) -> impl IntoResponse {
+  if let Err(err) = tree::check_key(&key) {
+    return (StatusCode::BAD_REQUEST, err).into_response();
+  }
+  let expires_at = match payload.expiry.resolve(chrono::Utc::now()) {
+    Ok(expires_at) => expires_at,
+    Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+  };
+  let preconditions = Preconditions::from_headers(&headers);
+  let expected =
+    match expected_version(&state, &project, &key, &preconditions).await {
+      Ok(expected) => expected,
+      Err(response) => return response,
+    };
+  store_secret(
+    &state,
+    &project,
+    &key,
+    &payload.value,
+    expires_at,
+    expected,
+    &auth.principal.label,
+  )
+  .await
+}
+
+fn precondition_failed() -> Response {
+  (
+    StatusCode::PRECONDITION_FAILED,
+    "Secret changed since it was read",
+  )
+    .into_response()
+}
+
+/// Check `preconditions` against the live version of `key` and return the
+/// version the write must still find (0 for none), or `None` when the
+/// request is unconditional.
+async fn expected_version(
+  state: &AppState,
+  project: &str,
+  key: &str,
+  preconditions: &Preconditions,
+) -> Result<Option<i32>, Response> {
+  if preconditions.is_empty() {
+    return Ok(None);
+  }
+  let sql = state
+    .queries
+    .get("get_current_version")
+    .map_err(query_error)?;
+  let current: Option<i32> = sqlx::query_scalar(sql)
+    .bind(key)
+    .bind(project)
+    .fetch_optional(&state.write_pool)
+    .await
+    .map_err(|err| db_error("reading current version", err))?;
+  if !preconditions.allow(current) {
+    return Err(precondition_failed());
+  }
+  Ok(Some(current.unwrap_or(0)))
+}
+
+/// Seal and append a new version of `key`. With `expected`, the write only
+/// happens if the live version is still that one (0 for none); the new
+/// version is returned as the ETag.
+async fn store_secret(
+  state: &AppState,
+  project: &str,
+  key: &str,
+  value: &serde_json::Value,
+  expires_at: Option<chrono::DateTime<chrono::Utc>>,
+  expected: Option<i32>,
+  author: &str,
+) -> Response {
   let sql = match state.queries.get("upsert_secret") {
     Ok(q) => q,
+    Err(err) => return query_error(err),
+  };
+
+  let sealed = match state.master_key.seal(project, key, value) {
+    Ok(sealed) => sealed,
     Err(err) => {
-      return (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        format!("Query error: {}", err),
-      )
+      tracing::error!("Failed to seal secret: {}", err);
+      return (StatusCode::INTERNAL_SERVER_ERROR, "Encryption error")
         .into_response();
     }
   };
 
-  let result = sqlx::query(sql)
-    .bind(&project)
-    .bind(&payload.key)
-    .bind(&payload.value)
-    .execute(&state.write_pool)
+  let (size, content_type) = search::value_metadata(value);
+  let result: Result<Option<i32>, _> = sqlx::query_scalar(sql)
+    .bind(project)
+    .bind(key)
+    .bind(&sealed.ciphertext)
+    .bind(&sealed.wrapped_key)
+    .bind(&sealed.key_id)
+    .bind(author)
+    .bind(expires_at)
+    .bind(expected)
+    .bind(size)
+    .bind(content_type)
+    .fetch_optional(&state.write_pool)
     .await;
 
   match result {
-    Ok(_) => StatusCode::NO_CONTENT.into_response(),
+    Ok(Some(version)) => {
+      (StatusCode::NO_CONTENT, [(header::ETAG, etag(version))]).into_response()
+    }
+    Ok(None) => precondition_failed(),
+    // A concurrent write took the version this one was checked against
+    Err(sqlx::Error::Database(err))
+      if err.is_unique_violation() && expected.is_some() =>
+    {
+      precondition_failed()
+    }
+    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+      (StatusCode::CONFLICT, "Concurrent write, retry").into_response()
+    }
     Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response(),
   }
 }
 
-// PUT /secrets/:key
-pub async fn upsert_secret_by_path(
-  _auth: WriteAuth,
+// DELETE /secrets/*key  [If-Match: "N"]
+pub async fn delete_secret(
+  auth: WriteAuth,
   ProjectKey(project): ProjectKey,
   Path(key): Path<String>,
   Extension(state): Extension<AppState>,
-  Json(payload): Json<SecretValueOnly>,
+  headers: HeaderMap,
 ) -> impl IntoResponse {
-  let sql = match state.queries.get("upsert_secret") {
+  let sql = match state.queries.get("delete_secret") {
     Ok(q) => q,
     Err(err) => {
       return (
@@ -201,28 +615,49 @@ pub async fn upsert_secret_by_path(
         .into_response();
     }
   };
+  let preconditions = Preconditions::from_headers(&headers);
+  let expected =
+    match expected_version(&state, &project, &key, &preconditions).await {
+      // Only `If-None-Match` lets a missing secret through, and there is
+      // nothing to delete
+      Ok(Some(0)) => {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+      }
+      Ok(expected) => expected,
+      Err(response) => return response,
+    };
 
+  // Deleting appends a tombstone version; history is kept
   let result = sqlx::query(sql)
-    .bind(&project)
     .bind(&key)
-    .bind(&payload.value)
+    .bind(&project)
+    .bind(&auth.principal.label)
+    .bind(expected)
     .execute(&state.write_pool)
     .await;
 
   match result {
+    Ok(done) if expected.is_some() && done.rows_affected() == 0 => {
+      precondition_failed()
+    }
     Ok(_) => StatusCode::NO_CONTENT.into_response(),
+    Err(sqlx::Error::Database(err))
+      if err.is_unique_violation() && expected.is_some() =>
+    {
+      precondition_failed()
+    }
     Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response(),
   }
 }
 
-// DELETE /secrets/:key
-pub async fn delete_secret(
-  _auth: WriteAuth,
+// GET /secrets/*key/versions
+pub async fn list_secret_versions(
+  _auth: ReadAuth,
   ProjectKey(project): ProjectKey,
   Path(key): Path<String>,
   Extension(state): Extension<AppState>,
 ) -> impl IntoResponse {
-  let sql = match state.queries.get("delete_secret") {
+  let sql = match state.queries.get("list_secret_versions") {
     Ok(q) => q,
     Err(err) => {
       return (
@@ -233,86 +668,79 @@ pub async fn delete_secret(
     }
   };
 
-  let result = sqlx::query(sql)
+  let result: Result<Vec<SecretVersion>, _> = sqlx::query_as(sql)
     .bind(&key)
     .bind(&project)
-    .execute(&state.write_pool)
+    .fetch_all(&state.read_pool)
     .await;
 
   match result {
-    Ok(_) => StatusCode::NO_CONTENT.into_response(),
+    Ok(versions) if versions.is_empty() => {
+      (StatusCode::NOT_FOUND, "Not found").into_response()
+    }
+    Ok(versions) => (StatusCode::OK, Json(versions)).into_response(),
     Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response(),
   }
 }
 
-// POST /search
-pub async fn search_secrets(
-  _auth: ReadAuth,
+// POST /secrets/*key/rollback  [If-Match: "N"]
+pub async fn rollback_secret(
+  auth: WriteAuth,
   ProjectKey(project): ProjectKey,
+  Path(key): Path<String>,
   Extension(state): Extension<AppState>,
-  Json(payload): Json<SearchInput>,
+  headers: HeaderMap,
+  Json(payload): Json<RollbackInput>,
 ) -> impl IntoResponse {
-  // Return Response directly to handle errors
-  let raw_query = payload.query.unwrap_or_default();
-
-  // 1) Attempt to parse the raw query into a SQL WHERE clause
-  let where_clause = match query_to_sql(&raw_query) {
-    Ok(clause) => clause,
-    Err(parse_err) => {
-      tracing::warn!(
-        "Query parsing failed: {:?} for query: '{}'",
-        parse_err,
-        raw_query
-      );
-      // Return 400 Bad Request on parse error
+  let sql = match state.queries.get("rollback_secret") {
+    Ok(q) => q,
+    Err(err) => {
       return (
-        StatusCode::BAD_REQUEST,
-        // Provide a user-friendly error message from the Display impl
-        format!("Invalid search query syntax. Error details: {}", parse_err),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Query error: {}", err),
       )
         .into_response();
     }
   };
-
-  // 2) Build the final SQL query safely
-  let sql = format!(
-    "SELECT secret_key, project_key, secret_value FROM secrets WHERE \
-     project_key = $1 AND ({})",
-    where_clause // Inject the parsed and validated WHERE clause
-  );
-
-  tracing::debug!("🔍 Raw query = {:?}", raw_query);
-  tracing::debug!("🔍 Generated SQL = {}", sql); // Log the full SQL for debugging
-
-  // 3) Execute the query
-  let result = sqlx::query_as::<_, (String, String, serde_json::Value)>(&sql)
+  let preconditions = Preconditions::from_headers(&headers);
+  let expected =
+    match expected_version(&state, &project, &key, &preconditions).await {
+      Ok(expected) => expected,
+      Err(response) => return response,
+    };
+
+  // The restored version is appended as a copy of the sealed value, so the
+  // rollback itself shows up in the history. It keeps its expiry, so one
+  // that has passed cannot be restored.
+  let result: Result<Option<(Option<i32>, bool)>, _> = sqlx::query_as(sql)
+    .bind(&key)
     .bind(&project)
-    .fetch_all(&state.read_pool)
+    .bind(payload.version)
+    .bind(&auth.principal.label)
+    .bind(expected)
+    .fetch_optional(&state.write_pool)
     .await;
 
   match result {
-    Ok(rows) => {
-      // 4) Format and return results as JSON
-      let secrets = rows
-        .into_iter()
-        .map(|(k, p, v)| {
-          serde_json::json!({
-              "secret_key": k,
-              "project_key": p,
-              "secret_value": v,
-          })
-        })
-        .collect::<Vec<_>>();
-      (StatusCode::OK, Json(secrets)).into_response()
+    Ok(Some((Some(version), _))) => (
+      StatusCode::OK,
+      [(header::ETAG, etag(version))],
+      Json(serde_json::json!({ "version": version })),
+    )
+      .into_response(),
+    Ok(Some((None, true))) => {
+      (StatusCode::GONE, "Version has expired").into_response()
     }
-    Err(db_err) => {
-      tracing::error!("Database error during search: {}", db_err);
-      // Handle potential DB errors (e.g., invalid SQL generated, connection issues)
-      (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        "Database error executing search".to_string(), // Keep DB details internal
-      )
-        .into_response()
+    Ok(Some((None, false))) => precondition_failed(),
+    Ok(None) => (StatusCode::NOT_FOUND, "Version not found").into_response(),
+    Err(sqlx::Error::Database(err))
+      if err.is_unique_violation() && expected.is_some() =>
+    {
+      precondition_failed()
     }
+    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+      (StatusCode::CONFLICT, "Concurrent write, retry").into_response()
+    }
+    Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response(),
   }
 }
@@ -0,0 +1,635 @@
+use chrono::{DateTime, Utc};
+use pest::{Parser, iterators::Pair};
+use regex::{Regex, RegexBuilder};
+use serde_json::{Number, Value};
+use std::{borrow::Cow, cmp::Ordering};
+
+use crate::auth::glob_match;
+use crate::lucene_parser::{
+  Bound, PathStep, QueryParseError, QueryParser, Range, Rule, TIME_COLUMNS,
+  Token, is_sep, is_ws, next_non_ws, parse_time, regex_pattern,
+};
+
+/// Largest compiled size of a `/regex/`, in bytes
+const MAX_REGEX_SIZE: usize = 1 << 20;
+
+/// What a query sees of one secret. `value` may be left out until a
+/// condition needs it; see [`Filter::matches`].
+pub struct Candidate<'a> {
+  pub key: &'a str,
+  pub value: Option<&'a Value>,
+  pub updated_at: DateTime<Utc>,
+  pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A search query compiled for evaluation on the server, so decrypted values
+/// never leave it.
+pub struct Filter(Node);
+
+impl Filter {
+  /// Whether `candidate` matches, or `None` if that depends on its value and
+  /// the value was left out.
+  pub fn matches(&self, candidate: &Candidate) -> Option<bool> {
+    self.0.eval(candidate)
+  }
+
+  /// Whether some condition looks at values, so matching may mean opening
+  /// them.
+  pub fn reads_values(&self) -> bool {
+    self.0.reads_values()
+  }
+}
+
+/// Text compared case-insensitively
+#[derive(Clone)]
+enum TextMatch {
+  /// Lowercase substring
+  Contains(String),
+  /// Lowercase glob that must cover the whole text
+  Wildcard(String),
+  Regex(Regex),
+}
+
+impl TextMatch {
+  fn like(token: &Token) -> TextMatch {
+    if token.wildcard {
+      TextMatch::Wildcard(token.text.to_lowercase())
+    } else {
+      TextMatch::Contains(token.text.to_lowercase())
+    }
+  }
+
+  fn is_match(&self, text: &str) -> bool {
+    match self {
+      TextMatch::Contains(needle) => text.to_lowercase().contains(needle),
+      TextMatch::Wildcard(pattern) => glob_match(pattern, &text.to_lowercase()),
+      TextMatch::Regex(regex) => regex.is_match(text),
+    }
+  }
+}
+
+/// The fields a `field:...` condition looks at
+enum Fields {
+  /// The top-level field of an object value
+  Top(String),
+  /// Top-level fields of an object value whose name matches the glob
+  Named(String),
+  /// What the path selects
+  Path(Vec<PathStep>),
+}
+
+/// What a field's value must be
+enum FieldTest {
+  /// Its text, strings unquoted
+  Text(TextMatch),
+  /// Equal to a number, boolean or string
+  Equals(Value),
+}
+
+/// One end of a range over JSON values, times or keys
+struct End<T> {
+  bound: T,
+  inclusive: bool,
+}
+
+/// Whether what `cmp` compares with the bounds lies between the ends given.
+fn within<T>(
+  lower: &Option<End<T>>,
+  upper: &Option<End<T>>,
+  cmp: impl Fn(&T) -> Option<Ordering>,
+) -> bool {
+  let admits = |end: &Option<End<T>>, beyond: Ordering| {
+    end.as_ref().is_none_or(|end| {
+      cmp(&end.bound).is_some_and(|o| o == beyond || end.inclusive && o.is_eq())
+    })
+  };
+  admits(lower, Ordering::Greater) && admits(upper, Ordering::Less)
+}
+
+/// Node of a compiled query
+enum Node {
+  All,
+  And(Vec<Node>),
+  Or(Vec<Node>),
+  Not(Box<Node>),
+  /// `secret_key`
+  Key(TextMatch),
+  /// The value as JSON text
+  ValueJson(TextMatch),
+  /// Strings unquoted, anything else as JSON
+  ValueText(TextMatch),
+  /// An object value with this field equal to this
+  FieldEquals(String, Value),
+  /// Something selected by the path equals this
+  PathEquals(Vec<PathStep>, Value),
+  /// Some selected field passes the test
+  Field(Fields, FieldTest),
+  /// Some field is selected, whatever it holds
+  Exists(Fields),
+  /// Something the path selects falls in the range
+  ValueRange(Vec<PathStep>, Option<End<Value>>, Option<End<Value>>),
+  /// `updated_at`, or `expires_at` when set, falls in the range
+  TimeRange(bool, Option<End<DateTime<Utc>>>, Option<End<DateTime<Utc>>>),
+  KeyRange(Option<End<String>>, Option<End<String>>),
+}
+
+impl Node {
+  /// Three-valued: `None` when the value is needed but missing.
+  fn eval(&self, candidate: &Candidate) -> Option<bool> {
+    let value = || candidate.value;
+    match self {
+      Node::All => Some(true),
+      Node::And(nodes) => {
+        let mut result = Some(true);
+        for node in nodes {
+          match node.eval(candidate) {
+            Some(false) => return Some(false),
+            None => result = None,
+            Some(true) => {}
+          }
+        }
+        result
+      }
+      Node::Or(nodes) => {
+        let mut result = Some(false);
+        for node in nodes {
+          match node.eval(candidate) {
+            Some(true) => return Some(true),
+            None => result = None,
+            Some(false) => {}
+          }
+        }
+        result
+      }
+      Node::Not(node) => node.eval(candidate).map(|matched| !matched),
+      Node::Key(text) => Some(text.is_match(candidate.key)),
+      Node::ValueJson(text) => {
+        value().map(|value| text.is_match(&jsonb_text(value)))
+      }
+      Node::ValueText(text) => value().map(|value| {
+        unquoted_text(value).is_some_and(|value| text.is_match(&value))
+      }),
+      Node::FieldEquals(field, expected) => value().map(|value| {
+        value
+          .as_object()
+          .and_then(|object| object.get(field))
+          .is_some_and(|found| json_eq(found, expected))
+      }),
+      Node::PathEquals(steps, expected) => value().map(|value| {
+        filtered(select(value, steps)).any(|found| json_eq(found, expected))
+      }),
+      Node::Field(fields, test) => value().map(|value| {
+        selected(value, fields).any(|found| match test {
+          FieldTest::Text(text) => {
+            unquoted_text(found).is_some_and(|found| text.is_match(&found))
+          }
+          FieldTest::Equals(expected) => json_eq(found, expected),
+        })
+      }),
+      Node::Exists(fields) => {
+        value().map(|value| selected(value, fields).next().is_some())
+      }
+      Node::ValueRange(steps, lower, upper) => value().map(|value| {
+        let found = select(value, steps);
+        if lower.is_none() && upper.is_none() {
+          return !found.is_empty();
+        }
+        filtered(found)
+          .any(|found| within(lower, upper, |bound| json_cmp(found, bound)))
+      }),
+      Node::TimeRange(expires, lower, upper) => {
+        let at = if *expires {
+          candidate.expires_at
+        } else {
+          Some(candidate.updated_at)
+        };
+        Some(
+          at.is_some_and(|at| {
+            within(lower, upper, |bound| Some(at.cmp(bound)))
+          }),
+        )
+      }
+      Node::KeyRange(lower, upper) => Some(within(lower, upper, |bound| {
+        Some(candidate.key.cmp(bound.as_str()))
+      })),
+    }
+  }
+
+  fn reads_values(&self) -> bool {
+    match self {
+      Node::All | Node::Key(_) | Node::TimeRange(..) | Node::KeyRange(..) => {
+        false
+      }
+      Node::And(nodes) | Node::Or(nodes) => {
+        nodes.iter().any(Node::reads_values)
+      }
+      Node::Not(node) => node.reads_values(),
+      _ => true,
+    }
+  }
+}
+
+/// What `steps` selects in `value`, read as a lax jsonpath: a field of an
+/// array is looked up in each element, and `[0]` of anything else is itself.
+fn select<'v>(value: &'v Value, steps: &[PathStep]) -> Vec<&'v Value> {
+  let mut found = vec![value];
+  for step in steps {
+    let mut next = Vec::new();
+    for item in found {
+      match (step, item) {
+        (PathStep::Field(name), Value::Object(object)) => {
+          next.extend(object.get(name))
+        }
+        (PathStep::Field(name), Value::Array(elements)) => next.extend(
+          elements
+            .iter()
+            .filter_map(|element| element.as_object()?.get(name)),
+        ),
+        (PathStep::Index(i), Value::Array(elements)) => {
+          next.extend(elements.get(*i as usize))
+        }
+        (PathStep::Index(0), item) => next.push(item),
+        _ => {}
+      }
+    }
+    found = next;
+  }
+  found
+}
+
+/// What a jsonpath filter tests of `found`: the elements of arrays, anything
+/// else as it is.
+fn filtered(found: Vec<&Value>) -> impl Iterator<Item = &Value> {
+  found.into_iter().flat_map(|item| match item {
+    Value::Array(elements) => elements.iter().collect::<Vec<_>>(),
+    item => vec![item],
+  })
+}
+
+/// The values of the fields `fields` names in `value`.
+fn selected<'v>(
+  value: &'v Value,
+  fields: &'v Fields,
+) -> Box<dyn Iterator<Item = &'v Value> + 'v> {
+  match fields {
+    Fields::Top(name) => Box::new(
+      value
+        .as_object()
+        .and_then(|object| object.get(name))
+        .into_iter(),
+    ),
+    Fields::Named(pattern) => Box::new(
+      value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| glob_match(pattern, &name.to_lowercase()))
+        .map(|(_, field)| field),
+    ),
+    Fields::Path(steps) => Box::new(select(value, steps).into_iter()),
+  }
+}
+
+fn number_cmp(a: &Number, b: &Number) -> Option<Ordering> {
+  if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+    return Some(a.cmp(&b));
+  }
+  if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+    return Some(a.cmp(&b));
+  }
+  a.as_f64()?.partial_cmp(&b.as_f64()?)
+}
+
+/// Order of two JSON scalars of the same type; `None` for anything else.
+fn json_cmp(a: &Value, b: &Value) -> Option<Ordering> {
+  match (a, b) {
+    (Value::Number(a), Value::Number(b)) => number_cmp(a, b),
+    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+    (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+    _ => None,
+  }
+}
+
+fn json_eq(a: &Value, b: &Value) -> bool {
+  json_cmp(a, b) == Some(Ordering::Equal)
+}
+
+/// A string's own text, anything else as JSON text; `None` for `null`.
+fn unquoted_text(value: &Value) -> Option<Cow<'_, str>> {
+  match value {
+    Value::Null => None,
+    Value::String(text) => Some(Cow::Borrowed(text)),
+    value => Some(Cow::Owned(jsonb_text(value))),
+  }
+}
+
+/// `value` as Postgres prints `jsonb`: `", "` and `": "` separators and
+/// object fields ordered by length, then bytes.
+fn jsonb_text(value: &Value) -> String {
+  let mut text = String::new();
+  write_jsonb(value, &mut text);
+  text
+}
+
+fn write_jsonb(value: &Value, out: &mut String) {
+  match value {
+    Value::Array(elements) => {
+      out.push('[');
+      for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+          out.push_str(", ");
+        }
+        write_jsonb(element, out);
+      }
+      out.push(']');
+    }
+    Value::Object(object) => {
+      let mut fields: Vec<_> = object.iter().collect();
+      fields.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then(a.cmp(b)));
+      out.push('{');
+      for (i, (name, field)) in fields.into_iter().enumerate() {
+        if i > 0 {
+          out.push_str(", ");
+        }
+        out.push_str(&Value::from(name.as_str()).to_string());
+        out.push_str(": ");
+        write_jsonb(field, out);
+      }
+      out.push('}');
+    }
+    scalar => out.push_str(&scalar.to_string()),
+  }
+}
+
+/// Compile a raw Lucene-style query for [`Filter::matches`].
+pub fn query_to_filter(raw: &str) -> Result<Filter, QueryParseError> {
+  let q = raw.trim();
+  if q.is_empty() {
+    return Ok(Filter(Node::All));
+  }
+  let mut pairs = QueryParser::parse(Rule::expression, q)
+    .map_err(|e| QueryParseError::SyntaxError(Box::new(e)))?;
+  let expr_pair = pairs
+    .next()
+    .ok_or_else(|| QueryParseError::InternalError("Empty parse tree".into()))?;
+  build(expr_pair).map(Filter)
+}
+
+fn compile_regex(pair: &Pair<Rule>) -> Result<Regex, QueryParseError> {
+  let pattern = regex_pattern(pair)?;
+  RegexBuilder::new(&pattern)
+    .case_insensitive(true)
+    // Postgres regexes are not newline-sensitive
+    .dot_matches_new_line(true)
+    .size_limit(MAX_REGEX_SIZE)
+    .build()
+    .map_err(|err| {
+      QueryParseError::at(
+        pair.as_span(),
+        format!("Invalid regular expression: {}", err),
+      )
+    })
+}
+
+/// Operands of an `or_expr` or `and_expr`.
+fn operands(pair: Pair<Rule>) -> Result<Vec<Node>, QueryParseError> {
+  pair
+    .into_inner()
+    .filter(|p| !is_sep(p))
+    .map(build)
+    .collect()
+}
+
+/// Recursively walk the parse tree.
+fn build(pair: Pair<Rule>) -> Result<Node, QueryParseError> {
+  match pair.as_rule() {
+    Rule::expression | Rule::primary | Rule::grouped => {
+      let mut inner = pair.into_inner();
+      let expr = next_non_ws(&mut inner).ok_or_else(|| {
+        QueryParseError::InternalError("Empty expression".into())
+      })?;
+      build(expr)
+    }
+    Rule::or_expr => {
+      let mut nodes = operands(pair)?;
+      Ok(if nodes.len() == 1 {
+        nodes.pop().unwrap()
+      } else {
+        Node::Or(nodes)
+      })
+    }
+    Rule::and_expr => {
+      let mut nodes = operands(pair)?;
+      Ok(if nodes.len() == 1 {
+        nodes.pop().unwrap()
+      } else {
+        Node::And(nodes)
+      })
+    }
+    Rule::not_expr => {
+      let mut negated = false;
+      for p in pair.into_inner().filter(|p| !is_ws(p)) {
+        if p.as_rule() == Rule::NOT_OP {
+          negated = true;
+          continue;
+        }
+        let node = build(p)?;
+        return Ok(if negated {
+          Node::Not(Box::new(node))
+        } else {
+          node
+        });
+      }
+      Err(QueryParseError::InternalError("Missing NOT target".into()))
+    }
+    Rule::key_value => build_key_value(pair),
+    Rule::regex => {
+      let regex = compile_regex(&pair)?;
+      Ok(Node::Or(vec![
+        Node::Key(TextMatch::Regex(regex.clone())),
+        Node::ValueText(TextMatch::Regex(regex)),
+      ]))
+    }
+    Rule::exists_field => {
+      let field = pair
+        .into_inner()
+        .next()
+        .and_then(|key| key.into_inner().next())
+        .ok_or_else(|| {
+          QueryParseError::InternalError("Missing field in _exists_".into())
+        })?;
+      Ok(exists(&Token::from_pair(&field)?))
+    }
+    Rule::phrase => {
+      let quoted = pair.into_inner().next().ok_or_else(|| {
+        QueryParseError::InternalError("Missing phrase text".into())
+      })?;
+      Ok(term(&Token::from_pair(&quoted)?))
+    }
+    Rule::term => Ok(term(&Token::new(pair.as_str().to_string(), false))),
+    other => Err(QueryParseError::InternalError(format!(
+      "Unexpected rule encountered: {:?}",
+      other
+    ))),
+  }
+}
+
+/// A bare word or phrase: the key, or the value as JSON text; a wildcard
+/// must cover the key or the value's text.
+fn term(token: &Token) -> Node {
+  let text = TextMatch::like(token);
+  let value = if token.wildcard {
+    Node::ValueText(text.clone())
+  } else {
+    Node::ValueJson(text.clone())
+  };
+  Node::Or(vec![Node::Key(text), value])
+}
+
+/// The fields a key names: top-level names for a wildcard, else a path.
+fn fields(key: &Token) -> Fields {
+  if key.wildcard {
+    return Fields::Named(key.text.to_lowercase());
+  }
+  Fields::Path(
+    key
+      .path()
+      .unwrap_or_else(|| vec![PathStep::Field(key.text.clone())]),
+  )
+}
+
+/// `_exists_:field` or `field:*`; a plain name only counts as a field of an
+/// object value.
+fn exists(field: &Token) -> Node {
+  match field.path() {
+    None if !field.wildcard => Node::Exists(Fields::Top(field.text.clone())),
+    _ => Node::Exists(fields(field)),
+  }
+}
+
+fn build_key_value(pair: Pair<Rule>) -> Result<Node, QueryParseError> {
+  let mut iter = pair.into_inner().filter(|p| !is_ws(p));
+  let (Some(key_pair), Some(value_pair)) = (iter.next(), iter.next()) else {
+    return Err(QueryParseError::InternalError(
+      "Missing key or value in key_value rule".into(),
+    ));
+  };
+  let key_inner = key_pair.into_inner().next().ok_or_else(|| {
+    QueryParseError::InternalError("Missing inner pair for key rule".into())
+  })?;
+  let value_inner = value_pair.into_inner().next().ok_or_else(|| {
+    QueryParseError::InternalError("Missing inner pair for value rule".into())
+  })?;
+  let key = Token::from_pair(&key_inner)?;
+
+  match value_inner.as_rule() {
+    Rule::regex => {
+      let text = TextMatch::Regex(compile_regex(&value_inner)?);
+      return Ok(match key.text.as_str() {
+        _ if !key.is_column() => {
+          Node::Field(fields(&key), FieldTest::Text(text))
+        }
+        "secret_key" => Node::Key(text),
+        _ => Node::ValueText(text),
+      });
+    }
+    Rule::range | Rule::comparison => {
+      if key.wildcard {
+        return Err(QueryParseError::at(
+          key_inner.as_span(),
+          "Ranges need a field name without wildcards",
+        ));
+      }
+      return build_range(&key, value_inner);
+    }
+    _ => {}
+  }
+
+  let value = Token::from_pair(&value_inner)?;
+  if value.text == "*" && !value.quoted && !key.is_column() {
+    return Ok(exists(&key));
+  }
+  if key.is_column() {
+    let text = TextMatch::like(&value);
+    return Ok(match key.text.as_str() {
+      "secret_key" => Node::Key(text),
+      _ if value.wildcard => Node::ValueText(text),
+      _ => Node::ValueJson(text),
+    });
+  }
+  // The key and value text, or the field the key names
+  let key_text = Node::Key(TextMatch::like(&key));
+  let (value_text, field) = if key.wildcard || value.wildcard {
+    let test = if value.wildcard {
+      FieldTest::Text(TextMatch::like(&value))
+    } else {
+      FieldTest::Equals(value.json())
+    };
+    (
+      Node::ValueText(TextMatch::like(&value)),
+      Node::Field(fields(&key), test),
+    )
+  } else {
+    let field = match key.path() {
+      None => Node::FieldEquals(key.text.clone(), value.json()),
+      Some(steps) => Node::PathEquals(steps, value.json()),
+    };
+    (Node::ValueJson(TextMatch::like(&value)), field)
+  };
+  Ok(Node::Or(vec![Node::And(vec![key_text, value_text]), field]))
+}
+
+/// `key:[a TO b]` or `key:>a`: JSON fields compare by type, numbers with
+/// numbers and strings with strings; `updated_at` and `expires_at` take
+/// times and `secret_key` compares as text.
+fn build_range(
+  key: &Token,
+  value: Pair<Rule>,
+) -> Result<Node, QueryParseError> {
+  let value_span = value.as_span();
+  let range = Range::from_pair(value)?;
+  let path = key.path();
+  if path.is_none() && TIME_COLUMNS.contains(&key.text.as_str()) {
+    let time = |end: Option<&Bound>| {
+      end
+        .map(|end| {
+          let bound = parse_time(&end.token.text).ok_or_else(|| {
+            QueryParseError::at(
+              value_span,
+              format!("'{}' is not a date or RFC 3339 time", end.token.text),
+            )
+          })?;
+          Ok(End { bound, inclusive: end.inclusive })
+        })
+        .transpose()
+    };
+    return Ok(Node::TimeRange(
+      key.text == "expires_at",
+      time(range.lower.as_ref())?,
+      time(range.upper.as_ref())?,
+    ));
+  }
+  if path.is_none() && key.text == "secret_key" {
+    let text = |end: &Bound| End {
+      bound: end.token.text.clone(),
+      inclusive: end.inclusive,
+    };
+    return Ok(Node::KeyRange(
+      range.lower.as_ref().map(text),
+      range.upper.as_ref().map(text),
+    ));
+  }
+  // The whole value for `secret_value`, else the named field
+  let steps = match path {
+    Some(steps) => steps,
+    None if key.text == "secret_value" => Vec::new(),
+    None => vec![PathStep::Field(key.text.clone())],
+  };
+  let json =
+    |end: &Bound| End { bound: end.token.json(), inclusive: end.inclusive };
+  Ok(Node::ValueRange(
+    steps,
+    range.lower.as_ref().map(json),
+    range.upper.as_ref().map(json),
+  ))
+}
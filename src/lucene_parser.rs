@@ -1,8 +1,18 @@
-use pest::{Parser, error::Error as PestError, iterators::Pair};
+use chrono::{DateTime, NaiveDate, Utc};
+use pest::{
+  Span,
+  error::{Error as PestError, ErrorVariant},
+  iterators::Pair,
+};
 use pest_derive::Parser;
+use regex_syntax::ast::{
+  self as regex_ast, AssertionKind, Ast, ClassSet, ClassSetItem, GroupKind,
+  LiteralKind, RepetitionKind, RepetitionRange,
+};
+use serde_json::Value;
 use std::{error::Error, fmt};
 
-/// Possible errors during query parsing or rendering
+/// Possible errors during query parsing or compiling
 #[derive(Debug)]
 pub enum QueryParseError {
   SyntaxError(Box<PestError<Rule>>),
@@ -22,6 +32,16 @@ impl fmt::Display for QueryParseError {
   }
 }
 
+impl QueryParseError {
+  /// A [`QueryParseError::SyntaxError`] pointing at `span`.
+  pub(crate) fn at(span: Span, message: impl Into<String>) -> QueryParseError {
+    QueryParseError::SyntaxError(Box::new(PestError::new_from_span(
+      ErrorVariant::CustomError { message: message.into() },
+      span,
+    )))
+  }
+}
+
 impl Error for QueryParseError {
   fn source(&self) -> Option<&(dyn Error + 'static)> {
     match self {
@@ -37,246 +57,378 @@ impl Error for QueryParseError {
 pub struct QueryParser;
 /// ---------- little helpers ----------
 #[inline]
-fn is_ws(pair: &pest::iterators::Pair<Rule>) -> bool {
+pub(crate) fn is_ws(pair: &pest::iterators::Pair<Rule>) -> bool {
   pair.as_rule() == Rule::WHITESPACE
 }
 
 /// True for any “divider” token we should ignore when collecting operands.
-fn is_sep(pair: &pest::iterators::Pair<Rule>) -> bool {
+pub(crate) fn is_sep(pair: &pest::iterators::Pair<Rule>) -> bool {
   is_ws(pair) || matches!(pair.as_rule(), Rule::and_op | Rule::or_op)
 }
 
-fn next_non_ws<'a, I>(pairs: &mut I) -> Option<pest::iterators::Pair<'a, Rule>>
+pub(crate) fn next_non_ws<'a, I>(
+  pairs: &mut I,
+) -> Option<pest::iterators::Pair<'a, Rule>>
 where
   I: Iterator<Item = pest::iterators::Pair<'a, Rule>>,
 {
   pairs.find(|p| !is_ws(p))
 }
 
-/// Escape `%`, `_`, and backslash for SQL LIKE patterns.
-fn escape_sql_like(s: &str) -> String {
-  s.replace('\\', "\\\\")
-    .replace('%', "\\%")
-    .replace('_', "\\_")
+/// Whether an unquoted token uses the `*` or `?` wildcards.
+fn has_wildcard(s: &str) -> bool {
+  s.contains(['*', '?'])
 }
 
-/// Convert a raw Lucene-style query into a SQL WHERE clause.
-pub fn query_to_sql(raw: &str) -> Result<String, QueryParseError> {
-  let q = raw.trim();
-  if q.is_empty() {
-    return Ok("TRUE".to_string());
-  }
-  match QueryParser::parse(Rule::expression, q) {
-    Ok(mut pairs) => {
-      let expr_pair = pairs.next().ok_or_else(|| {
-        QueryParseError::InternalError("Empty parse tree".into())
-      })?;
-      // Pass the top-level expression directly
-      parse_expression(expr_pair)
+/// One step of a path into a JSON value
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PathStep {
+  Field(String),
+  Index(u32),
+}
+
+/// Split an unquoted field name such as `config.db.port` or `hosts[0]` into
+/// steps, or `None` if it names a single top-level field. Names with empty
+/// segments, like `.env`, are taken as they are.
+fn field_path(name: &str) -> Option<Vec<PathStep>> {
+  let mut steps = Vec::new();
+  for segment in name.split('.') {
+    let end = segment.find('[').unwrap_or(segment.len());
+    if end == 0 {
+      return None;
+    }
+    steps.push(PathStep::Field(segment[..end].to_string()));
+    let mut rest = &segment[end..];
+    while let Some(inner) = rest.strip_prefix('[') {
+      let (index, after) = inner.split_once(']')?;
+      steps.push(PathStep::Index(index.parse().ok()?));
+      rest = after;
+    }
+    if !rest.is_empty() {
+      return None;
     }
-    Err(e) => Err(QueryParseError::SyntaxError(Box::new(e))),
   }
+  (steps.len() > 1).then_some(steps)
 }
 
-/// Recursively walk the parse tree and generate SQL.
-fn parse_expression(pair: Pair<Rule>) -> Result<String, QueryParseError> {
-  match pair.as_rule() {
-    Rule::expression => {
-      let mut inner = pair.into_inner();
-      let expr = next_non_ws(&mut inner).ok_or_else(|| {
-        QueryParseError::InternalError("Empty expression".into())
-      })?;
-      parse_expression(expr)
-    }
+/// Longest `/regex/` pattern accepted, in characters
+pub const MAX_REGEX_LEN: usize = 256;
+/// Largest count of a `{m,n}` repetition
+const MAX_REGEX_REPEAT: u32 = 100;
+/// Largest product of the counts of nested repetitions, as in
+/// `(a{1,10}){1,10}`, which expand into that many copies when compiled
+const MAX_REGEX_REPEAT_PRODUCT: u64 = 1000;
 
-    // ---------- OR ----------
-    Rule::or_expr => {
-      let mut inner = pair.into_inner();
-      let first = next_non_ws(&mut inner).unwrap();
-      let mut parts = vec![parse_expression(first)?];
-      for p in inner {
-        if is_sep(&p) {
-          continue;
-        }
-        parts.push(parse_expression(p)?);
-      }
-      if parts.len() == 1 {
-        Ok(parts.pop().unwrap())
-      } else {
-        Ok(parts.join(" OR "))
-      }
-    }
+/// The pattern of a `regex` pair, checked before it is compiled. Besides
+/// syntax errors this rejects what Postgres reads differently or not at all,
+/// and patterns that can take exponential time or memory.
+pub(crate) fn regex_pattern(
+  pair: &Pair<Rule>,
+) -> Result<String, QueryParseError> {
+  let span = pair.as_span();
+  let text = pair.as_str();
+  let pattern = &text[1..text.len() - 1];
+  if pattern.chars().count() > MAX_REGEX_LEN {
+    return Err(QueryParseError::at(
+      span,
+      format!(
+        "Regular expressions may have at most {} characters",
+        MAX_REGEX_LEN
+      ),
+    ));
+  }
+  // A part of the pattern as a span of the query
+  let at = |inner: &regex_ast::Span, message: String| {
+    let start = span.start() + 1;
+    let inner = Span::new(
+      span.get_input(),
+      start + inner.start.offset,
+      start + inner.end.offset,
+    );
+    QueryParseError::at(inner.unwrap_or(span), message)
+  };
+  let ast = regex_ast::parse::Parser::new()
+    .parse(pattern)
+    .map_err(|err| {
+      at(
+        err.span(),
+        format!("Invalid regular expression: {}", err.kind()),
+      )
+    })?;
+  check_regex(&ast, None).map_err(|(inner, message)| at(&inner, message))?;
+  Ok(pattern.to_string())
+}
 
-    // ---------- AND ----------
-    Rule::and_expr => {
-      let mut inner = pair.into_inner();
-      let first = next_non_ws(&mut inner).unwrap();
-      let mut parts = vec![parse_expression(first)?];
-      for p in inner {
-        if is_sep(&p) {
-          continue;
-        }
-        parts.push(parse_expression(p)?);
+fn unsupported(
+  span: &regex_ast::Span,
+  what: &str,
+) -> Result<(), (regex_ast::Span, String)> {
+  Err((*span, format!("{} are not supported", what)))
+}
+
+/// Reject nested unbounded repetition such as `(a+)+`, large repetition
+/// counts, stacked repetitions like `a{2}{3}`, and syntax Postgres lacks or
+/// reads differently, like `\b`.
+/// `outer` is the outermost repetition around `ast` with the product of the
+/// counts of every repetition between them.
+fn check_regex(
+  ast: &Ast,
+  outer: Option<(regex_ast::Span, u64)>,
+) -> Result<(), (regex_ast::Span, String)> {
+  match ast {
+    Ast::Empty(_) | Ast::Dot(_) | Ast::ClassPerl(_) => Ok(()),
+    Ast::Literal(literal) => match literal.kind {
+      LiteralKind::HexBrace(_) => unsupported(&literal.span, "\\x{..} escapes"),
+      _ => Ok(()),
+    },
+    Ast::Flags(flags) => unsupported(&flags.span, "Inline flags"),
+    Ast::Assertion(assertion) => match assertion.kind {
+      AssertionKind::StartLine
+      | AssertionKind::EndLine
+      | AssertionKind::StartText => Ok(()),
+      _ => unsupported(&assertion.span, "Assertions other than ^, $ and \\A"),
+    },
+    Ast::ClassUnicode(class) => unsupported(&class.span, "Unicode classes"),
+    Ast::ClassBracketed(class) => check_class(&class.kind),
+    Ast::Repetition(repetition) => {
+      if let RepetitionKind::Range(
+        RepetitionRange::Exactly(n)
+        | RepetitionRange::AtLeast(n)
+        | RepetitionRange::Bounded(_, n),
+      ) = repetition.op.kind
+        && n > MAX_REGEX_REPEAT
+      {
+        return Err((
+          repetition.op.span,
+          format!("Repetition counts may be at most {}", MAX_REGEX_REPEAT),
+        ));
       }
-      if parts.len() == 1 {
-        Ok(parts.pop().unwrap())
-      } else {
-        Ok(parts.join(" AND "))
+      if let Ast::Repetition(_) = repetition.ast.as_ref() {
+        return Err((
+          repetition.op.span,
+          "Repeat a group, as in (a{2}){3}, not another repetition".to_string(),
+        ));
       }
-    }
-
-    // ---------- NOT ----------
-    Rule::not_expr => {
-      let inner = pair.into_inner();
-      let mut has_not = false;
-      let mut target: Option<Pair<Rule>> = None;
-      for p in inner {
-        if is_ws(&p) {
-          continue;
-        }
-        if p.as_rule() == Rule::NOT_OP {
-          has_not = true;
-        } else {
-          target = Some(p);
-          break;
-        }
+      if has_unbounded_repetition(&repetition.ast) {
+        return Err((
+          repetition.span,
+          "Repeating a repetition such as (a+)+ can take exponential time"
+            .to_string(),
+        ));
       }
-      let expr_sql = parse_expression(target.ok_or_else(|| {
-        QueryParseError::InternalError("Missing NOT target".into())
-      })?)?;
-      if has_not {
-        Ok(format!("NOT {}", expr_sql))
-      } else {
-        Ok(expr_sql)
+      let count = match repetition.op.kind {
+        RepetitionKind::Range(
+          RepetitionRange::Exactly(n)
+          | RepetitionRange::AtLeast(n)
+          | RepetitionRange::Bounded(_, n),
+        ) => u64::from(n),
+        _ => 1,
+      };
+      let (span, product) = match outer {
+        Some((span, product)) => (span, product * count),
+        None => (repetition.span, count),
+      };
+      if product > MAX_REGEX_REPEAT_PRODUCT {
+        return Err((
+          span,
+          format!(
+            "Nested repetition counts may multiply to at most {}",
+            MAX_REGEX_REPEAT_PRODUCT
+          ),
+        ));
       }
+      check_regex(&repetition.ast, Some((span, product)))
     }
+    Ast::Group(group) => match &group.kind {
+      GroupKind::CaptureName { .. } => unsupported(&group.span, "Named groups"),
+      GroupKind::NonCapturing(flags) if !flags.items.is_empty() => {
+        unsupported(&flags.span, "Inline flags")
+      }
+      _ => check_regex(&group.ast, outer),
+    },
+    Ast::Alternation(alternation) => alternation
+      .asts
+      .iter()
+      .try_for_each(|ast| check_regex(ast, outer)),
+    Ast::Concat(concat) => concat
+      .asts
+      .iter()
+      .try_for_each(|ast| check_regex(ast, outer)),
+  }
+}
 
-    Rule::primary => {
-      let inner = pair.into_inner().next().unwrap();
-      // Recursive call without is_top
-      parse_expression(inner)
-    }
+fn check_class(set: &ClassSet) -> Result<(), (regex_ast::Span, String)> {
+  match set {
+    ClassSet::Item(item) => check_class_item(item),
+    ClassSet::BinaryOp(op) => unsupported(&op.span, "Class set operations"),
+  }
+}
 
-    Rule::grouped => {
-      let mut inner = pair.into_inner();
-      let inner_pair = next_non_ws(&mut inner).unwrap();
-      let inner_sql = parse_expression(inner_pair)?;
-      Ok(format!("({})", inner_sql))
+fn check_class_item(
+  item: &ClassSetItem,
+) -> Result<(), (regex_ast::Span, String)> {
+  match item {
+    ClassSetItem::Unicode(class) => unsupported(&class.span, "Unicode classes"),
+    ClassSetItem::Bracketed(class) => {
+      unsupported(&class.span, "Nested brackets")
     }
+    ClassSetItem::Union(union) => {
+      union.items.iter().try_for_each(check_class_item)
+    }
+    _ => Ok(()),
+  }
+}
 
-    // Call render_key_value without is_top
-    Rule::key_value => render_key_value(pair),
-
-    Rule::phrase => {
-      // Inline unquoting logic for phrases
-      let s = pair.as_str();
-      let t = if s.starts_with('"') && s.ends_with('"') && s.len() >= 2 {
-        let inner = &s[1..s.len() - 1];
-        inner.replace("\\\\", "\\").replace("\\\"", "\"")
-      } else {
-        s.to_string() // Fallback, though grammar should ensure quotes
-      };
-      Ok(format!(
-        "(secret_key ILIKE '%{0}%' OR secret_value::text ILIKE '%{0}%')", // Keep parens for term search grouping
-        escape_sql_like(&t)
-      ))
+/// Whether `ast` holds a `*`, `+` or `{n,}`.
+fn has_unbounded_repetition(ast: &Ast) -> bool {
+  match ast {
+    Ast::Repetition(repetition) => {
+      matches!(
+        repetition.op.kind,
+        RepetitionKind::ZeroOrMore
+          | RepetitionKind::OneOrMore
+          | RepetitionKind::Range(RepetitionRange::AtLeast(_))
+      ) || has_unbounded_repetition(&repetition.ast)
     }
-    Rule::term => {
-      let t = pair.as_str();
-      Ok(format!(
-        "(secret_key ILIKE '%{0}%' OR secret_value::text ILIKE '%{0}%')", // Keep parens for term search grouping
-        escape_sql_like(t)
-      ))
+    Ast::Group(group) => has_unbounded_repetition(&group.ast),
+    Ast::Alternation(alternation) => {
+      alternation.asts.iter().any(has_unbounded_repetition)
     }
-    Rule::EOI => Ok(String::new()), // Should not be reached if called from query_to_sql correctly
-    other => Err(QueryParseError::InternalError(format!(
-      "Unexpected rule encountered: {:?}",
-      other
-    ))),
+    Ast::Concat(concat) => concat.asts.iter().any(has_unbounded_repetition),
+    _ => false,
   }
 }
 
-/// Render a key:value pair, handling schema vs. generic fields.
-fn render_key_value(pair: Pair<Rule>) -> Result<String, QueryParseError> {
-  let mut iter = pair.into_inner().filter(|p| !is_ws(p)); // pair is the key_value rule match
-  let key_rule_pair = iter.next().ok_or_else(|| {
-    // This pair corresponds to the 'key' rule
-    QueryParseError::InternalError("Missing key in key_value rule".into())
-  })?;
-  let value_rule_pair = iter.next().ok_or_else(|| {
-    // This pair corresponds to the 'value' rule
-    QueryParseError::InternalError("Missing value in key_value rule".into())
-  })?;
+/// A key or value of a `key:value` pair
+pub(crate) struct Token {
+  /// Unquoted and unescaped
+  pub text: String,
+  pub quoted: bool,
+  /// Unquoted and containing `*` or `?`
+  pub wildcard: bool,
+}
 
-  // --- Determine raw key string ---
-  // Look inside the 'key' rule's pair to find the actual token (quoted_string or ident)
-  let key_inner_pair = key_rule_pair.into_inner().next().ok_or_else(|| {
-    QueryParseError::InternalError("Missing inner pair for key rule".into())
-  })?;
+impl Token {
+  pub(crate) fn new(text: String, quoted: bool) -> Token {
+    let wildcard = !quoted && has_wildcard(&text);
+    Token { text, quoted, wildcard }
+  }
 
-  let key_raw = match key_inner_pair.as_rule() {
-    Rule::quoted_string => {
-      let s = key_inner_pair.as_str();
-      // Slice off the outer quotes guaranteed by the rule match
-      let inner = &s[1..s.len() - 1];
-      // Now unescape standard sequences like \\ and \" from the inner content
-      inner.replace("\\\\", "\\").replace("\\\"", "\"")
+  /// The token of a `quoted_string` or `ident` pair.
+  pub(crate) fn from_pair(pair: &Pair<Rule>) -> Result<Token, QueryParseError> {
+    match pair.as_rule() {
+      Rule::quoted_string => {
+        let s = pair.as_str();
+        let inner = &s[1..s.len() - 1];
+        Ok(Token::new(
+          inner.replace("\\\\", "\\").replace("\\\"", "\""),
+          true,
+        ))
+      }
+      Rule::field_path | Rule::ident => {
+        Ok(Token::new(pair.as_str().to_string(), false))
+      }
+      other => Err(QueryParseError::InternalError(format!(
+        "Unexpected token rule: {:?}",
+        other
+      ))),
     }
-    Rule::ident => key_inner_pair.as_str().to_string(),
-    _ => {
-      return Err(QueryParseError::InternalError(format!(
-        "Unexpected rule inside key: {:?}",
-        key_inner_pair.as_rule()
-      )));
+  }
+
+  /// The value as JSON: unquoted numbers and booleans keep their type,
+  /// anything else is a string.
+  pub(crate) fn json(&self) -> Value {
+    if !self.quoted {
+      match self.text.as_str() {
+        "true" => return Value::Bool(true),
+        "false" => return Value::Bool(false),
+        text => {
+          if let Ok(number) = text.parse::<serde_json::Number>() {
+            return Value::Number(number);
+          }
+        }
+      }
     }
-  };
+    Value::String(self.text.clone())
+  }
 
+  /// The steps of a nested field name; see [`field_path`].
+  pub(crate) fn path(&self) -> Option<Vec<PathStep>> {
+    if self.quoted {
+      None
+    } else {
+      field_path(&self.text)
+    }
+  }
 
-  // --- Determine raw value string ---
-  // Look inside the 'value' rule's pair to find the actual token
-  let value_inner_pair =
-    value_rule_pair.into_inner().next().ok_or_else(|| {
-      QueryParseError::InternalError("Missing inner pair for value rule".into())
-    })?;
+  /// Whether this key names the `secret_key` or `secret_value` column.
+  pub(crate) fn is_column(&self) -> bool {
+    matches!(self.text.as_str(), "secret_key" | "secret_value")
+      && self.path().is_none()
+  }
+}
 
-  let val_raw = match value_inner_pair.as_rule() {
-    Rule::quoted_string => {
-      let s = value_inner_pair.as_str();
-      // Slice off the outer quotes guaranteed by the rule match
-      let inner = &s[1..s.len() - 1];
-      // Now unescape standard sequences like \\ and \" from the inner content
-      inner.replace("\\\\", "\\").replace("\\\"", "\"")
-    }
-    // Handle both single and multi-word identifiers correctly
-    Rule::ident => value_inner_pair.as_str().to_string(),
-    _ => {
-      return Err(QueryParseError::InternalError(format!(
-        "Unexpected rule inside value: {:?}",
-        value_inner_pair.as_rule()
-      )));
-    }
-  };
+/// One end of a range
+pub(crate) struct Bound {
+  pub token: Token,
+  pub inclusive: bool,
+}
 
-  // --- The rest of the function remains exactly as you had it ---
-  let like_key = escape_sql_like(&key_raw);
-  let like_val = escape_sql_like(&val_raw);
-  let json_key = key_raw.replace('\\', "\\\\").replace('"', "\\\"");
-  let json_val = val_raw.replace('\\', "\\\\").replace('"', "\\\"");
+/// `[a TO b]`, `{a TO b}` or a comparison; `None` ends are open.
+pub(crate) struct Range {
+  pub lower: Option<Bound>,
+  pub upper: Option<Bound>,
+}
 
-  let sql = match key_raw.as_str() {
-    "secret_key" => format!("secret_key ILIKE '%{}%'", like_val),
-    "secret_value" => format!("secret_value::text ILIKE '%{}%'", like_val),
-    _ => {
-      format!(
-        "(secret_key ILIKE '%{lk}%' AND secret_value::text ILIKE '%{lv}%' OR \
-         secret_value @> '{{\"{jk}\": \"{jv}\"}}')",
-        lk = like_key,
-        lv = like_val,
-        jk = json_key,
-        jv = json_val
-      )
+impl Range {
+  /// Read a `range` or `comparison` pair.
+  pub(crate) fn from_pair(pair: Pair<Rule>) -> Result<Range, QueryParseError> {
+    let rule = pair.as_rule();
+    let parts: Vec<Pair<Rule>> = pair.into_inner().collect();
+    // A `bound` pair, or `None` for an unquoted `*`
+    let token = |pair: &Pair<Rule>| -> Result<Option<Token>, QueryParseError> {
+      let inner = pair.clone().into_inner().next().ok_or_else(|| {
+        QueryParseError::InternalError("Missing inner pair for bound".into())
+      })?;
+      let token = Token::from_pair(&inner)?;
+      Ok((token.quoted || token.text != "*").then_some(token))
+    };
+    match (rule, parts.as_slice()) {
+      (Rule::range, [open, lower, upper, close]) => Ok(Range {
+        lower: token(lower)?
+          .map(|token| Bound { token, inclusive: open.as_str() == "[" }),
+        upper: token(upper)?
+          .map(|token| Bound { token, inclusive: close.as_str() == "]" }),
+      }),
+      (Rule::comparison, [op, bound]) => {
+        let bound = token(bound)?;
+        let (lower, upper, inclusive) = match op.as_str() {
+          ">" => (bound, None, false),
+          ">=" => (bound, None, true),
+          "<" => (None, bound, false),
+          _ => (None, bound, true),
+        };
+        Ok(Range {
+          lower: lower.map(|token| Bound { token, inclusive }),
+          upper: upper.map(|token| Bound { token, inclusive }),
+        })
+      }
+      _ => Err(QueryParseError::InternalError(format!(
+        "Malformed {:?}",
+        rule
+      ))),
     }
-  };
+  }
+}
 
-  Ok(sql)
+/// Metadata columns that take ranges of times
+pub(crate) const TIME_COLUMNS: [&str; 2] = ["updated_at", "expires_at"];
+
+/// Read a time bound: RFC 3339, or a date standing for its midnight UTC.
+pub(crate) fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+  if let Ok(at) = DateTime::parse_from_rfc3339(text) {
+    return Some(at.with_timezone(&Utc));
+  }
+  let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+  Some(date.and_hms_opt(0, 0, 0)?.and_utc())
 }
@@ -1,23 +1,107 @@
-use axum::{
-  Router,
-  extract::Extension,
-  http::Method,
-  response::IntoResponse,
-  routing::{get, post},
-};
+use axum::http::{HeaderValue, Method, header};
+use clap::{Parser, Subcommand};
 use dotenvy::dotenv;
-use hyper::{HeaderMap, StatusCode};
+use serde_json::Value;
 use sqlx::postgres::PgPoolOptions;
-use std::{env, net::SocketAddr};
-use tower_http::cors::{Any, CorsLayer};
+use std::{
+  collections::HashMap,
+  io::Write,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tracing_subscriber::FmtSubscriber;
 use tracing_subscriber::filter::EnvFilter;
 
-use keyvault::{
-  AppState, Queries, delete_secret, get_secret, search_secrets, upsert_secret,
-  upsert_secret_by_path,
-};
+use keyvault::auth::{hash_api_key, random_key};
+use keyvault::client::{Client, ClientError, ConnectionArgs, secrets_to_env};
+use keyvault::config::Config;
+use keyvault::crypto::check_master_key;
+use keyvault::expiry;
+use keyvault::migrate;
+use keyvault::template::Template;
+use keyvault::{AppState, Queries, router};
+
+
+#[derive(Parser)]
+#[command(name = "keyvault", version, about = "Secrets vault HTTP API")]
+struct Cli {
+  /// TOML configuration file; environment variables take precedence
+  #[arg(long, global = true, env = "KEYVAULT_CONFIG")]
+  config: Option<PathBuf>,
+
+  #[command(subcommand)]
+  command: Option<Command>,
+}
 
+#[derive(Subcommand)]
+enum Command {
+  /// Serve the HTTP API (the default)
+  Serve,
+  /// Print a random key and the hash to configure it with
+  GenKey {
+    /// Hash with Argon2id instead of SHA-256
+    #[arg(long)]
+    argon2: bool,
+  },
+  /// Run a command with a project's secrets in its environment
+  Run {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Only inject secrets matching this Lucene query
+    #[arg(long, short)]
+    query: Option<String>,
+    /// Prepended to every variable name
+    #[arg(long, default_value = "")]
+    prefix: String,
+    /// Set object values as JSON instead of one variable per field
+    #[arg(long)]
+    no_flatten: bool,
+    /// The command and its arguments, after `--`
+    #[arg(last = true, required = true)]
+    command: Vec<String>,
+  },
+  /// Render a template file with a project's secrets
+  Render {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Template using `{{ secret "key" }}` and `| json_path "$.field"`
+    template: PathBuf,
+    /// Write to this file (replaced atomically, mode 0600) instead of stdout
+    #[arg(long, short)]
+    out: Option<PathBuf>,
+    /// Keep running and re-render whenever a referenced secret changes
+    #[arg(long, requires = "out")]
+    watch: bool,
+    /// Seconds between checks for changes in watch mode
+    #[arg(
+      long,
+      default_value_t = 30,
+      value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    interval: u64,
+    /// Shell command run after each re-render in watch mode
+    #[arg(long, requires = "watch")]
+    reload: Option<String>,
+  },
+  /// Manage the database schema over an admin connection (POSTGRES_USER)
+  Migrate {
+    #[command(subcommand)]
+    action: MigrateAction,
+  },
+}
+
+#[derive(Subcommand)]
+enum MigrateAction {
+  /// Apply pending migrations and set up the reader and writer logins
+  Up,
+  /// List migrations and whether they are applied
+  Status,
+  /// Revert every migration newer than VERSION
+  DownTo { version: i64 },
+}
 
 #[tokio::main]
 async fn main() {
@@ -29,63 +113,334 @@ async fn main() {
   tracing::subscriber::set_global_default(subscriber)
     .expect("setting default subscriber failed");
 
-  let queries: Queries = {
-    let data = tokio::fs::read_to_string("queries.yaml")
-      .await
-      .expect("queries.yaml not found");
-    serde_yaml::from_str(&data).expect("Failed to parse queries.yaml")
+  let cli = Cli::parse();
+  match cli.command.unwrap_or(Command::Serve) {
+    Command::Serve => serve(cli.config).await,
+    Command::GenKey { argon2 } => gen_key(argon2),
+    Command::Run { connection, query, prefix, no_flatten, command } => {
+      let err =
+        run_command(connection, query, prefix, !no_flatten, command).await;
+      eprintln!("keyvault: {}", err);
+      std::process::exit(err.exit_code());
+    }
+    Command::Render { connection, template, out, watch, interval, reload } => {
+      let watch = watch.then(|| (Duration::from_secs(interval), reload));
+      if let Err(err) = render_command(connection, template, out, watch).await {
+        eprintln!("keyvault: {}", err);
+        std::process::exit(err.exit_code());
+      }
+    }
+    Command::Migrate { action } => {
+      if let Err(err) = run_migrate(cli.config, action).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+      }
+    }
+  }
+}
+
+/// Exec `command` with the secrets added to the inherited environment. They
+/// are passed only through the child's environment, never written to disk.
+/// Returns only on failure.
+async fn run_command(
+  connection: ConnectionArgs,
+  query: Option<String>,
+  prefix: String,
+  flatten: bool,
+  command: Vec<String>,
+) -> ClientError {
+  let vars = match connection.client() {
+    Ok(client) => match client.search(query.as_deref()).await {
+      Ok(entries) => secrets_to_env(&entries, &prefix, flatten),
+      Err(err) => return err,
+    },
+    Err(err) => return err,
+  };
+  let vars = match vars {
+    Ok(vars) => vars,
+    Err(err) => return ClientError::Config(err),
+  };
+
+  let mut child = std::process::Command::new(&command[0]);
+  child.args(&command[1..]).envs(vars);
+  #[cfg(unix)]
+  let err = std::os::unix::process::CommandExt::exec(&mut child);
+  #[cfg(not(unix))]
+  let err = match child.status() {
+    Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+    Err(err) => err,
+  };
+  ClientError::Exec(format!("{}: {}", command[0], err))
+}
+
+/// Render `template_path` once, then, with `watch`, poll every interval and
+/// re-render and run the reload command when a referenced secret changes.
+/// Failed polls in watch mode are reported and retried.
+async fn render_command(
+  connection: ConnectionArgs,
+  template_path: PathBuf,
+  out: Option<PathBuf>,
+  watch: Option<(Duration, Option<String>)>,
+) -> Result<(), ClientError> {
+  let source = std::fs::read_to_string(&template_path).map_err(|err| {
+    ClientError::Config(format!(
+      "Cannot read {}: {}",
+      template_path.display(),
+      err
+    ))
+  })?;
+  let template = Template::parse(&source).map_err(|err| {
+    ClientError::Config(format!("{}: {}", template_path.display(), err))
+  })?;
+  let client = connection.client()?;
+
+  let mut secrets = fetch_secrets(&client, &template).await?;
+  let rendered = render(&template, &template_path, &secrets)?;
+  let Some(out) = out else {
+    print!("{}", rendered);
+    return Ok(());
+  };
+  write_rendered(&out, &rendered)?;
+
+  let Some((interval, reload)) = watch else {
+    return Ok(());
+  };
+  loop {
+    tokio::time::sleep(interval).await;
+    let latest = match fetch_secrets(&client, &template).await {
+      Ok(latest) => latest,
+      Err(err) => {
+        eprintln!("keyvault: {}; keeping {}", err, out.display());
+        continue;
+      }
+    };
+    if latest == secrets {
+      continue;
+    }
+    secrets = latest;
+    let written = render(&template, &template_path, &secrets)
+      .and_then(|rendered| write_rendered(&out, &rendered));
+    if let Err(err) = written {
+      eprintln!("keyvault: {}; keeping {}", err, out.display());
+      continue;
+    }
+    eprintln!("keyvault: re-rendered {}", out.display());
+    if let Some(command) = &reload {
+      match tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .await
+      {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("keyvault: reload command {}", status),
+        Err(err) => eprintln!("keyvault: reload command failed: {}", err),
+      }
+    }
+  }
+}
+
+async fn fetch_secrets(
+  client: &Client,
+  template: &Template,
+) -> Result<HashMap<String, Value>, ClientError> {
+  let mut secrets = HashMap::new();
+  for key in template.keys() {
+    secrets.insert(key.to_string(), client.get(key).await?);
+  }
+  Ok(secrets)
+}
+
+fn render(
+  template: &Template,
+  path: &Path,
+  secrets: &HashMap<String, Value>,
+) -> Result<String, ClientError> {
+  template
+    .render(secrets)
+    .map_err(|err| ClientError::Config(format!("{}: {}", path.display(), err)))
+}
+
+/// Replace `path` by renaming a fully written, owner-only sibling over it, so
+/// readers never see a partial file.
+fn write_rendered(path: &Path, contents: &str) -> Result<(), ClientError> {
+  let failed = |err: std::io::Error| {
+    ClientError::Config(format!("Cannot write {}: {}", path.display(), err))
   };
+  let name = path.file_name().ok_or_else(|| {
+    ClientError::Config(format!("Not a file path: {}", path.display()))
+  })?;
+  let tmp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+  let _ = std::fs::remove_file(&tmp);
 
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  let mut file = options.open(&tmp).map_err(failed)?;
+  file
+    .write_all(contents.as_bytes())
+    .and_then(|()| file.sync_all())
+    .and_then(|()| std::fs::rename(&tmp, path))
+    .map_err(|err| {
+      let _ = std::fs::remove_file(&tmp);
+      failed(err)
+    })
+}
+
+async fn run_migrate(
+  config_path: Option<PathBuf>,
+  action: MigrateAction,
+) -> Result<(), String> {
   dotenv().ok();
-  let host = env::var("PG_HOST").unwrap_or_else(|_| "postgres".into());
-  let db = env::var("POSTGRES_DB").expect("POSTGRES_DB unset");
-  let rusr = env::var("SECRETS_READ_USER").expect("...READ_USER");
-  let rpwd = env::var("SECRETS_READ_PASSWORD").expect("...READ_PASSWORD");
-  let wusr = env::var("SECRETS_WRITE_USER").expect("...WRITE_USER");
-  let wpwd = env::var("SECRETS_WRITE_PASSWORD").expect("...WRITE_PASSWORD");
+  let config =
+    Config::load(config_path.as_deref()).map_err(|e| e.to_string())?;
+  let admin = PgPoolOptions::new()
+    .max_connections(1)
+    .acquire_timeout(config.database.connect_timeout)
+    .connect(&config.database.admin_url()?)
+    .await
+    .map_err(|e| format!("Admin connection failed: {}", e))?;
+
+  match action {
+    MigrateAction::Up => {
+      migrate::up(&admin, &config.database, &config.keys.master_key).await?
+    }
+    MigrateAction::DownTo { version } => {
+      migrate::down_to(&admin, version).await?
+    }
+    MigrateAction::Status => {}
+  }
+  for m in migrate::status(&admin).await? {
+    let state = match (m.applied_at, m.success) {
+      (None, _) => "pending".to_string(),
+      (Some(_), false) => "FAILED".to_string(),
+      (Some(at), true) => format!("applied {}", at.to_rfc3339()),
+    };
+    let unknown = if m.known {
+      ""
+    } else {
+      " (unknown to this binary)"
+    };
+    println!(
+      "{:>6}  {:<24} {}{}",
+      m.version, m.description, state, unknown
+    );
+  }
+  Ok(())
+}
+
+/// Print a new key for an `API_MASTER_KEY_*_HASH` variable (or for
+/// `SECRETS_MASTER_KEY`, which takes the key itself).
+fn gen_key(argon2: bool) {
+  let key = random_key();
+  let hash = hash_api_key(&key, argon2).unwrap_or_else(|err| {
+    eprintln!("Failed to hash key: {}", err);
+    std::process::exit(1);
+  });
+  println!("key:  {}", key);
+  println!("hash: {}", hash);
+}
+
+/// Log `err` and exit; startup problems are reported, never panicked on.
+fn exit_with(err: impl std::fmt::Display) -> ! {
+  tracing::error!("{}", err);
+  std::process::exit(1);
+}
 
-  let read_url = format!("postgres://{}:{}@{}/{}", rusr, rpwd, host, db);
-  let write_url = format!("postgres://{}:{}@{}/{}", wusr, wpwd, host, db);
+async fn serve(config_path: Option<PathBuf>) {
+  dotenv().ok();
+  let config =
+    Config::load(config_path.as_deref()).unwrap_or_else(|err| exit_with(err));
 
+  let db = &config.database;
   let read_pool = PgPoolOptions::new()
-    .max_connections(5)
-    .connect(&read_url)
+    .max_connections(db.read_pool_size)
+    .acquire_timeout(db.connect_timeout)
+    .connect(&db.read_url())
     .await
-    .expect("read pool failed");
+    .unwrap_or_else(|err| exit_with(format!("Read pool failed: {}", err)));
   let write_pool = PgPoolOptions::new()
-    .max_connections(5)
-    .connect(&write_url)
+    .max_connections(db.write_pool_size)
+    .acquire_timeout(db.connect_timeout)
+    .connect(&db.write_url())
     .await
-    .expect("write pool failed");
+    .unwrap_or_else(|err| exit_with(format!("Write pool failed: {}", err)));
+
+  // Refuse to serve a schema this binary was not built for
+  if let Err(err) = migrate::check_schema_version(&read_pool).await {
+    exit_with(err);
+  }
+
+  // The query catalog is compiled in; a file may override single entries
+  let mut queries = Queries::embedded();
+  if let Some(path) = &config.query_file {
+    queries = tokio::fs::read_to_string(path)
+      .await
+      .map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+      .and_then(|data| queries.with_overrides(&data))
+      .unwrap_or_else(|err| exit_with(err));
+  }
+  if let Err(err) = queries.validate() {
+    exit_with(err);
+  }
+  if let Err(err) = queries.check_prepared(&write_pool).await {
+    exit_with(err);
+  }
 
-  let state = AppState { read_pool, write_pool, queries };
+  // Refuse to serve without the master key the stored values were sealed with
+  let master_key = config.keys.master_key.clone();
+  if let Err(err) = check_master_key(&write_pool, &queries, &master_key).await {
+    exit_with(err);
+  }
 
   let cors = CorsLayer::new()
-    .allow_origin(Any) // Permite qualquer origem. Para maior segurança, especifique a origem do seu frontend.
-    .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-    .allow_headers(Any);
+    .allow_methods([
+      Method::GET,
+      Method::POST,
+      Method::PUT,
+      Method::PATCH,
+      Method::DELETE,
+    ])
+    .allow_headers(Any)
+    // the UI sends ETags back in If-Match
+    .expose_headers([header::ETAG]);
+  let cors = if config.cors_origins.iter().any(|o| o == "*") {
+    cors.allow_origin(Any)
+  } else {
+    // validated when the configuration was loaded
+    cors.allow_origin(AllowOrigin::list(
+      config
+        .cors_origins
+        .iter()
+        .filter_map(|o| HeaderValue::from_str(o).ok()),
+    ))
+  };
 
-  async fn cors_preflight() -> impl IntoResponse {
-    (StatusCode::NO_CONTENT, HeaderMap::new())
+  let addr = config.listen_addr;
+  let state = AppState {
+    read_pool,
+    write_pool,
+    queries,
+    master_key,
+    config: Arc::new(config),
+  };
+  if let Some(interval) = state.config.expiry_reap_interval {
+    tokio::spawn(expiry::run_reaper(state.clone(), interval));
   }
+  let app = router(state).layer(cors);
 
-  let app = Router::new()
-    .route(
-      "/secrets/{key}",
-      get(get_secret)
-        .put(upsert_secret_by_path)
-        .delete(delete_secret)
-        .options(cors_preflight),
-    )
-    .route("/secrets", post(upsert_secret).options(cors_preflight))
-    .route("/search", post(search_secrets).options(cors_preflight))
-    .layer(cors)
-    .layer(Extension(state));
-
-  let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-  let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-
-  axum::serve(listener, app.into_make_service())
+  let listener = tokio::net::TcpListener::bind(addr)
     .await
-    .unwrap();
+    .unwrap_or_else(|err| exit_with(format!("Cannot bind {}: {}", addr, err)));
+
+  // Peer addresses are recorded in the audit log
+  if let Err(err) = axum::serve(
+    listener,
+    app.into_make_service_with_connect_info::<SocketAddr>(),
+  )
+  .await
+  {
+    exit_with(err);
+  }
 }
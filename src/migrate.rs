@@ -0,0 +1,343 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, PgPool, migrate::Migrator};
+
+use crate::config::DatabaseConfig;
+use crate::crypto::{MasterKey, SealedValue};
+use crate::search::value_metadata;
+
+/// The versioned schema shipped in `migrations/`.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// One migration as known to the binary, the database, or both.
+pub struct MigrationStatus {
+  pub version: i64,
+  pub description: String,
+  /// `None` while pending
+  pub applied_at: Option<DateTime<Utc>>,
+  /// `false` if the migration failed part-way
+  pub success: bool,
+  /// `false` for migrations applied by a newer binary
+  pub known: bool,
+}
+
+#[derive(sqlx::FromRow)]
+struct AppliedMigration {
+  version: i64,
+  description: String,
+  installed_on: DateTime<Utc>,
+  success: bool,
+}
+
+/// Schema version this binary was built for.
+pub fn expected_version() -> i64 {
+  MIGRATOR
+    .iter()
+    .filter(|m| m.migration_type.is_up_migration())
+    .map(|m| m.version)
+    .max()
+    .unwrap_or(0)
+}
+
+async fn applied(pool: &PgPool) -> Result<Vec<AppliedMigration>, String> {
+  let table: Option<String> =
+    sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations')::text")
+      .fetch_one(pool)
+      .await
+      .map_err(|e| format!("Failed to read schema version: {}", e))?;
+  if table.is_none() {
+    return Ok(Vec::new());
+  }
+  sqlx::query_as(
+    "SELECT version, description, installed_on, success \
+       FROM _sqlx_migrations ORDER BY version",
+  )
+  .fetch_all(pool)
+  .await
+  .map_err(|e| format!("Failed to read schema version: {}", e))
+}
+
+/// Seal any plaintext values left from before encryption, apply pending
+/// migrations, fill in the value metadata of versions written before it was
+/// stored, then make sure the configured login users exist and belong to the
+/// reader and writer roles the migrations grant to.
+pub async fn up(
+  admin: &PgPool,
+  db: &DatabaseConfig,
+  master_key: &MasterKey,
+) -> Result<(), String> {
+  seal_plaintext_values(admin, master_key)
+    .await
+    .map_err(|e| format!("Failed to seal plaintext values: {}", e))?;
+  MIGRATOR
+    .run(admin)
+    .await
+    .map_err(|e| format!("Migration failed: {}", e))?;
+  fill_value_metadata(admin, master_key)
+    .await
+    .map_err(|e| format!("Failed to fill in value metadata: {}", e))?;
+
+  for (user, password, role) in [
+    (&db.read_user, &db.read_password, "secrets_reader"),
+    (&db.write_user, &db.write_password, "secrets_writer"),
+  ] {
+    provision_login(admin, user, password, role)
+      .await
+      .map_err(|e| format!("Failed to set up login '{}': {}", user, e))?;
+  }
+  Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct PlaintextSecret {
+  project_key: String,
+  secret_key: String,
+  secret_value: serde_json::Value,
+}
+
+/// Databases from before encryption keep values in a `secret_value` column of
+/// a `secrets` table. Seal each one into `ciphertext`, `wrapped_key` and
+/// `key_id` and drop the column, all in one transaction, so no plaintext
+/// survives into the versioned schema.
+async fn seal_plaintext_values(
+  admin: &PgPool,
+  master_key: &MasterKey,
+) -> Result<(), String> {
+  let legacy: bool = sqlx::query_scalar(
+    "SELECT EXISTS (SELECT 1 FROM pg_attribute a JOIN pg_class c \
+       ON c.oid = a.attrelid WHERE c.oid = to_regclass('secrets') \
+       AND c.relkind = 'r' AND a.attname = 'secret_value' \
+       AND NOT a.attisdropped)",
+  )
+  .fetch_one(admin)
+  .await
+  .map_err(|e| e.to_string())?;
+  if !legacy {
+    return Ok(());
+  }
+
+  let mut tx = admin.begin().await.map_err(|e| e.to_string())?;
+  tx.execute(
+    "ALTER TABLE secrets ADD COLUMN IF NOT EXISTS ciphertext BYTEA, \
+       ADD COLUMN IF NOT EXISTS wrapped_key BYTEA, \
+       ADD COLUMN IF NOT EXISTS key_id TEXT",
+  )
+  .await
+  .map_err(|e| e.to_string())?;
+  let rows: Vec<PlaintextSecret> = sqlx::query_as(
+    "SELECT project_key, secret_key, secret_value FROM secrets \
+       WHERE ciphertext IS NULL",
+  )
+  .fetch_all(&mut *tx)
+  .await
+  .map_err(|e| e.to_string())?;
+  for row in &rows {
+    let sealed = master_key
+      .seal(&row.project_key, &row.secret_key, &row.secret_value)
+      .map_err(|e| format!("{}/{}: {}", row.project_key, row.secret_key, e))?;
+    sqlx::query(
+      "UPDATE secrets SET ciphertext = $3, wrapped_key = $4, key_id = $5 \
+       WHERE project_key = $1 AND secret_key = $2",
+    )
+    .bind(&row.project_key)
+    .bind(&row.secret_key)
+    .bind(&sealed.ciphertext)
+    .bind(&sealed.wrapped_key)
+    .bind(&sealed.key_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+  }
+  tx.execute("ALTER TABLE secrets DROP COLUMN secret_value")
+    .await
+    .map_err(|e| e.to_string())?;
+  tx.commit().await.map_err(|e| e.to_string())?;
+  tracing::info!("Sealed {} plaintext secret values", rows.len());
+  Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct UnsizedVersion {
+  project_key: String,
+  secret_key: String,
+  version: i32,
+  ciphertext: Vec<u8>,
+  wrapped_key: Vec<u8>,
+  key_id: String,
+}
+
+/// Versions sealed before migration 0004 have no `value_size` or
+/// `content_type`; open each one once and record them.
+async fn fill_value_metadata(
+  admin: &PgPool,
+  master_key: &MasterKey,
+) -> Result<(), String> {
+  let mut tx = admin.begin().await.map_err(|e| e.to_string())?;
+  let rows: Vec<UnsizedVersion> = sqlx::query_as(
+    "SELECT project_key, secret_key, version, ciphertext, wrapped_key, \
+       key_id FROM secret_versions WHERE value_size IS NULL AND NOT deleted",
+  )
+  .fetch_all(&mut *tx)
+  .await
+  .map_err(|e| e.to_string())?;
+  for row in rows {
+    let sealed = SealedValue {
+      ciphertext: row.ciphertext,
+      wrapped_key: row.wrapped_key,
+      key_id: row.key_id,
+    };
+    let value = master_key
+      .open(&row.project_key, &row.secret_key, &sealed)
+      .map_err(|e| {
+        format!(
+          "{}/{} version {}: {}",
+          row.project_key, row.secret_key, row.version, e
+        )
+      })?;
+    let (size, content_type) = value_metadata(&value);
+    sqlx::query(
+      "UPDATE secret_versions SET value_size = $4, content_type = $5 \
+       WHERE project_key = $1 AND secret_key = $2 AND version = $3",
+    )
+    .bind(&row.project_key)
+    .bind(&row.secret_key)
+    .bind(row.version)
+    .bind(size)
+    .bind(content_type)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+  }
+  tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// Create `user` if missing (an existing password is left alone) and grant
+/// it `role` and access to the current database. Identifiers and the
+/// password are quoted by the server through `format()`.
+async fn provision_login(
+  admin: &PgPool,
+  user: &str,
+  password: &str,
+  role: &str,
+) -> Result<(), sqlx::Error> {
+  let exists: bool = sqlx::query_scalar(
+    "SELECT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = $1)",
+  )
+  .bind(user)
+  .fetch_one(admin)
+  .await?;
+
+  let mut statements = Vec::new();
+  if !exists {
+    statements.push(
+      sqlx::query_scalar::<_, String>(
+        "SELECT format('CREATE ROLE %I LOGIN PASSWORD %L', $1::text, $2::text)",
+      )
+      .bind(user)
+      .bind(password)
+      .fetch_one(admin)
+      .await?,
+    );
+  }
+  statements.push(
+    sqlx::query_scalar(
+      "SELECT format('GRANT %I TO %I; GRANT CONNECT ON DATABASE %I TO %I', \
+       $1::text, $2::text, current_database(), $2::text)",
+    )
+    .bind(role)
+    .bind(user)
+    .fetch_one(admin)
+    .await?,
+  );
+  for sql in statements {
+    sqlx::raw_sql(&sql).execute(admin).await?;
+  }
+  Ok(())
+}
+
+/// Revert applied migrations newer than `version`.
+pub async fn down_to(admin: &PgPool, version: i64) -> Result<(), String> {
+  if version < 0 {
+    return Err("Target version must not be negative".to_string());
+  }
+  // The down migration refuses too, but only once the later migrations are
+  // already reverted
+  let legacy = version < 1
+    && sqlx::query_scalar("SELECT to_regclass('legacy_secrets') IS NOT NULL")
+      .fetch_one(admin)
+      .await
+      .map_err(|e| format!("Migration failed: {}", e))?;
+  if legacy {
+    return Err(
+      "Migration 0001 cannot be reverted on a database from before version \
+       history, whose sealed legacy_secrets the old binary cannot read; \
+       restore the backup taken before `keyvault migrate up`"
+        .to_string(),
+    );
+  }
+  MIGRATOR
+    .undo(admin, version)
+    .await
+    .map_err(|e| format!("Migration failed: {}", e))
+}
+
+/// Every migration the binary ships, plus any the database has applied that
+/// this binary does not know, ordered by version.
+pub async fn status(pool: &PgPool) -> Result<Vec<MigrationStatus>, String> {
+  let applied = applied(pool).await?;
+  let mut statuses: Vec<MigrationStatus> = MIGRATOR
+    .iter()
+    .filter(|m| m.migration_type.is_up_migration())
+    .map(|m| {
+      let row = applied.iter().find(|a| a.version == m.version);
+      MigrationStatus {
+        version: m.version,
+        description: m.description.to_string(),
+        applied_at: row.map(|a| a.installed_on),
+        success: row.is_none_or(|a| a.success),
+        known: true,
+      }
+    })
+    .collect();
+  for row in applied {
+    if !statuses.iter().any(|s| s.version == row.version) {
+      statuses.push(MigrationStatus {
+        version: row.version,
+        description: row.description,
+        applied_at: Some(row.installed_on),
+        success: row.success,
+        known: false,
+      });
+    }
+  }
+  statuses.sort_by_key(|s| s.version);
+  Ok(statuses)
+}
+
+/// Refuse to serve against a schema this binary was not built for.
+pub async fn check_schema_version(pool: &PgPool) -> Result<(), String> {
+  let applied = applied(pool).await?;
+  if let Some(failed) = applied.iter().find(|a| !a.success) {
+    return Err(format!(
+      "Migration {} failed part-way; repair the database and run \
+       `keyvault migrate up`",
+      failed.version
+    ));
+  }
+  let current = applied.iter().map(|a| a.version).max().unwrap_or(0);
+  let expected = expected_version();
+  if current < expected {
+    return Err(format!(
+      "Database schema is at version {} but this binary expects {}; run \
+       `keyvault migrate up`",
+      current, expected
+    ));
+  }
+  if current > expected {
+    return Err(format!(
+      "Database schema is at version {}, newer than the {} this binary \
+       expects; upgrade keyvault",
+      current, expected
+    ));
+  }
+  Ok(())
+}
@@ -0,0 +1,194 @@
+use axum::{
+  extract::{Extension, Json, Path, Query},
+  http::StatusCode,
+  response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{AdminAuth, AppState, ListAuth, WriteAuth, db_error, query_error};
+
+// Request payloads
+#[derive(Deserialize)]
+pub struct CreateProjectInput {
+  pub project_key: String,
+  #[serde(default)]
+  pub description: String,
+  /// Defaults to the caller's label
+  pub owner: Option<String>,
+  #[serde(default)]
+  pub labels: BTreeMap<String, String>,
+}
+
+/// Fields left out are unchanged; `labels` replaces the whole set.
+#[derive(Deserialize)]
+pub struct UpdateProjectInput {
+  pub description: Option<String>,
+  pub owner: Option<String>,
+  pub labels: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteProjectParams {
+  /// Delete the project's secrets as well
+  #[serde(default)]
+  pub force: bool,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Project {
+  pub project_key: String,
+  pub description: String,
+  pub owner: Option<String>,
+  pub labels: sqlx::types::Json<BTreeMap<String, String>>,
+  pub created_at: DateTime<Utc>,
+}
+
+fn not_found() -> Response {
+  (StatusCode::NOT_FOUND, "Project not found").into_response()
+}
+
+// GET /projects
+pub async fn list_projects(
+  auth: ListAuth,
+  Extension(state): Extension<AppState>,
+) -> Result<Json<Vec<Project>>, Response> {
+  let sql = state.queries.get("list_projects").map_err(query_error)?;
+  let projects: Vec<Project> = sqlx::query_as(sql)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|err| db_error("listing projects", err))?;
+  // Only the projects this caller could list
+  Ok(Json(
+    projects
+      .into_iter()
+      .filter(|p| auth.principal.can_access(&p.project_key))
+      .collect(),
+  ))
+}
+
+// POST /projects
+pub async fn create_project(
+  auth: WriteAuth,
+  Extension(state): Extension<AppState>,
+  Json(input): Json<CreateProjectInput>,
+) -> Result<(StatusCode, Json<Project>), Response> {
+  if input.project_key.is_empty() {
+    return Err(
+      (StatusCode::BAD_REQUEST, "project_key must not be empty")
+        .into_response(),
+    );
+  }
+  auth
+    .principal
+    .check_project(&input.project_key)
+    .map_err(IntoResponse::into_response)?;
+
+  let sql = state.queries.get("create_project").map_err(query_error)?;
+  let project: Option<Project> = sqlx::query_as(sql)
+    .bind(&input.project_key)
+    .bind(&input.description)
+    .bind(input.owner.as_ref().unwrap_or(&auth.principal.label))
+    .bind(sqlx::types::Json(&input.labels))
+    .fetch_optional(&state.write_pool)
+    .await
+    .map_err(|err| db_error("creating project", err))?;
+  match project {
+    Some(project) => Ok((StatusCode::CREATED, Json(project))),
+    None => {
+      Err((StatusCode::CONFLICT, "Project already exists").into_response())
+    }
+  }
+}
+
+// PATCH /projects/:project
+pub async fn update_project(
+  auth: WriteAuth,
+  Path(project): Path<String>,
+  Extension(state): Extension<AppState>,
+  Json(input): Json<UpdateProjectInput>,
+) -> Result<Json<Project>, Response> {
+  auth
+    .principal
+    .check_project(&project)
+    .map_err(IntoResponse::into_response)?;
+
+  let sql = state.queries.get("update_project").map_err(query_error)?;
+  let updated: Option<Project> = sqlx::query_as(sql)
+    .bind(&project)
+    .bind(&input.description)
+    .bind(&input.owner)
+    .bind(input.labels.as_ref().map(sqlx::types::Json))
+    .fetch_optional(&state.write_pool)
+    .await
+    .map_err(|err| db_error("updating project", err))?;
+  updated.map(Json).ok_or_else(not_found)
+}
+
+// DELETE /projects/:project[?force=true]
+pub async fn delete_project(
+  auth: AdminAuth,
+  Path(project): Path<String>,
+  Query(params): Query<DeleteProjectParams>,
+  Extension(state): Extension<AppState>,
+) -> Result<StatusCode, Response> {
+  auth
+    .principal
+    .check_project(&project)
+    .map_err(IntoResponse::into_response)?;
+
+  let delete_sql = state.queries.get("delete_project").map_err(query_error)?;
+  let keys_sql = state.queries.get("list_secret_keys").map_err(query_error)?;
+  let tombstone_sql = state
+    .queries
+    .get("delete_project_secrets")
+    .map_err(query_error)?;
+
+  let mut tx = state
+    .write_pool
+    .begin()
+    .await
+    .map_err(|err| db_error("starting project delete", err))?;
+  // Deleting the row first locks it against a concurrent first write
+  let deleted: Option<String> = sqlx::query_scalar(delete_sql)
+    .bind(&project)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|err| db_error("deleting project", err))?;
+  if deleted.is_none() {
+    return Err(not_found());
+  }
+
+  let keys: Vec<String> = sqlx::query_scalar(keys_sql)
+    .bind(&project)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|err| db_error("listing keys", err))?;
+  if !keys.is_empty() {
+    if !params.force {
+      return Err(
+        (
+          StatusCode::CONFLICT,
+          format!(
+            "Project still has {} secrets; pass force=true to delete them",
+            keys.len()
+          ),
+        )
+          .into_response(),
+      );
+    }
+    // Secrets get tombstones like any delete; their history is kept
+    sqlx::query(tombstone_sql)
+      .bind(&project)
+      .bind(&auth.principal.label)
+      .execute(&mut *tx)
+      .await
+      .map_err(|err| db_error("deleting project secrets", err))?;
+  }
+
+  tx.commit()
+    .await
+    .map_err(|err| db_error("committing project delete", err))?;
+  Ok(StatusCode::NO_CONTENT)
+}
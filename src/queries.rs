@@ -0,0 +1,116 @@
+use serde::Deserialize;
+use sqlx::{Executor, PgPool};
+use std::collections::HashMap;
+
+/// The catalog compiled into the binary.
+const EMBEDDED: &str = include_str!("../queries.yaml");
+
+/// Every query the handlers look up. A catalog must define exactly these.
+pub const REQUIRED: &[&str] = &[
+  "get_secret",
+  "get_secret_version",
+  "get_current_version",
+  "upsert_secret",
+  "delete_secret",
+  "list_secret_versions",
+  "rollback_secret",
+  "delete_project_secrets",
+  "list_secrets",
+  "list_secret_keys",
+  "list_secret_page",
+  "count_secret_page",
+  "list_subtree",
+  "list_subtree_secrets",
+  "tombstone_secrets",
+  "reap_expired_secrets",
+  "search_candidates",
+  "list_projects",
+  "create_project",
+  "update_project",
+  "delete_project",
+  "init_key_fingerprint",
+  "get_key_fingerprint",
+  "get_token",
+  "create_token",
+  "list_tokens",
+  "get_active_token",
+  "revoke_token",
+  "expire_token",
+  "insert_audit_event",
+  "list_audit_events",
+];
+
+// SQL queries by name, from queries.yaml
+#[derive(Debug, Deserialize, Clone)]
+pub struct Queries(pub HashMap<String, String>);
+
+impl Queries {
+  /// The default catalog shipped with the binary.
+  pub fn embedded() -> Self {
+    serde_yaml::from_str(EMBEDDED).expect("embedded queries.yaml is valid")
+  }
+
+  /// Replace entries of this catalog with those defined in `yaml`.
+  pub fn with_overrides(mut self, yaml: &str) -> Result<Self, String> {
+    let overrides: Queries = serde_yaml::from_str(yaml)
+      .map_err(|e| format!("Invalid query overrides: {}", e))?;
+    self.0.extend(overrides.0);
+    Ok(self)
+  }
+
+  pub fn get(&self, key: &str) -> Result<&str, String> {
+    self
+      .0
+      .get(key)
+      .map(|s| s.as_str())
+      .ok_or_else(|| format!("Missing query '{}'", key))
+  }
+
+  /// Check that every required query is defined and nothing else is, so a
+  /// misspelt override is caught at startup.
+  pub fn validate(&self) -> Result<(), String> {
+    let mut problems: Vec<String> = REQUIRED
+      .iter()
+      .filter(|name| !self.0.contains_key(**name))
+      .map(|name| format!("missing query '{}'", name))
+      .collect();
+    let mut unknown: Vec<&String> = self
+      .0
+      .keys()
+      .filter(|name| !REQUIRED.contains(&name.as_str()))
+      .collect();
+    unknown.sort();
+    problems.extend(
+      unknown
+        .iter()
+        .map(|name| format!("unknown query '{}'", name)),
+    );
+    report(problems)
+  }
+
+  /// Prepare every statement against `pool`, reporting each one the
+  /// database rejects.
+  pub async fn check_prepared(&self, pool: &PgPool) -> Result<(), String> {
+    let mut names: Vec<&String> = self.0.keys().collect();
+    names.sort();
+    let mut problems = Vec::new();
+    for name in names {
+      if let Err(err) = pool.prepare(self.0[name].as_str()).await {
+        problems.push(format!("query '{}' does not prepare: {}", name, err));
+      }
+    }
+    report(problems)
+  }
+}
+
+fn report(problems: Vec<String>) -> Result<(), String> {
+  if problems.is_empty() {
+    return Ok(());
+  }
+  let mut message = "Invalid query catalog:".to_string();
+  for problem in problems {
+    message.push_str("\n  - ");
+    message.push_str(&problem);
+  }
+  Err(message)
+}
@@ -0,0 +1,529 @@
+use axum::{
+  extract::{Extension, Json, Query},
+  http::StatusCode,
+  response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  AppState, ListAuth, ProjectKey, ReadAuth,
+  crypto::SealedValue,
+  db_error,
+  lucene_filter::{Candidate, Filter, query_to_filter},
+  query_error,
+};
+
+/// Page size when the request gives none.
+pub const DEFAULT_SEARCH_LIMIT: i64 = 100;
+/// Largest page a request may ask for.
+pub const MAX_SEARCH_LIMIT: i64 = 1000;
+
+/// Rows fetched at a time while search looks for matches.
+const SCAN_BATCH: i64 = 500;
+
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchSort {
+  #[default]
+  Key,
+  UpdatedAt,
+}
+
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+  #[default]
+  Asc,
+  Desc,
+}
+
+/// What each result carries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchFields {
+  /// Metadata and the value
+  #[default]
+  All,
+  /// Metadata only; values never leave the server
+  Keys,
+}
+
+/// Paging and sorting shared by `/search` and the key listing.
+#[derive(Deserialize, Default)]
+pub struct PageParams {
+  /// Page size, at most [`MAX_SEARCH_LIMIT`]
+  pub limit: Option<i64>,
+  /// `next_cursor` from the previous page
+  pub cursor: Option<String>,
+  #[serde(default)]
+  pub sort: SearchSort,
+  #[serde(default)]
+  pub order: SortOrder,
+  /// Also count every match, not just this page
+  #[serde(default)]
+  pub total: bool,
+}
+
+impl SearchSort {
+  /// How `search_candidates` and `list_secret_page` name the sort
+  fn as_str(self) -> &'static str {
+    match self {
+      SearchSort::Key => "key",
+      SearchSort::UpdatedAt => "updated_at",
+    }
+  }
+}
+
+impl PageParams {
+  /// The checked page size and the decoded cursor.
+  fn resolve(&self) -> Result<(i64, Option<Cursor>), String> {
+    let limit = self.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+      return Err(format!("limit must be between 1 and {}", MAX_SEARCH_LIMIT));
+    }
+    let cursor = match self.cursor.as_deref() {
+      Some(text) => match Cursor::decode(text) {
+        Some(c) if c.sort == self.sort && c.order == self.order => Some(c),
+        Some(_) => {
+          return Err("Cursor was made for a different sort".to_string());
+        }
+        None => return Err("Invalid cursor".to_string()),
+      },
+      None => None,
+    };
+    Ok((limit, cursor))
+  }
+
+  /// Cut `rows`, fetched one past the page, down to `limit` and return the
+  /// cursor after the last one kept if more follow. `position` gives a
+  /// row's key and update time.
+  fn next_cursor<T>(
+    &self,
+    rows: &mut Vec<T>,
+    limit: i64,
+    position: impl Fn(&T) -> (String, DateTime<Utc>),
+  ) -> Option<String> {
+    if rows.len() as i64 <= limit {
+      return None;
+    }
+    rows.truncate(limit as usize);
+    rows.last().map(|last| {
+      let (key, updated_at) = position(last);
+      self.cursor_at(key, updated_at)
+    })
+  }
+
+  /// The cursor continuing after `key`, written at `updated_at`.
+  fn cursor_at(&self, key: String, updated_at: DateTime<Utc>) -> String {
+    Cursor {
+      sort: self.sort,
+      order: self.order,
+      key,
+      updated_at,
+    }
+    .encode()
+  }
+}
+
+#[derive(Deserialize, Default)]
+pub struct SearchInput {
+  pub query: Option<String>,
+  #[serde(default)]
+  pub fields: SearchFields,
+  /// Also match secrets past their expiry
+  #[serde(default)]
+  pub include_expired: bool,
+  #[serde(flatten)]
+  pub page: PageParams,
+}
+
+/// Query string of the key listing: a prefix plus [`PageParams`], spelled out
+/// because query strings cannot fill flattened numbers and flags.
+#[derive(Deserialize)]
+pub struct ListParams {
+  /// Only keys starting with this
+  pub prefix: Option<String>,
+  pub limit: Option<i64>,
+  pub cursor: Option<String>,
+  #[serde(default)]
+  pub sort: SearchSort,
+  #[serde(default)]
+  pub order: SortOrder,
+  #[serde(default)]
+  pub total: bool,
+  #[serde(default)]
+  pub include_expired: bool,
+}
+
+/// A key listed by `list_secret_page`, with the metadata stored beside its
+/// sealed value
+#[derive(sqlx::FromRow)]
+struct KeyRow {
+  secret_key: String,
+  version: i32,
+  updated_at: DateTime<Utc>,
+  expires_at: Option<DateTime<Utc>>,
+  value_size: Option<i32>,
+  content_type: Option<String>,
+}
+
+/// A secret search may match, as `search_candidates` loads it
+#[derive(sqlx::FromRow)]
+struct CandidateRow {
+  #[sqlx(flatten)]
+  meta: KeyRow,
+  #[sqlx(flatten)]
+  sealed: SealedValue,
+}
+
+impl KeyRow {
+  fn into_hit(
+    self,
+    project: &str,
+    value: Option<serde_json::Value>,
+  ) -> SearchHit {
+    SearchHit {
+      secret_key: self.secret_key,
+      project_key: project.to_string(),
+      secret_value: value,
+      version: self.version,
+      updated_at: self.updated_at,
+      expires_at: self.expires_at,
+      size: self.value_size.unwrap_or_default() as usize,
+      content_type: self.content_type.unwrap_or_default(),
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+  pub secret_key: String,
+  pub project_key: String,
+  /// Left out when only keys were asked for
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub secret_value: Option<serde_json::Value>,
+  pub version: i32,
+  pub updated_at: DateTime<Utc>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub expires_at: Option<DateTime<Utc>>,
+  /// Length of the value as JSON text, in bytes
+  pub size: usize,
+  /// JSON type of the value: string, number, boolean, object, array or null
+  pub content_type: String,
+}
+
+#[derive(Serialize)]
+pub struct SearchPage {
+  pub items: Vec<SearchHit>,
+  /// Pass back as `cursor` for the next page; `null` on the last one
+  pub next_cursor: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub total: Option<i64>,
+}
+
+/// Position after the last row of a page. Sent to clients hex-encoded and
+/// only valid with the sort it was made for.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+  sort: SearchSort,
+  order: SortOrder,
+  key: String,
+  updated_at: DateTime<Utc>,
+}
+
+impl Cursor {
+  fn encode(&self) -> String {
+    hex::encode(serde_json::to_vec(self).unwrap_or_default())
+  }
+
+  fn decode(text: &str) -> Option<Cursor> {
+    serde_json::from_slice(&hex::decode(text).ok()?).ok()
+  }
+}
+
+fn bad_request(message: impl Into<String>) -> Response {
+  (StatusCode::BAD_REQUEST, message.into()).into_response()
+}
+
+/// The `value_size` and `content_type` stored with each version, so listings
+/// can show them without decrypting.
+pub(crate) fn value_metadata(value: &serde_json::Value) -> (i32, &'static str) {
+  let size = value.to_string().len();
+  (i32::try_from(size).unwrap_or(i32::MAX), content_type(value))
+}
+
+fn content_type(value: &serde_json::Value) -> &'static str {
+  match value {
+    serde_json::Value::String(_) => "string",
+    serde_json::Value::Number(_) => "number",
+    serde_json::Value::Bool(_) => "boolean",
+    serde_json::Value::Object(_) => "object",
+    serde_json::Value::Array(_) => "array",
+    serde_json::Value::Null => "null",
+  }
+}
+
+// POST /search
+pub async fn search_secrets(
+  _auth: ReadAuth,
+  ProjectKey(project): ProjectKey,
+  Extension(state): Extension<AppState>,
+  Json(payload): Json<SearchInput>,
+) -> Result<Json<SearchPage>, Response> {
+  let raw_query = payload.query.unwrap_or_default();
+
+  // Compile the query; it runs here, over each secret as it is opened
+  let filter = query_to_filter(&raw_query).map_err(|parse_err| {
+    tracing::warn!(
+      "Query parsing failed: {:?} for query: '{}'",
+      parse_err,
+      raw_query
+    );
+    bad_request(format!(
+      "Invalid search query syntax. Error details: {}",
+      parse_err
+    ))
+  })?;
+  tracing::debug!("🔍 Raw query = {:?}", raw_query);
+
+  run_search(
+    &state,
+    &project,
+    &filter,
+    &payload.page,
+    payload.fields,
+    payload.include_expired,
+  )
+  .await
+  .map(Json)
+}
+
+// GET /secrets[?prefix=..&limit=..&cursor=..]
+//
+// Needs only the `list` permission, so it takes a prefix rather than a
+// query: matching on values would let a caller probe what it cannot read.
+// Pages straight from the table; nothing is decrypted.
+pub async fn list_secret_keys(
+  _auth: ListAuth,
+  ProjectKey(project): ProjectKey,
+  Extension(state): Extension<AppState>,
+  Query(params): Query<ListParams>,
+) -> Result<Json<SearchPage>, Response> {
+  let page = PageParams {
+    limit: params.limit,
+    cursor: params.cursor,
+    sort: params.sort,
+    order: params.order,
+    total: params.total,
+  };
+  let (limit, cursor) = page.resolve().map_err(bad_request)?;
+  let sql = state.queries.get("list_secret_page").map_err(query_error)?;
+  let mut rows: Vec<KeyRow> = sqlx::query_as(sql)
+    .bind(&project)
+    .bind(&params.prefix)
+    .bind(page.sort.as_str())
+    .bind(page.order == SortOrder::Desc)
+    .bind(cursor.as_ref().map(|c| c.key.as_str()))
+    .bind(cursor.as_ref().map(|c| c.updated_at))
+    .bind(limit + 1)
+    .bind(params.include_expired)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|err| db_error("listing keys", err))?;
+
+  // Counted apart from the page, so a cursor past the end still sees them
+  let total = if page.total {
+    let sql = state
+      .queries
+      .get("count_secret_page")
+      .map_err(query_error)?;
+    let count: i64 = sqlx::query_scalar(sql)
+      .bind(&project)
+      .bind(&params.prefix)
+      .bind(params.include_expired)
+      .fetch_one(&state.read_pool)
+      .await
+      .map_err(|err| db_error("counting keys", err))?;
+    Some(count)
+  } else {
+    None
+  };
+
+  let next_cursor = page.next_cursor(&mut rows, limit, |row| {
+    (row.secret_key.clone(), row.updated_at)
+  });
+  let items = rows
+    .into_iter()
+    .map(|row| row.into_hit(&project, None))
+    .collect();
+  Ok(Json(SearchPage { items, next_cursor, total }))
+}
+
+/// What [`scan`] goes through: the project's secrets in page order, tested
+/// against `filter`
+struct Scope<'a> {
+  project: &'a str,
+  filter: &'a Filter,
+  page: &'a PageParams,
+  include_expired: bool,
+}
+
+/// Where [`scan`] stopped
+enum ScanEnd {
+  /// `found` wanted no more, or the secrets ran out
+  Done,
+  /// The scan limit ran out after the secret with this key and update time
+  Limited(String, DateTime<Utc>),
+}
+
+/// Test `filter` against the project's secrets in page order and return the
+/// requested page. Values are opened only when the filter needs them, or for
+/// the page when they are asked for. At most `search_scan_limit` secrets are
+/// tested; a page cut short by that ends with a cursor after the last one.
+async fn run_search(
+  state: &AppState,
+  project: &str,
+  filter: &Filter,
+  page: &PageParams,
+  fields: SearchFields,
+  include_expired: bool,
+) -> Result<SearchPage, Response> {
+  let (limit, cursor) = page.resolve().map_err(bad_request)?;
+  // Counting would open every value in the project
+  if page.total && filter.reads_values() {
+    return Err(bad_request(
+      "total is only available for queries on keys and times",
+    ));
+  }
+  let after = cursor.map(|c| (c.key, c.updated_at));
+  let scope = Scope { project, filter, page, include_expired };
+
+  // One match past the page tells whether another page follows
+  let mut rows = Vec::new();
+  let scan_limit = Some(state.config.search_scan_limit);
+  let end = scan(state, &scope, after, scan_limit, |row| {
+    rows.push(row);
+    rows.len() as i64 <= limit
+  })
+  .await?;
+  let next_cursor = match end {
+    ScanEnd::Limited(key, updated_at) => Some(page.cursor_at(key, updated_at)),
+    ScanEnd::Done => page.next_cursor(&mut rows, limit, |(row, _)| {
+      (row.meta.secret_key.clone(), row.meta.updated_at)
+    }),
+  };
+
+  // Counted over every page, not just from the cursor on; nothing is opened
+  let total = if page.total {
+    let mut count = 0;
+    scan(state, &scope, None, None, |_| {
+      count += 1;
+      true
+    })
+    .await?;
+    Some(count)
+  } else {
+    None
+  };
+
+  let mut items = Vec::with_capacity(rows.len());
+  for (row, value) in rows {
+    let value = match (fields, value) {
+      (SearchFields::Keys, _) => None,
+      (SearchFields::All, Some(value)) => Some(value),
+      (SearchFields::All, None) => Some(
+        open_value(state, project, &row)
+          .map_err(IntoResponse::into_response)?,
+      ),
+    };
+    items.push(row.meta.into_hit(project, value));
+  }
+  Ok(SearchPage { items, next_cursor, total })
+}
+
+/// Hand each secret of `scope` after `after` that matches to `found`, with
+/// its value if that had to be opened, until `found` returns false, the
+/// secrets run out or `scan_limit` of them have been tested.
+async fn scan(
+  state: &AppState,
+  scope: &Scope<'_>,
+  mut after: Option<(String, DateTime<Utc>)>,
+  scan_limit: Option<u32>,
+  mut found: impl FnMut((CandidateRow, Option<serde_json::Value>)) -> bool,
+) -> Result<ScanEnd, Response> {
+  let sql = state
+    .queries
+    .get("search_candidates")
+    .map_err(query_error)?;
+  let mut left = scan_limit.map(i64::from);
+  loop {
+    let fetch = left.map_or(SCAN_BATCH, |left| left.min(SCAN_BATCH));
+    let batch: Vec<CandidateRow> = sqlx::query_as(sql)
+      .bind(scope.project)
+      .bind(scope.page.sort.as_str())
+      .bind(scope.page.order == SortOrder::Desc)
+      .bind(after.as_ref().map(|(key, _)| key.as_str()))
+      .bind(after.as_ref().map(|(_, at)| *at))
+      .bind(fetch)
+      .bind(scope.include_expired)
+      .fetch_all(&state.read_pool)
+      .await
+      .map_err(|err| db_error("during search", err))?;
+    let last_batch = (batch.len() as i64) < fetch;
+    if let Some(left) = &mut left {
+      *left -= batch.len() as i64;
+    }
+    for row in batch {
+      after = Some((row.meta.secret_key.clone(), row.meta.updated_at));
+      let mut candidate = Candidate {
+        key: &row.meta.secret_key,
+        value: None,
+        updated_at: row.meta.updated_at,
+        expires_at: row.meta.expires_at,
+      };
+      let (matched, value) = match scope.filter.matches(&candidate) {
+        Some(matched) => (matched, None),
+        None => {
+          let value = open_value(state, scope.project, &row)
+            .map_err(IntoResponse::into_response)?;
+          candidate.value = Some(&value);
+          let matched = scope.filter.matches(&candidate) == Some(true);
+          (matched, Some(value))
+        }
+      };
+      if matched && !found((row, value)) {
+        return Ok(ScanEnd::Done);
+      }
+    }
+    if last_batch {
+      return Ok(ScanEnd::Done);
+    }
+    if let (Some(0), Some((key, updated_at))) = (left, &after) {
+      return Ok(ScanEnd::Limited(key.clone(), *updated_at));
+    }
+  }
+}
+
+/// Decrypt the value of a candidate row.
+fn open_value(
+  state: &AppState,
+  project: &str,
+  row: &CandidateRow,
+) -> Result<serde_json::Value, (StatusCode, &'static str)> {
+  state
+    .master_key
+    .open(project, &row.meta.secret_key, &row.sealed)
+    .map_err(|err| {
+      tracing::error!(
+        "Failed to open secret '{}': {}",
+        row.meta.secret_key,
+        err
+      );
+      (StatusCode::INTERNAL_SERVER_ERROR, "Decryption error")
+    })
+}
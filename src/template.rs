@@ -0,0 +1,292 @@
+//! Config-file templates rendered from secrets.
+//!
+//! Text is copied as-is except for actions between `{{` and `}}`:
+//!
+//! ```text
+//! password = "{{ secret "db/password" }}"
+//! user = "{{ secret "db" | json_path "$.user" }}"
+//! ```
+//!
+//! `secret` inserts a secret's value (strings unquoted, anything else as
+//! compact JSON); `json_path` selects a field of a JSON value with `.name`,
+//! `['name']` and `[index]` steps from the root `$`.
+
+use serde_json::Value;
+use std::{collections::HashMap, error::Error, fmt};
+
+use crate::client::raw_value;
+
+/// A parse or render failure, located in the template source.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TemplateError {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "line {}, column {}: {}",
+      self.line, self.column, self.message
+    )
+  }
+}
+
+impl Error for TemplateError {}
+
+/// One step of a `json_path` expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathStep {
+  Field(String),
+  Index(usize),
+}
+
+#[derive(Debug)]
+enum Segment {
+  Text(String),
+  Secret {
+    /// Byte offset of the action, for error positions
+    offset: usize,
+    key: String,
+    path: Option<(String, Vec<PathStep>)>,
+  },
+}
+
+/// A parsed template.
+#[derive(Debug)]
+pub struct Template {
+  source: String,
+  segments: Vec<Segment>,
+}
+
+impl Template {
+  pub fn parse(source: &str) -> Result<Template, TemplateError> {
+    let mut segments = Vec::new();
+    let mut rest = 0;
+    while let Some(start) = source[rest..].find("{{").map(|i| rest + i) {
+      if start > rest {
+        segments.push(Segment::Text(source[rest..start].to_string()));
+      }
+      let mut parser = ActionParser { source, pos: start + 2 };
+      segments.push(parser.action(start)?);
+      rest = parser.pos;
+    }
+    if rest < source.len() {
+      segments.push(Segment::Text(source[rest..].to_string()));
+    }
+    Ok(Template { source: source.to_string(), segments })
+  }
+
+  /// Every secret key the template refers to, sorted and without
+  /// duplicates.
+  pub fn keys(&self) -> Vec<&str> {
+    let mut keys: Vec<&str> = self
+      .segments
+      .iter()
+      .filter_map(|segment| match segment {
+        Segment::Secret { key, .. } => Some(key.as_str()),
+        Segment::Text(_) => None,
+      })
+      .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+  }
+
+  /// Render with `secrets`, which must hold every key from [`keys`].
+  ///
+  /// [`keys`]: Template::keys
+  pub fn render(
+    &self,
+    secrets: &HashMap<String, Value>,
+  ) -> Result<String, TemplateError> {
+    let mut out = String::new();
+    for segment in &self.segments {
+      match segment {
+        Segment::Text(text) => out.push_str(text),
+        Segment::Secret { offset, key, path } => {
+          let value = secrets.get(key).ok_or_else(|| {
+            error_at(&self.source, *offset, format!("No secret '{}'", key))
+          })?;
+          let value = match path {
+            Some((expr, steps)) => select(value, steps).ok_or_else(|| {
+              error_at(
+                &self.source,
+                *offset,
+                format!("json_path '{}' matches nothing in '{}'", expr, key),
+              )
+            })?,
+            None => value,
+          };
+          out.push_str(&raw_value(value));
+        }
+      }
+    }
+    Ok(out)
+  }
+}
+
+fn select<'a>(mut value: &'a Value, steps: &[PathStep]) -> Option<&'a Value> {
+  for step in steps {
+    value = match step {
+      PathStep::Field(name) => value.get(name)?,
+      PathStep::Index(index) => value.get(index)?,
+    };
+  }
+  Some(value)
+}
+
+fn error_at(source: &str, offset: usize, message: String) -> TemplateError {
+  let before = &source[..offset];
+  let line = before.matches('\n').count() + 1;
+  let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+  TemplateError {
+    line,
+    column: before[line_start..].chars().count() + 1,
+    message,
+  }
+}
+
+/// Parses the inside of one `{{ ... }}` action.
+struct ActionParser<'a> {
+  source: &'a str,
+  pos: usize,
+}
+
+impl ActionParser<'_> {
+  fn error(&self, message: impl Into<String>) -> TemplateError {
+    error_at(self.source, self.pos, message.into())
+  }
+
+  fn rest(&self) -> &str {
+    &self.source[self.pos..]
+  }
+
+  fn skip_whitespace(&mut self) {
+    let rest = self.rest();
+    self.pos += rest.len() - rest.trim_start().len();
+  }
+
+  fn word(&mut self) -> &str {
+    self.skip_whitespace();
+    let rest = self.rest();
+    let len = rest
+      .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+      .unwrap_or(rest.len());
+    let start = self.pos;
+    self.pos += len;
+    &self.source[start..self.pos]
+  }
+
+  fn expect_word(&mut self, expected: &str) -> Result<(), TemplateError> {
+    let start = self.pos;
+    let word = self.word();
+    if word == expected {
+      return Ok(());
+    }
+    let found = if word.is_empty() {
+      "nothing".to_string()
+    } else {
+      format!("'{}'", word)
+    };
+    self.pos = start;
+    self.skip_whitespace();
+    Err(self.error(format!("Expected '{}', found {}", expected, found)))
+  }
+
+  /// A double-quoted string with `\"` and `\\` escapes.
+  fn string(&mut self) -> Result<String, TemplateError> {
+    self.skip_whitespace();
+    if !self.rest().starts_with('"') {
+      return Err(self.error("Expected a quoted string"));
+    }
+    let start = self.pos;
+    let mut value = String::new();
+    let mut chars = self.rest()[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+      match c {
+        '"' => {
+          self.pos += i + 2;
+          return Ok(value);
+        }
+        '\\' => match chars.next() {
+          Some((_, c @ ('"' | '\\'))) => value.push(c),
+          _ => {
+            self.pos += i + 1;
+            return Err(self.error("Invalid escape; use \\\" or \\\\"));
+          }
+        },
+        '\n' => break,
+        c => value.push(c),
+      }
+    }
+    self.pos = start;
+    Err(self.error("Unterminated string"))
+  }
+
+  fn action(&mut self, offset: usize) -> Result<Segment, TemplateError> {
+    self.expect_word("secret")?;
+    let key = self.string()?;
+    if key.is_empty() {
+      return Err(error_at(self.source, offset, "Empty secret key".into()));
+    }
+    let mut path = None;
+    self.skip_whitespace();
+    if self.rest().starts_with('|') {
+      self.pos += 1;
+      self.expect_word("json_path")?;
+      self.skip_whitespace();
+      let path_pos = self.pos;
+      let expr = self.string()?;
+      let steps = parse_path(&expr)
+        .map_err(|message| error_at(self.source, path_pos, message))?;
+      path = Some((expr, steps));
+      self.skip_whitespace();
+    }
+    if !self.rest().starts_with("}}") {
+      return Err(self.error("Expected '}}'"));
+    }
+    self.pos += 2;
+    Ok(Segment::Secret { offset, key, path })
+  }
+}
+
+/// Parse `$`, `$.a.b`, `$.hosts[0]`, `$["a key"]`.
+fn parse_path(expr: &str) -> Result<Vec<PathStep>, String> {
+  let invalid = |why: &str| format!("Invalid json_path '{}': {}", expr, why);
+  let mut rest = expr
+    .strip_prefix('$')
+    .ok_or_else(|| invalid("must start with '$'"))?;
+  let mut steps = Vec::new();
+  while !rest.is_empty() {
+    if let Some(after) = rest.strip_prefix('.') {
+      let len = after.find(['.', '[']).unwrap_or(after.len());
+      if len == 0 {
+        return Err(invalid("empty field name"));
+      }
+      steps.push(PathStep::Field(after[..len].to_string()));
+      rest = &after[len..];
+    } else if let Some(after) = rest.strip_prefix('[') {
+      let end = after.find(']').ok_or_else(|| invalid("unclosed '['"))?;
+      let inner = &after[..end];
+      let quoted = inner
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .or_else(|| inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')));
+      steps.push(match quoted {
+        Some(name) => PathStep::Field(name.to_string()),
+        None => PathStep::Index(
+          inner
+            .parse()
+            .map_err(|_| invalid("expected an index or a quoted name"))?,
+        ),
+      });
+      rest = &after[end + 1..];
+    } else {
+      return Err(invalid("expected '.' or '['"));
+    }
+  }
+  Ok(steps)
+}
@@ -0,0 +1,251 @@
+use axum::{
+  extract::{Extension, Json, Path},
+  http::StatusCode,
+  response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+
+use crate::{
+  AdminAuth, AppState,
+  auth::{Permission, generate_token, hash_token, scope_covers},
+  db_error, query_error,
+};
+
+/// Grace period applied by `POST /tokens/{id}/rotate` when none is given.
+const DEFAULT_GRACE_PERIOD_SECS: i64 = 3600;
+
+// Request payloads
+#[derive(Deserialize)]
+pub struct CreateTokenInput {
+  pub label: String,
+  pub project_scope: String,
+  pub permissions: Vec<String>,
+  pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct RotateTokenInput {
+  /// Seconds the old token keeps working after rotation
+  pub grace_period_secs: Option<i64>,
+}
+
+/// Everything about a token except its secret, which is only ever returned
+/// by the call that creates it.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct TokenInfo {
+  pub id: i64,
+  pub label: String,
+  pub project_scope: String,
+  pub permissions: Vec<String>,
+  pub expires_at: Option<DateTime<Utc>>,
+  pub created_at: DateTime<Utc>,
+  pub revoked_at: Option<DateTime<Utc>>,
+  pub rotated_from: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+  pub token: String,
+  #[serde(flatten)]
+  pub info: TokenInfo,
+}
+
+fn out_of_scope() -> Response {
+  (StatusCode::FORBIDDEN, "Token scope exceeds admin scope").into_response()
+}
+
+/// Insert a new token row, returning its plaintext alongside the metadata.
+async fn insert_token(
+  tx: &mut Transaction<'_, Postgres>,
+  state: &AppState,
+  input: &CreateTokenInput,
+  rotated_from: Option<i64>,
+) -> Result<CreatedToken, Response> {
+  let sql = state.queries.get("create_token").map_err(query_error)?;
+  let token = generate_token();
+  let info: TokenInfo = sqlx::query_as(sql)
+    .bind(&input.label)
+    .bind(hash_token(&token))
+    .bind(&input.project_scope)
+    .bind(&input.permissions)
+    .bind(input.expires_at)
+    .bind(rotated_from)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(|err| db_error("creating token", err))?;
+  Ok(CreatedToken { token, info })
+}
+
+/// Lock an active token for update, or 404 if it is revoked, expired or
+/// unknown. Tokens outside the admin's own scope are 403.
+async fn lock_active_token(
+  tx: &mut Transaction<'_, Postgres>,
+  state: &AppState,
+  auth: &AdminAuth,
+  id: i64,
+) -> Result<TokenInfo, Response> {
+  let sql = state.queries.get("get_active_token").map_err(query_error)?;
+  let token: Option<TokenInfo> = sqlx::query_as(sql)
+    .bind(id)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|err| db_error("loading token", err))?;
+  let token = token.ok_or_else(|| {
+    (StatusCode::NOT_FOUND, "Token not found").into_response()
+  })?;
+  if !scope_covers(&auth.principal.project_scope, &token.project_scope) {
+    return Err(out_of_scope());
+  }
+  Ok(token)
+}
+
+// POST /tokens
+pub async fn create_token(
+  auth: AdminAuth,
+  Extension(state): Extension<AppState>,
+  Json(input): Json<CreateTokenInput>,
+) -> Response {
+  if input.label.trim().is_empty() {
+    return (StatusCode::BAD_REQUEST, "Label must not be empty")
+      .into_response();
+  }
+  if input.project_scope.is_empty() {
+    return (StatusCode::BAD_REQUEST, "Project scope must not be empty")
+      .into_response();
+  }
+  if input.permissions.is_empty()
+    || input
+      .permissions
+      .iter()
+      .any(|p| Permission::parse(p).is_none())
+  {
+    return (
+      StatusCode::BAD_REQUEST,
+      "Permissions must be a non-empty list of list, read, write or admin",
+    )
+      .into_response();
+  }
+  if !scope_covers(&auth.principal.project_scope, &input.project_scope) {
+    return out_of_scope();
+  }
+
+  let mut tx = match state.write_pool.begin().await {
+    Ok(tx) => tx,
+    Err(err) => return db_error("starting transaction", err),
+  };
+  let created = match insert_token(&mut tx, &state, &input, None).await {
+    Ok(created) => created,
+    Err(response) => return response,
+  };
+  match tx.commit().await {
+    Ok(_) => (StatusCode::CREATED, Json(created)).into_response(),
+    Err(err) => db_error("creating token", err),
+  }
+}
+
+// GET /tokens
+pub async fn list_tokens(
+  auth: AdminAuth,
+  Extension(state): Extension<AppState>,
+) -> Response {
+  let sql = match state.queries.get("list_tokens") {
+    Ok(q) => q,
+    Err(err) => return query_error(err),
+  };
+
+  let result: Result<Vec<TokenInfo>, _> =
+    sqlx::query_as(sql).fetch_all(&state.read_pool).await;
+  match result {
+    Ok(tokens) => {
+      let visible: Vec<TokenInfo> = tokens
+        .into_iter()
+        .filter(|t| {
+          scope_covers(&auth.principal.project_scope, &t.project_scope)
+        })
+        .collect();
+      (StatusCode::OK, Json(visible)).into_response()
+    }
+    Err(err) => db_error("listing tokens", err),
+  }
+}
+
+// DELETE /tokens/{id}
+pub async fn revoke_token(
+  auth: AdminAuth,
+  Path(id): Path<i64>,
+  Extension(state): Extension<AppState>,
+) -> Response {
+  let sql = match state.queries.get("revoke_token") {
+    Ok(q) => q,
+    Err(err) => return query_error(err),
+  };
+
+  let mut tx = match state.write_pool.begin().await {
+    Ok(tx) => tx,
+    Err(err) => return db_error("starting transaction", err),
+  };
+  if let Err(response) = lock_active_token(&mut tx, &state, &auth, id).await {
+    return response;
+  }
+  if let Err(err) = sqlx::query(sql).bind(id).execute(&mut *tx).await {
+    return db_error("revoking token", err);
+  }
+  match tx.commit().await {
+    Ok(_) => StatusCode::NO_CONTENT.into_response(),
+    Err(err) => db_error("revoking token", err),
+  }
+}
+
+// POST /tokens/{id}/rotate
+pub async fn rotate_token(
+  auth: AdminAuth,
+  Path(id): Path<i64>,
+  Extension(state): Extension<AppState>,
+  input: Option<Json<RotateTokenInput>>,
+) -> Response {
+  let grace = input
+    .and_then(|Json(input)| input.grace_period_secs)
+    .unwrap_or(DEFAULT_GRACE_PERIOD_SECS);
+  if grace < 0 {
+    return (StatusCode::BAD_REQUEST, "Grace period must not be negative")
+      .into_response();
+  }
+  let sql = match state.queries.get("expire_token") {
+    Ok(q) => q,
+    Err(err) => return query_error(err),
+  };
+
+  let mut tx = match state.write_pool.begin().await {
+    Ok(tx) => tx,
+    Err(err) => return db_error("starting transaction", err),
+  };
+  let old = match lock_active_token(&mut tx, &state, &auth, id).await {
+    Ok(old) => old,
+    Err(response) => return response,
+  };
+  let replacement = CreateTokenInput {
+    label: old.label,
+    project_scope: old.project_scope,
+    permissions: old.permissions,
+    expires_at: old.expires_at,
+  };
+  let created =
+    match insert_token(&mut tx, &state, &replacement, Some(id)).await {
+      Ok(created) => created,
+      Err(response) => return response,
+    };
+  if let Err(err) = sqlx::query(sql)
+    .bind(id)
+    .bind(grace as f64)
+    .execute(&mut *tx)
+    .await
+  {
+    return db_error("expiring rotated token", err);
+  }
+  match tx.commit().await {
+    Ok(_) => (StatusCode::CREATED, Json(created)).into_response(),
+    Err(err) => db_error("rotating token", err),
+  }
+}
@@ -0,0 +1,367 @@
+//! Path-style keys such as `db/prod/password`, and operations on every
+//! secret below a path.
+//!
+//! A path names the secret with that key as well as every key under
+//! `path/`, so `db` covers `db`, `db/user` and `db/prod/password`.
+
+use axum::{
+  extract::{Extension, Json, Path, Query},
+  http::StatusCode,
+  response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+
+use crate::{
+  AppState, ListAuth, ProjectKey, StoredSecret, WriteAuth, audit::AuditTrail,
+  db_error, open_secrets, query_error, search::value_metadata,
+};
+
+/// Suffix of `GET /secrets/*key/versions`
+pub const VERSIONS_SUFFIX: &str = "/versions";
+/// Suffix of `POST /secrets/*key/rollback`
+pub const ROLLBACK_SUFFIX: &str = "/rollback";
+
+/// Reject keys that cannot be addressed as `/secrets/*key`: empty ones,
+/// empty segments, and keys ending in a segment the routes reserve.
+pub fn check_key(key: &str) -> Result<(), String> {
+  if key.is_empty() {
+    return Err("Empty key".to_string());
+  }
+  if key.split('/').any(str::is_empty) {
+    return Err(format!(
+      "Invalid key '{}': empty path segment or leading or trailing '/'",
+      key
+    ));
+  }
+  if key.ends_with(VERSIONS_SUFFIX) || key.ends_with(ROLLBACK_SUFFIX) {
+    return Err(format!(
+      "Invalid key '{}': '{}' and '{}' are reserved last segments",
+      key,
+      &VERSIONS_SUFFIX[1..],
+      &ROLLBACK_SUFFIX[1..]
+    ));
+  }
+  Ok(())
+}
+
+/// Whether `key` is `path` or lies below it.
+pub fn in_subtree(key: &str, path: &str) -> bool {
+  key
+    .strip_prefix(path)
+    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[derive(Serialize, Default)]
+pub struct TreeListing {
+  pub path: String,
+  /// Secrets directly under the path
+  pub secrets: Vec<String>,
+  /// Deeper paths holding further secrets
+  pub paths: Vec<String>,
+}
+
+/// Split `keys` below `path` (`""` for the root) into direct children and
+/// deeper paths. The secret named `path` itself is not its own child.
+pub fn children<'a>(
+  path: &str,
+  keys: impl IntoIterator<Item = &'a str>,
+) -> TreeListing {
+  let mut secrets = BTreeSet::new();
+  let mut paths = BTreeSet::new();
+  for key in keys {
+    let rest = if path.is_empty() {
+      key
+    } else {
+      match key.strip_prefix(path).and_then(|r| r.strip_prefix('/')) {
+        Some(rest) => rest,
+        None => continue,
+      }
+    };
+    let child_len = key.len() - rest.len();
+    match rest.find('/') {
+      Some(i) => paths.insert(key[..child_len + i].to_string()),
+      None => secrets.insert(key.to_string()),
+    };
+  }
+  TreeListing {
+    path: path.to_string(),
+    secrets: secrets.into_iter().collect(),
+    paths: paths.into_iter().collect(),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TreeOperation {
+  Copy,
+  /// Copy, then delete the source
+  Move,
+}
+
+#[derive(Deserialize)]
+pub struct CopyTreeInput {
+  pub operation: TreeOperation,
+  /// Destination path
+  pub to: String,
+  /// Destination project; defaults to the request's
+  pub project: Option<String>,
+  /// Write over secrets already at the destination
+  #[serde(default)]
+  pub overwrite: bool,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteTreeParams {
+  /// Must repeat the path
+  pub confirm: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TreeChange {
+  /// Keys written (copy, move) or deleted
+  pub keys: Vec<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct SubtreeKey {
+  secret_key: String,
+  version: i32,
+}
+
+fn bad_request(message: String) -> Response {
+  (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+fn path_not_found() -> Response {
+  (StatusCode::NOT_FOUND, "Path not found").into_response()
+}
+
+fn conflict_or_db_error(
+  context: &'static str,
+) -> impl Fn(sqlx::Error) -> Response {
+  move |err| match err {
+    sqlx::Error::Database(err) if err.is_unique_violation() => {
+      (StatusCode::CONFLICT, "Concurrent write, retry").into_response()
+    }
+    err => db_error(context, err),
+  }
+}
+
+// GET /tree[/*path]
+pub async fn list_tree(
+  _auth: ListAuth,
+  ProjectKey(project): ProjectKey,
+  path: Option<Path<String>>,
+  Extension(state): Extension<AppState>,
+) -> Result<Json<TreeListing>, Response> {
+  let path = path.map(|Path(path)| path).unwrap_or_default();
+  let sql = state.queries.get("list_subtree").map_err(query_error)?;
+  let keys: Vec<SubtreeKey> = sqlx::query_as(sql)
+    .bind(&project)
+    .bind(&path)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|err| db_error("listing path", err))?;
+  if keys.is_empty() && !path.is_empty() {
+    return Err(path_not_found());
+  }
+  Ok(Json(children(
+    &path,
+    keys.iter().map(|k| k.secret_key.as_str()),
+  )))
+}
+
+// DELETE /tree/*path?confirm=<path>
+pub async fn delete_tree(
+  auth: WriteAuth,
+  ProjectKey(project): ProjectKey,
+  Path(path): Path<String>,
+  Query(params): Query<DeleteTreeParams>,
+  Extension(state): Extension<AppState>,
+) -> Result<Json<TreeChange>, Response> {
+  let list_sql = state.queries.get("list_subtree").map_err(query_error)?;
+  let tombstone_sql = state
+    .queries
+    .get("tombstone_secrets")
+    .map_err(query_error)?;
+
+  let mut tx = state
+    .write_pool
+    .begin()
+    .await
+    .map_err(|err| db_error("starting path delete", err))?;
+  let keys: Vec<SubtreeKey> = sqlx::query_as(list_sql)
+    .bind(&project)
+    .bind(&path)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|err| db_error("listing path", err))?;
+  if keys.is_empty() {
+    return Err(path_not_found());
+  }
+  // A mistyped path could take out far more than meant
+  if params.confirm.as_deref() != Some(path.as_str()) {
+    return Err(bad_request(format!(
+      "Deleting '{}' removes {} secrets; repeat with confirm={}",
+      path,
+      keys.len(),
+      path
+    )));
+  }
+
+  let (names, versions): (Vec<String>, Vec<i32>) =
+    keys.into_iter().map(|k| (k.secret_key, k.version)).unzip();
+  sqlx::query(tombstone_sql)
+    .bind(&project)
+    .bind(&names)
+    .bind(&versions)
+    .bind(&auth.principal.label)
+    .execute(&mut *tx)
+    .await
+    .map_err(conflict_or_db_error("deleting path"))?;
+  tx.commit()
+    .await
+    .map_err(|err| db_error("committing path delete", err))?;
+  Ok(Json(TreeChange { keys: names }))
+}
+
+// POST /tree/*path  {"operation": "copy"|"move", "to": .., "project": ..}
+pub async fn copy_tree(
+  auth: WriteAuth,
+  ProjectKey(project): ProjectKey,
+  Path(path): Path<String>,
+  audit: AuditTrail,
+  Extension(state): Extension<AppState>,
+  Json(input): Json<CopyTreeInput>,
+) -> Result<Json<TreeChange>, Response> {
+  if input.operation == TreeOperation::Move {
+    audit.set_action("move_tree");
+  }
+  let to_project = input.project.unwrap_or_else(|| project.clone());
+  auth
+    .principal
+    .check_project(&to_project)
+    .map_err(IntoResponse::into_response)?;
+  check_key(&input.to).map_err(bad_request)?;
+  if to_project == project
+    && (in_subtree(&input.to, &path) || in_subtree(&path, &input.to))
+  {
+    return Err(bad_request(format!(
+      "'{}' and '{}' overlap",
+      path, input.to
+    )));
+  }
+
+  let secrets_sql = state
+    .queries
+    .get("list_subtree_secrets")
+    .map_err(query_error)?;
+  let keys_sql = state.queries.get("list_subtree").map_err(query_error)?;
+  let upsert_sql = state.queries.get("upsert_secret").map_err(query_error)?;
+  let tombstone_sql = state
+    .queries
+    .get("tombstone_secrets")
+    .map_err(query_error)?;
+
+  // All or nothing: the source, the destination check and every write
+  // share one transaction
+  let mut tx = state
+    .write_pool
+    .begin()
+    .await
+    .map_err(|err| db_error("starting path copy", err))?;
+  let rows: Vec<StoredSecret> = sqlx::query_as(secrets_sql)
+    .bind(&project)
+    .bind(&path)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|err| db_error("loading path", err))?;
+  if rows.is_empty() {
+    return Err(path_not_found());
+  }
+  let secrets = open_secrets(&state, &project, rows)
+    .map_err(IntoResponse::into_response)?;
+
+  // `path/x` lands at `to/x`
+  let targets: Vec<String> = secrets
+    .iter()
+    .map(|secret| format!("{}{}", input.to, &secret.key[path.len()..]))
+    .collect();
+  for target in &targets {
+    check_key(target).map_err(bad_request)?;
+  }
+  if !input.overwrite {
+    let existing: HashSet<String> = sqlx::query_as::<_, SubtreeKey>(keys_sql)
+      .bind(&to_project)
+      .bind(&input.to)
+      .fetch_all(&mut *tx)
+      .await
+      .map_err(|err| db_error("listing destination", err))?
+      .into_iter()
+      .map(|k| k.secret_key)
+      .collect();
+    let conflicts: Vec<&str> = targets
+      .iter()
+      .filter(|target| existing.contains(*target))
+      .map(String::as_str)
+      .collect();
+    if !conflicts.is_empty() {
+      return Err(
+        (
+          StatusCode::CONFLICT,
+          format!(
+            "Keys already exist: {}; pass overwrite=true to replace them",
+            conflicts.join(", ")
+          ),
+        )
+          .into_response(),
+      );
+    }
+  }
+
+  for (secret, target) in secrets.iter().zip(&targets) {
+    // Sealed values are bound to their project and key, so re-seal
+    let sealed = state
+      .master_key
+      .seal(&to_project, target, &secret.value)
+      .map_err(|err| {
+        tracing::error!("Failed to seal secret: {}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Encryption error").into_response()
+      })?;
+    let (size, content_type) = value_metadata(&secret.value);
+    sqlx::query(upsert_sql)
+      .bind(&to_project)
+      .bind(target)
+      .bind(&sealed.ciphertext)
+      .bind(&sealed.wrapped_key)
+      .bind(&sealed.key_id)
+      .bind(&auth.principal.label)
+      .bind(secret.expires_at)
+      .bind(None::<i32>)
+      .bind(size)
+      .bind(content_type)
+      .execute(&mut *tx)
+      .await
+      .map_err(conflict_or_db_error("copying secret"))?;
+  }
+  if input.operation == TreeOperation::Move {
+    let (names, versions): (Vec<&str>, Vec<i32>) = secrets
+      .iter()
+      .map(|secret| (secret.key.as_str(), secret.version))
+      .unzip();
+    sqlx::query(tombstone_sql)
+      .bind(&project)
+      .bind(&names)
+      .bind(&versions)
+      .bind(&auth.principal.label)
+      .execute(&mut *tx)
+      .await
+      .map_err(conflict_or_db_error("removing moved secrets"))?;
+  }
+
+  tx.commit()
+    .await
+    .map_err(|err| db_error("committing path copy", err))?;
+  Ok(Json(TreeChange { keys: targets }))
+}
@@ -1,21 +1,30 @@
-use axum::Extension;
+use aes_gcm::aead::OsRng;
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
 use axum::body::to_bytes;
+use axum::extract::ConnectInfo;
 use axum::http::{Request, StatusCode};
 use axum::{Router, body::Body};
 use dotenvy::dotenv;
 use once_cell::sync::Lazy;
 use serde_json::Value;
 use sqlx::{Executor, PgPool};
-use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 use tokio::sync::OnceCell;
 use tower::util::ServiceExt; // for .oneshot
 use uuid::Uuid;
 
-use keyvault::{
-  AppState, Queries, delete_secret, get_secret, search_secrets, upsert_secret,
-  upsert_secret_by_path,
+use keyvault::auth::{
+  glob_match, glob_to_like, hash_api_key, hash_token, scope_covers,
 };
+use keyvault::client::{Client, ClientError, Profile};
+use keyvault::config::Config;
+use keyvault::crypto::{MasterKey, SealedValue, check_master_key};
+use keyvault::migrate;
+use keyvault::{AppState, Queries, router};
 
 // Single-instance ephemeral test database for the suite
 static TEST_DB: Lazy<OnceCell<TestDb>> = Lazy::new(OnceCell::const_new);
@@ -47,79 +56,6 @@ impl TestDb {
     };
     let admin_pool = PgPool::connect(&admin_url).await.unwrap();
 
-    // ── ensure reader/writer roles exist ────────────────────────────────
-    admin_pool
-      .execute(
-        r#"DO $$
-BEGIN
-  IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname='secrets_reader') THEN
-      CREATE ROLE secrets_reader;
-  END IF;
-END
-$$;"#,
-      )
-      .await
-      .unwrap();
-    admin_pool
-      .execute(
-        r#"DO $$
-BEGIN
-  IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname='secrets_writer') THEN
-      CREATE ROLE secrets_writer;
-  END IF;
-END
-$$;"#,
-      )
-      .await
-      .unwrap();
-
-    // ── create login users and assign roles ─────────────────────────────
-    let read_user = std::env::var("SECRETS_READ_USER").unwrap();
-    let read_pwd = std::env::var("SECRETS_READ_PASSWORD").unwrap();
-    admin_pool
-      .execute(
-        format!(
-          r#"DO $$
-BEGIN
-  IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = '{0}') THEN
-      CREATE ROLE "{0}" LOGIN PASSWORD '{1}';
-  END IF;
-END
-$$;"#,
-          read_user, read_pwd
-        )
-        .as_str(),
-      )
-      .await
-      .unwrap();
-    admin_pool
-      .execute(format!("GRANT secrets_reader TO \"{}\";", read_user).as_str())
-      .await
-      .unwrap();
-
-    let write_user = std::env::var("SECRETS_WRITE_USER").unwrap();
-    let write_pwd = std::env::var("SECRETS_WRITE_PASSWORD").unwrap();
-    admin_pool
-      .execute(
-        format!(
-          r#"DO $$
-BEGIN
-  IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = '{0}') THEN
-      CREATE ROLE "{0}" LOGIN PASSWORD '{1}';
-  END IF;
-END
-$$;"#,
-          write_user, write_pwd
-        )
-        .as_str(),
-      )
-      .await
-      .unwrap();
-    admin_pool
-      .execute(format!("GRANT secrets_writer TO \"{}\";", write_user).as_str())
-      .await
-      .unwrap();
-
     // ── create a fresh database ────────────────────────────────────────
     let name = format!("testdb_{}", Uuid::new_v4().simple());
     admin_pool
@@ -127,22 +63,6 @@ $$;"#,
       .await
       .unwrap();
 
-    // ── allow roles to connect ─────────────────────────────────────────
-    admin_pool
-      .execute(
-        format!("GRANT CONNECT ON DATABASE {} TO secrets_reader;", name)
-          .as_str(),
-      )
-      .await
-      .unwrap();
-    admin_pool
-      .execute(
-        format!("GRANT CONNECT ON DATABASE {} TO secrets_writer;", name)
-          .as_str(),
-      )
-      .await
-      .unwrap();
-
     // Build test DB URL and connect as admin
     let test_url = if admin_pwd.is_empty() {
       format!("postgres://{}@{}/{}", admin_user, host, name)
@@ -151,56 +71,14 @@ $$;"#,
     };
     let test_admin = PgPool::connect(&test_url).await.unwrap();
 
-    // ── create schema and table ─────────────────────────────────────────
-    test_admin
-      .execute(r#"CREATE SCHEMA IF NOT EXISTS public;"#)
-      .await
-      .unwrap();
-    test_admin
-      .execute(
-        r#"
-          CREATE TABLE IF NOT EXISTS secrets (
-              project_key TEXT NOT NULL,
-              secret_key TEXT NOT NULL,
-              secret_value JSONB NOT NULL,
-              PRIMARY KEY (project_key, secret_key)
-          );
-      "#,
-      )
-      .await
-      .unwrap();
-
-    // ── grant privileges to roles ───────────────────────────────────────
-    test_admin
-      .execute(r#"GRANT USAGE ON SCHEMA public TO secrets_reader;"#)
-      .await
-      .unwrap();
-    test_admin
-      .execute(r#"GRANT USAGE ON SCHEMA public TO secrets_writer;"#)
-      .await
-      .unwrap();
-    test_admin
-      .execute(r#"GRANT SELECT ON secrets TO secrets_reader;"#)
-      .await
-      .unwrap();
-    test_admin
-      .execute(
-        r#"GRANT SELECT, INSERT, UPDATE, DELETE ON secrets TO secrets_writer;"#,
-      )
+    // ── schema, roles and grants from the shipped migrations ───────────
+    let config = test_config(&name);
+    migrate::up(&test_admin, &config.database, &config.keys.master_key)
       .await
       .unwrap();
 
     // ── seed initial data as admin ─────────────────────────────────────
-    sqlx::query(
-      "INSERT INTO secrets (project_key, secret_key, secret_value) VALUES \
-       ($1, $2, $3)",
-    )
-    .bind("test_project")
-    .bind("mykey")
-    .bind(serde_json::json!({"some":"value"}))
-    .execute(&test_admin)
-    .await
-    .unwrap();
+    seed_secrets(&test_admin).await;
 
     TestDb { name }
   }
@@ -236,10 +114,70 @@ impl Drop for TestDb {
 
 /// Ensure the ephemeral database is created once per test session
 async fn test_setup() {
-  // ensure the DB exists
-  let test_db = TEST_DB.get_or_init(TestDb::init).await;
+  // ensure the DB exists, then reconnect as admin directly into it
+  let test_admin = test_admin_pool().await;
+
+  // reset table and reseed the original secret
+  test_admin
+    .execute("TRUNCATE TABLE secret_versions, tokens, projects;")
+    .await
+    .unwrap();
+  seed_secrets(&test_admin).await;
+}
+
+/// Master key used to seal every value in the test database
+fn test_master_key() -> MasterKey {
+  MasterKey::from_bytes(&[7u8; 32]).unwrap()
+}
+
+/// Insert the original sealed secret
+async fn seed_secrets(pool: &PgPool) {
+  let sealed = test_master_key()
+    .seal(
+      "test_project",
+      "mykey",
+      &serde_json::json!({"some":"value"}),
+    )
+    .unwrap();
+  sqlx::query(
+    "INSERT INTO secret_versions (project_key, secret_key, version, \
+     ciphertext, wrapped_key, key_id, created_by) VALUES ($1, $2, 1, $3, $4, \
+     $5, 'seed')",
+  )
+  .bind("test_project")
+  .bind("mykey")
+  .bind(&sealed.ciphertext)
+  .bind(&sealed.wrapped_key)
+  .bind(&sealed.key_id)
+  .execute(pool)
+  .await
+  .unwrap();
+}
+
+/// Store a scoped API token the way an operator would
+async fn insert_token(
+  token: &str,
+  scope: &str,
+  permissions: &[&str],
+  expires_in_secs: Option<i64>,
+) {
+  sqlx::query(
+    "INSERT INTO tokens (label, token_hash, project_scope, permissions, \
+     expires_at) VALUES ($1, $2, $3, $4, now() + make_interval(secs => $5))",
+  )
+  .bind(format!("label-{}", token))
+  .bind(hash_token(token))
+  .bind(scope)
+  .bind(permissions)
+  .bind(expires_in_secs.map(|s| s as f64))
+  .execute(&test_admin_pool().await)
+  .await
+  .unwrap();
+}
 
-  // reconnect as admin directly into that new test DB
+/// Connect as admin to the ephemeral database
+async fn test_admin_pool() -> PgPool {
+  let test_db = TEST_DB.get_or_init(TestDb::init).await;
   let admin_user = std::env::var("POSTGRES_USER").unwrap();
   let admin_pwd = std::env::var("POSTGRES_PASSWORD").unwrap();
   let host = std::env::var("PG_HOST").unwrap_or_else(|_| "localhost".into());
@@ -252,103 +190,62 @@ async fn test_setup() {
       admin_user, admin_pwd, host, test_db.name
     )
   };
-  let test_admin = PgPool::connect(&test_url).await.unwrap();
+  PgPool::connect(&test_url).await.unwrap()
+}
 
-  // reset table and reseed the original secret
-  test_admin.execute("TRUNCATE TABLE secrets;").await.unwrap();
-  sqlx::query(
-    "INSERT INTO secrets (project_key, secret_key, secret_value) VALUES ($1, \
-     $2, $3)",
-  )
-  .bind("test_project")
-  .bind("mykey")
-  .bind(serde_json::json!({"some":"value"}))
-  .execute(&test_admin)
-  .await
-  .unwrap();
+/// Configuration from the harness environment plus fixed test keys
+/// Argon2 hash of the write key. The cost is read back from the hash, so a
+/// minimal one keeps the unoptimized test build fast.
+static WRITE_KEY_HASH: Lazy<String> = Lazy::new(|| {
+  let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+  Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    .hash_password(b"test-api-key-write", &SaltString::generate(&mut OsRng))
+    .unwrap()
+    .to_string()
+});
+
+fn test_config(db_name: &str) -> Config {
+  Config::from_sources(None, |name| match name {
+    "POSTGRES_DB" => Some(db_name.to_string()),
+    "SECRETS_MASTER_KEY" => Some(hex::encode([7u8; 32])),
+    // one key per supported form: SHA-256, Argon2 and deprecated plaintext
+    "API_MASTER_KEY_READ_HASH" => hash_api_key("test-api-key-read", false).ok(),
+    "API_MASTER_KEY_WRITE_HASH" => Some(WRITE_KEY_HASH.clone()),
+    "API_MASTER_KEY_ADMIN" => Some("test-api-key-admin".to_string()),
+    "PG_HOST" => Some(std::env::var(name).unwrap_or("localhost".into())),
+    "KEYVAULT_TRUSTED_PROXIES" => Some("127.0.0.1, 172.16.0.1".to_string()),
+    _ => std::env::var(name).ok(),
+  })
+  .expect("SECRETS_READ_USER, SECRETS_WRITE_USER and passwords must be set")
 }
 
 /// Build AppState pointing at the ephemeral DB
 async fn create_test_state() -> AppState {
   test_setup().await;
-  // Set API key headers
-  unsafe {
-    std::env::set_var("API_MASTER_KEY_READ", "test-api-key-read");
-    std::env::set_var("API_MASTER_KEY_WRITE", "test-api-key-write");
-  }
-
-  // Queries map
-  let mut queries_map = HashMap::new();
-  queries_map.insert(
-    "get_secret".into(),
-    "SELECT secret_value FROM secrets WHERE secret_key = $1 AND project_key = \
-     $2"
-      .into(),
-  );
-  queries_map.insert(
-    "upsert_secret".into(),
-    // match your handler: project_key first, then key, then value::jsonb
-    "INSERT INTO secrets (project_key, secret_key, secret_value) VALUES ($1, \
-     $2, $3::jsonb) ON CONFLICT (project_key, secret_key) DO UPDATE SET \
-     secret_value = EXCLUDED.secret_value"
-      .into(),
-  );
-
-  // ─── support DELETE /secrets/:key ───────────
-  queries_map.insert(
-    "delete_secret".into(),
-    "DELETE FROM secrets WHERE secret_key = $1 AND project_key = $2".into(),
-  );
-  // ─── support POST /search ────────────────────
-  // ILIKE '%%' will match everything when term == ""
-  queries_map.insert(
-    "search_secrets".into(),
-    "SELECT secret_key, project_key, secret_value FROM secrets WHERE \
-     project_key = $1 AND secret_key ILIKE '%' || $2 || '%'"
-      .into(),
-  );
+  // Same query catalog the server loads at startup
+  let queries = Queries::embedded();
 
-  let queries = Queries(queries_map);
+  let config = test_config(&TEST_DB.get().unwrap().name);
 
   // Build read/write pools with vault roles
-  let host = std::env::var("PG_HOST").unwrap_or_else(|_| "localhost".into());
-  let db_name = &TEST_DB.get().unwrap().name;
-  let read_user =
-    std::env::var("SECRETS_READ_USER").expect("SECRETS_READ_USER must be set");
-  let read_pwd = std::env::var("SECRETS_READ_PASSWORD")
-    .expect("SECRETS_READ_PASSWORD must be set");
-  let write_user = std::env::var("SECRETS_WRITE_USER")
-    .expect("SECRETS_WRITE_USER must be set");
-  let write_pwd = std::env::var("SECRETS_WRITE_PASSWORD")
-    .expect("SECRETS_WRITE_PASSWORD must be set");
-
-  let read_url =
-    format!("postgres://{}:{}@{}/{}", read_user, read_pwd, host, db_name);
-  let write_url = format!(
-    "postgres://{}:{}@{}/{}",
-    write_user, write_pwd, host, db_name
-  );
-
-  let read_pool = PgPool::connect_lazy(&read_url).unwrap();
-  let write_pool = PgPool::connect_lazy(&write_url).unwrap();
-
-  AppState { read_pool, write_pool, queries }
+  let read_pool = PgPool::connect_lazy(&config.database.read_url()).unwrap();
+  let write_pool = PgPool::connect_lazy(&config.database.write_url()).unwrap();
+
+  AppState {
+    read_pool,
+    write_pool,
+    queries,
+    master_key: config.keys.master_key.clone(),
+    config: Arc::new(config),
+  }
 }
 
 /// Create test HTTP app and shared state
 async fn create_test_app() -> (Router, AppState) {
   let state = create_test_state().await;
 
-  let app = Router::new()
-    .route(
-      "/secrets/{key}",
-      axum::routing::get(get_secret)
-        .put(upsert_secret_by_path)
-        .delete(delete_secret),
-    )
-    .route("/secrets", axum::routing::post(upsert_secret))
-    .route("/search", axum::routing::post(search_secrets))
-    .layer(Extension(state.clone()));
+  // Same routes and layers the server uses
+  let app = router(state.clone());
 
   (app, state)
 }
@@ -538,7 +435,7 @@ async fn test_upsert_secret_with_read_key_forbidden() {
     )
     .await
     .unwrap();
-  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
 }
 
 #[tokio::test]
@@ -652,7 +549,8 @@ async fn test_search_secrets_without_query_returns_all() {
     .unwrap();
   assert_eq!(res.status(), StatusCode::OK);
   let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
-  let arr: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  let arr = page["items"].as_array().unwrap();
   // Should contain at least two entries
   assert!(arr.len() >= 2);
 }
@@ -675,7 +573,8 @@ async fn test_search_secrets_with_query_filters() {
     .unwrap();
   assert_eq!(res.status(), StatusCode::OK);
   let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
-  let arr: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  let arr = page["items"].as_array().unwrap();
   // Should only return keys matching 'myk'
   assert!(
     arr
@@ -725,7 +624,8 @@ async fn test_search_excludes_nonmatching() {
 
   // Parse and verify results
   let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
-  let arr: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  let arr = page["items"].as_array().unwrap();
   assert_eq!(arr.len(), 1, "Expected exactly one match");
   assert_eq!(arr[0]["secret_key"].as_str().unwrap(), "mykey");
 }
@@ -777,6 +677,2653 @@ async fn test_search_ignores_body_project_key_override() {
   // 3) We should get back an empty array, because "othersecret" lives under
   //    other_project, not test_project—our header binding wins.
   let bytes = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
-  let arr: Vec<Value> = serde_json::from_slice(&bytes).unwrap();
+  let page: Value = serde_json::from_slice(&bytes).unwrap();
+  let arr = page["items"].as_array().unwrap();
+  assert!(arr.is_empty(), "Expected no results, got {:?}", arr);
+}
+
+#[tokio::test]
+async fn test_secret_value_encrypted_at_rest() {
+  let (app, _state) = create_test_app().await;
+  let payload = r#"{"key":"dbpass","value":{"password":"hunter2-plaintext"}}"#;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/secrets")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .header("content-type", "application/json")
+        .body(Body::from(payload))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+  // The stored row must not contain the plaintext
+  let admin = test_admin_pool().await;
+  let (ciphertext, wrapped_key, key_id): (Vec<u8>, Vec<u8>, String) =
+    sqlx::query_as(
+      "SELECT ciphertext, wrapped_key, key_id FROM secret_versions WHERE \
+       project_key = 'test_project' AND secret_key = 'dbpass'",
+    )
+    .fetch_one(&admin)
+    .await
+    .unwrap();
+  let needle = b"hunter2-plaintext";
+  assert!(!ciphertext.windows(needle.len()).any(|w| w == needle));
+  assert!(!wrapped_key.windows(needle.len()).any(|w| w == needle));
+  assert_eq!(key_id, test_master_key().fingerprint());
+
+  // ...while reads decrypt transparently
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/dbpass")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let json: Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(json, serde_json::json!({"password":"hunter2-plaintext"}));
+}
+
+#[tokio::test]
+async fn test_sealed_value_bound_to_its_key() {
+  let key = test_master_key();
+  let value = serde_json::json!({"a": 1});
+  let sealed = key.seal("test_project", "one", &value).unwrap();
+  assert_eq!(key.open("test_project", "one", &sealed).unwrap(), value);
+  // Swapping a ciphertext under another key or project must fail
+  assert!(key.open("test_project", "two", &sealed).is_err());
+  assert!(key.open("other_project", "one", &sealed).is_err());
+}
+
+#[tokio::test]
+async fn test_master_key_fingerprint_mismatch_refused() {
+  let state = create_test_state().await;
+  // The test key is recorded on first check and matches afterwards
+  check_master_key(&state.write_pool, &state.queries, &state.master_key)
+    .await
+    .unwrap();
+  check_master_key(&state.write_pool, &state.queries, &state.master_key)
+    .await
+    .unwrap();
+
+  let other = MasterKey::from_bytes(&[9u8; 32]).unwrap();
+  let err = check_master_key(&state.write_pool, &state.queries, &other)
+    .await
+    .unwrap_err();
+  assert!(err.contains("does not match"), "unexpected error: {}", err);
+  assert!(MasterKey::from_hex("not-hex").is_err());
+  assert!(MasterKey::from_hex("abcd").is_err());
+}
+
+#[tokio::test]
+async fn test_search_quoted_injection_is_bound_as_data() {
+  let (app, _state) = create_test_app().await;
+  let res = app
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/search")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "test_project")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"query":"\"a' OR 1=1 --\""}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  // The phrase is a literal: no SQL error and no match-everything
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  let arr = page["items"].as_array().unwrap();
   assert!(arr.is_empty(), "Expected no results, got {:?}", arr);
 }
+
+#[tokio::test]
+async fn test_overwrite_keeps_previous_versions() {
+  let (app, _state) = create_test_app().await;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("PUT")
+        .uri("/secrets/mykey")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"value":{"some":"rotated"}}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+  // The history lists both versions, newest first, with the writer
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/mykey/versions")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let versions: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  assert_eq!(versions.len(), 2);
+  assert_eq!(versions[0]["version"], 2);
+  assert_eq!(versions[0]["created_by"], "API_MASTER_KEY_WRITE");
+  assert_eq!(versions[1]["version"], 1);
+  assert!(versions[0]["created_at"].is_string());
+
+  // The original value is still readable by version
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/mykey?version=1")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  // only the live version has an ETag to write against
+  assert!(res.headers().get("etag").is_none());
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let json: Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(json, serde_json::json!({"some":"value"}));
+}
+
+#[tokio::test]
+async fn test_rollback_restores_old_version() {
+  let (app, _state) = create_test_app().await;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("PUT")
+        .uri("/secrets/mykey")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"value":"broken"}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/secrets/mykey/rollback")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"version":1}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let json: Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(json, serde_json::json!({"version": 3}));
+
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/mykey")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let json: Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(json, serde_json::json!({"some":"value"}));
+
+  // Rollbacks honor If-Match like other writes
+  let rollback = Some(serde_json::json!({ "version": 2 }));
+  let (status, _) = send_conditional(
+    &app,
+    "POST",
+    "/secrets/mykey/rollback",
+    "test_project",
+    &[("if-match", "\"2\"")],
+    rollback.clone(),
+  )
+  .await;
+  assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+  let (status, etag) = send_conditional(
+    &app,
+    "POST",
+    "/secrets/mykey/rollback",
+    "test_project",
+    &[("if-match", "\"3\"")],
+    rollback,
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(etag.as_deref(), Some("\"4\""));
+
+  // Unknown versions cannot be restored
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/secrets/mykey/rollback")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"version":42}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+  // Posting to a plain key is not a route, whatever the body holds
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/secrets/mykey")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"value":"x"}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn test_delete_appends_tombstone() {
+  let (app, _state) = create_test_app().await;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("DELETE")
+        .uri("/secrets/mykey")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/mykey/versions")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let versions: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  assert_eq!(versions.len(), 2);
+  assert_eq!(versions[0]["deleted"], true);
+
+  // The tombstone itself has no value, but the deleted version survives
+  for (uri, status) in [
+    ("/secrets/mykey?version=2", StatusCode::NOT_FOUND),
+    ("/secrets/mykey?version=1", StatusCode::OK),
+  ] {
+    let res = app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .uri(uri)
+          .header("x-api-key", "test-api-key-read")
+          .header("x-project-key", "test_project")
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(res.status(), status, "{}", uri);
+  }
+
+  // Deleting again does not stack tombstones
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("DELETE")
+        .uri("/secrets/mykey")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+  let admin = test_admin_pool().await;
+  let (count,): (i64,) = sqlx::query_as(
+    "SELECT COUNT(*) FROM secret_versions WHERE secret_key = 'mykey'",
+  )
+  .fetch_one(&admin)
+  .await
+  .unwrap();
+  assert_eq!(count, 2);
+}
+
+#[test]
+fn test_project_scope_globs() {
+  assert!(glob_match("*", "anything"));
+  assert!(glob_match("test_*", "test_project"));
+  assert!(glob_match("test_?roject", "test_project"));
+  assert!(glob_match("*-prod", "billing-prod"));
+  assert!(glob_match("a*b*c", "a-x-b-y-c"));
+  assert!(!glob_match("test_*", "other_project"));
+  assert!(!glob_match("test_project", "test_project2"));
+  assert!(!glob_match("a*b", "a-x-c"));
+  // the same globs filter in SQL, where `_` and `%` are wildcards of their own
+  assert_eq!(glob_to_like("test_*"), "test\\_%");
+  assert_eq!(glob_to_like("50%?"), "50\\%_");
+}
+
+#[tokio::test]
+async fn test_scoped_token_rejects_other_project() {
+  let (app, _state) = create_test_app().await;
+  insert_token("scoped-read", "test_*", &["read"], None).await;
+
+  for (project, status) in [
+    ("test_project", StatusCode::OK),
+    ("other_project", StatusCode::FORBIDDEN),
+  ] {
+    let res = app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .uri("/secrets/mykey")
+          .header("x-api-key", "scoped-read")
+          .header("x-project-key", project)
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(res.status(), status, "project {}", project);
+  }
+
+  // The same scope check guards search
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/search")
+        .header("x-api-key", "scoped-read")
+        .header("x-project-key", "other_project")
+        .header("content-type", "application/json")
+        .body(Body::from("{}"))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_token_permissions_enforced() {
+  let (app, _state) = create_test_app().await;
+  insert_token("read-only", "test_project", &["read"], None).await;
+  insert_token("write-only", "test_project", &["write"], None).await;
+
+  let write = |token: &'static str| {
+    Request::builder()
+      .method("PUT")
+      .uri("/secrets/mykey")
+      .header("x-api-key", token)
+      .header("x-project-key", "test_project")
+      .header("content-type", "application/json")
+      .body(Body::from(r#"{"value":"v"}"#))
+      .unwrap()
+  };
+  // A known token without the permission is forbidden, not unknown
+  let res = app.clone().oneshot(write("read-only")).await.unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  assert_eq!(&body[..], b"Permission not granted to token");
+  let res = app.clone().oneshot(write("unknown")).await.unwrap();
+  assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+  let res = app.clone().oneshot(write("write-only")).await.unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+  // write-only tokens cannot read, not even the history
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/mykey/versions")
+        .header("x-api-key", "write-only")
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  // Writes are attributed to the token's label
+  let admin = test_admin_pool().await;
+  let (created_by,): (String,) = sqlx::query_as(
+    "SELECT created_by FROM secret_versions WHERE secret_key = 'mykey' \
+     ORDER BY version DESC LIMIT 1",
+  )
+  .fetch_one(&admin)
+  .await
+  .unwrap();
+  assert_eq!(created_by, "label-write-only");
+}
+
+#[tokio::test]
+async fn test_expired_token_rejected() {
+  let (app, _state) = create_test_app().await;
+  insert_token("expired", "*", &["read"], Some(-60)).await;
+  insert_token("fresh", "*", &["read"], Some(3600)).await;
+
+  for (token, status) in [
+    ("expired", StatusCode::UNAUTHORIZED),
+    ("fresh", StatusCode::OK),
+  ] {
+    let res = app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .uri("/secrets/mykey")
+          .header("x-api-key", token)
+          .header("x-project-key", "test_project")
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(res.status(), status, "token {}", token);
+  }
+}
+
+#[tokio::test]
+async fn test_audit_records_access_without_values() {
+  let (app, _state) = create_test_app().await;
+  let payload = r#"{"key":"auditkey","value":{"pw":"super-secret-value"}}"#;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/secrets")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "audit_project")
+        .header("content-type", "application/json")
+        // through two trusted proxies
+        .header("x-forwarded-for", "10.1.2.3, 172.16.0.1")
+        .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))))
+        .body(Body::from(payload))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+  // Every response carries the request id that is written to the log
+  assert!(res.headers().contains_key("x-request-id"));
+
+  for api_key in ["test-api-key-read", "wrong-api-key"] {
+    app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .uri("/secrets/auditkey")
+          .header("x-api-key", api_key)
+          .header("x-project-key", "audit_project")
+          // a direct client cannot pick its own address
+          .header("x-forwarded-for", "10.9.9.9")
+          .extension(ConnectInfo(SocketAddr::from(([192, 0, 2, 7], 40000))))
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+  }
+
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/audit?project=audit_project&key=auditkey")
+        .header("x-api-key", "test-api-key-admin")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  assert!(!String::from_utf8_lossy(&body).contains("super-secret-value"));
+  let events: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  let summary: Vec<(&str, &str, Option<&str>)> = events
+    .iter()
+    .map(|e| {
+      (
+        e["action"].as_str().unwrap(),
+        e["outcome"].as_str().unwrap(),
+        e["actor"].as_str(),
+      )
+    })
+    .collect();
+  // Newest first; the failed read has no known actor
+  assert_eq!(
+    summary,
+    vec![
+      ("read", "unauthorized", None),
+      ("read", "success", Some("API_MASTER_KEY_READ")),
+      ("write", "success", Some("API_MASTER_KEY_WRITE")),
+    ]
+  );
+  assert_eq!(events[2]["client_ip"], "10.1.2.3");
+  assert_eq!(events[0]["client_ip"], "192.0.2.7");
+  assert!(events.iter().all(|e| e["request_id"].is_string()));
+}
+
+#[tokio::test]
+async fn test_audit_filters_and_requires_admin() {
+  let (app, _state) = create_test_app().await;
+  insert_token("audit-scoped", "test_project", &["read"], None).await;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/mykey")
+        .header("x-api-key", "audit-scoped")
+        .header("x-project-key", "audit_other")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  // Scope violations are attributed to the token that attempted them
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/audit?actor=label-audit-scoped&since=2000-01-01T00:00:00Z")
+        .header("x-api-key", "test-api-key-admin")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let events: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  assert_eq!(events.len(), 1);
+  assert_eq!(events[0]["outcome"], "forbidden");
+  assert_eq!(events[0]["project_key"], "audit_other");
+  assert!(events[0]["token_id"].is_i64());
+
+  // Nothing is returned for a window in the past
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/audit?actor=label-audit-scoped&until=2000-01-01T00:00:00Z")
+        .header("x-api-key", "test-api-key-admin")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+  let events: Vec<Value> = serde_json::from_slice(&body).unwrap();
+  assert!(events.is_empty());
+
+  // A scoped admin only sees events in its own projects
+  insert_token("audit-admin-own", "audit_o*", &["admin"], None).await;
+  insert_token("audit-admin-other", "test_*", &["admin"], None).await;
+  for (admin, expected) in [("audit-admin-own", 1), ("audit-admin-other", 0)] {
+    let res = app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .uri("/audit?actor=label-audit-scoped")
+          .header("x-api-key", admin)
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = to_bytes(res.into_body(), 1024 * 1024).await.unwrap();
+    let events: Vec<Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(events.len(), expected, "{}", admin);
+  }
+
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/audit")
+        .header("x-api-key", "test-api-key-write")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_timed_out_request_is_audited() {
+  let mut state = create_test_state().await;
+  let mut config = test_config(&TEST_DB.get().unwrap().name);
+  config.request_timeout = Duration::from_secs(1);
+  state.config = Arc::new(config);
+  let app = router(state);
+  insert_token("timeout-victim", "*", &["read"], None).await;
+
+  // Hold the token's row so revoking it waits past the timeout
+  let admin = test_admin_pool().await;
+  let mut tx = admin.begin().await.unwrap();
+  let (id,): (i64,) = sqlx::query_as(
+    "SELECT id FROM tokens WHERE label = 'label-timeout-victim' FOR UPDATE",
+  )
+  .fetch_one(&mut *tx)
+  .await
+  .unwrap();
+  let res = app
+    .oneshot(
+      Request::builder()
+        .method("DELETE")
+        .uri(format!("/tokens/{}", id))
+        .header("x-api-key", "test-api-key-admin")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+  tx.rollback().await.unwrap();
+
+  let (outcome,): (String,) = sqlx::query_as(
+    "SELECT outcome FROM audit_events \
+     WHERE action = 'revoke_token' AND status = 408",
+  )
+  .fetch_one(&admin)
+  .await
+  .unwrap();
+  assert_eq!(outcome, "rejected");
+}
+
+/// Issue a token through the admin API, returning the response body
+async fn create_token_via_api(
+  app: &Router,
+  admin_key: &str,
+  body: &str,
+) -> Value {
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/tokens")
+        .header("x-api-key", admin_key)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::CREATED);
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  serde_json::from_slice(&body).unwrap()
+}
+
+/// Status of reading the seeded secret with `token`
+async fn read_status(app: &Router, token: &str) -> StatusCode {
+  app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/mykey")
+        .header("x-api-key", token)
+        .header("x-project-key", "test_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap()
+    .status()
+}
+
+#[test]
+fn test_scope_covers() {
+  assert!(scope_covers("*", "*"));
+  assert!(scope_covers("*", "test_*"));
+  assert!(scope_covers("test_*", "test_project"));
+  assert!(scope_covers("test_*", "test_a*"));
+  assert!(scope_covers("test_?", "test_?"));
+  assert!(!scope_covers("test_*", "*"));
+  assert!(!scope_covers("test_?", "test_*"));
+  assert!(!scope_covers("test_a", "test_?"));
+  assert!(!scope_covers("test_project", "other_project"));
+}
+
+#[tokio::test]
+async fn test_token_admin_create_and_list() {
+  let (app, _state) = create_test_app().await;
+  let created = create_token_via_api(
+    &app,
+    "test-api-key-admin",
+    r#"{"label":"ci","project_scope":"test_*","permissions":["read"]}"#,
+  )
+  .await;
+  let token = created["token"].as_str().unwrap().to_string();
+  let id = created["id"].as_i64().unwrap();
+  assert_eq!(created["label"], "ci");
+  assert_eq!(read_status(&app, &token).await, StatusCode::OK);
+
+  // Listing shows metadata only
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/tokens")
+        .header("x-api-key", "test-api-key-admin")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  let text = String::from_utf8(body.to_vec()).unwrap();
+  assert!(!text.contains(&token));
+  assert!(!text.contains(&hash_token(&token)));
+  let tokens: Value = serde_json::from_str(&text).unwrap();
+  let listed = tokens
+    .as_array()
+    .unwrap()
+    .iter()
+    .find(|t| t["id"] == id)
+    .expect("created token listed");
+  assert_eq!(listed["project_scope"], "test_*");
+  assert_eq!(listed["permissions"], serde_json::json!(["read"]));
+  assert!(listed.get("token").is_none());
+  assert!(listed.get("token_hash").is_none());
+
+  // Unknown permissions are rejected
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/tokens")
+        .header("x-api-key", "test-api-key-admin")
+        .header("content-type", "application/json")
+        .body(Body::from(
+          r#"{"label":"x","project_scope":"*","permissions":["root"]}"#,
+        ))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_token_admin_revoke() {
+  let (app, _state) = create_test_app().await;
+  let created = create_token_via_api(
+    &app,
+    "test-api-key-admin",
+    r#"{"label":"revoke-me","project_scope":"*","permissions":["read"]}"#,
+  )
+  .await;
+  let token = created["token"].as_str().unwrap();
+  let id = created["id"].as_i64().unwrap();
+  assert_eq!(read_status(&app, token).await, StatusCode::OK);
+
+  let revoke = || {
+    Request::builder()
+      .method("DELETE")
+      .uri(format!("/tokens/{}", id))
+      .header("x-api-key", "test-api-key-admin")
+      .body(Body::empty())
+      .unwrap()
+  };
+  let res = app.clone().oneshot(revoke()).await.unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+  assert_eq!(read_status(&app, token).await, StatusCode::UNAUTHORIZED);
+
+  // Revoking twice finds nothing to revoke
+  let res = app.clone().oneshot(revoke()).await.unwrap();
+  assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_token_admin_rotate_with_grace_period() {
+  let (app, _state) = create_test_app().await;
+  let rotate = |id: i64, body: &'static str| {
+    Request::builder()
+      .method("POST")
+      .uri(format!("/tokens/{}/rotate", id))
+      .header("x-api-key", "test-api-key-admin")
+      .header("content-type", "application/json")
+      .body(Body::from(body))
+      .unwrap()
+  };
+
+  for (grace, old_status) in [
+    (r#"{"grace_period_secs":3600}"#, StatusCode::OK),
+    (r#"{"grace_period_secs":0}"#, StatusCode::UNAUTHORIZED),
+  ] {
+    let created = create_token_via_api(
+      &app,
+      "test-api-key-admin",
+      r#"{"label":"rotating","project_scope":"*","permissions":["read"]}"#,
+    )
+    .await;
+    let old = created["token"].as_str().unwrap();
+    let id = created["id"].as_i64().unwrap();
+
+    let res = app.clone().oneshot(rotate(id, grace)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let rotated: Value = serde_json::from_slice(&body).unwrap();
+    let new = rotated["token"].as_str().unwrap();
+    assert_ne!(new, old);
+    assert_eq!(rotated["rotated_from"], id);
+    assert_eq!(rotated["label"], "rotating");
+
+    assert_eq!(read_status(&app, new).await, StatusCode::OK);
+    assert_eq!(read_status(&app, old).await, old_status, "grace {}", grace);
+  }
+}
+
+#[tokio::test]
+async fn test_token_admin_requires_admin_within_scope() {
+  let (app, _state) = create_test_app().await;
+  insert_token("scoped-admin", "test_*", &["admin"], None).await;
+
+  // Non-admin keys cannot manage tokens
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/tokens")
+        .header("x-api-key", "test-api-key-write")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  // A scoped admin cannot mint a token broader than its own scope
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/tokens")
+        .header("x-api-key", "scoped-admin")
+        .header("content-type", "application/json")
+        .body(Body::from(
+          r#"{"label":"wide","project_scope":"*","permissions":["read"]}"#,
+        ))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  // ...nor revoke one outside it
+  let created = create_token_via_api(
+    &app,
+    "test-api-key-admin",
+    r#"{"label":"global","project_scope":"*","permissions":["read"]}"#,
+  )
+  .await;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("DELETE")
+        .uri(format!("/tokens/{}", created["id"]))
+        .header("x-api-key", "scoped-admin")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  // but it can issue narrower ones
+  let narrow = create_token_via_api(
+    &app,
+    "scoped-admin",
+    r#"{"label":"narrow","project_scope":"test_project","permissions":["read"]}"#,
+  )
+  .await;
+  assert_eq!(
+    read_status(&app, narrow["token"].as_str().unwrap()).await,
+    StatusCode::OK
+  );
+}
+
+#[tokio::test]
+async fn test_master_key_hashes_verified() {
+  let (app, _state) = create_test_app().await;
+  assert_eq!(
+    hash_api_key("k", false).unwrap(),
+    format!("sha256:{}", hash_token("k"))
+  );
+
+  for (key, status) in [
+    ("test-api-key-read", StatusCode::OK),
+    ("test-api-key-write", StatusCode::OK),
+    ("test-api-key-admin", StatusCode::OK),
+    // same length as a real key, one character off
+    ("test-api-key-reae", StatusCode::UNAUTHORIZED),
+    ("", StatusCode::UNAUTHORIZED),
+  ] {
+    assert_eq!(read_status(&app, key).await, status, "key {:?}", key);
+  }
+
+  // Wrong keys checked at once, or again, are still refused
+  let checks: Vec<_> = (0..8)
+    .map(|i| {
+      let app = app.clone();
+      tokio::spawn(
+        async move { read_status(&app, &format!("k{}", i % 4)).await },
+      )
+    })
+    .collect();
+  for check in checks {
+    assert_eq!(check.await.unwrap(), StatusCode::UNAUTHORIZED);
+  }
+  assert_eq!(
+    read_status(&app, "test-api-key-reae").await,
+    StatusCode::UNAUTHORIZED
+  );
+}
+
+#[tokio::test]
+async fn test_query_catalog_validated() {
+  let state = create_test_state().await;
+  let queries = Queries::embedded();
+  queries.validate().unwrap();
+  queries.check_prepared(&state.write_pool).await.unwrap();
+
+  // A misspelt override is reported by name
+  let err = Queries::embedded()
+    .with_overrides("get_secert: SELECT 1")
+    .unwrap()
+    .validate()
+    .unwrap_err();
+  assert!(err.contains("unknown query 'get_secert'"), "{}", err);
+
+  // So is a statement the database rejects
+  let broken = Queries::embedded()
+    .with_overrides("get_secret: SELECT ciphertext FROM secretz")
+    .unwrap();
+  broken.validate().unwrap();
+  let err = broken.check_prepared(&state.write_pool).await.unwrap_err();
+  assert!(
+    err.contains("query 'get_secret' does not prepare"),
+    "{}",
+    err
+  );
+  assert!(!err.contains("get_secret_version"), "{}", err);
+}
+
+#[tokio::test]
+async fn test_migrations_up_status_down() {
+  // The shared database is at the version the binary expects
+  let state = create_test_state().await;
+  migrate::check_schema_version(&state.read_pool)
+    .await
+    .unwrap();
+
+  // Walk a scratch database down and back up
+  let test_admin = test_admin_pool().await;
+  let name = format!("testdb_{}", Uuid::new_v4().simple());
+  test_admin
+    .execute(format!("CREATE DATABASE {}", name).as_str())
+    .await
+    .unwrap();
+  let config = test_config(&name);
+  let admin = PgPool::connect(&config.database.admin_url().unwrap())
+    .await
+    .unwrap();
+
+  let err = migrate::check_schema_version(&admin).await.unwrap_err();
+  assert!(err.contains("keyvault migrate up"), "{}", err);
+  assert!(
+    migrate::status(&admin)
+      .await
+      .unwrap()
+      .iter()
+      .all(|m| m.applied_at.is_none())
+  );
+
+  migrate::up(&admin, &config.database, &config.keys.master_key)
+    .await
+    .unwrap();
+  // running again is a no-op
+  migrate::up(&admin, &config.database, &config.keys.master_key)
+    .await
+    .unwrap();
+  let status = migrate::status(&admin).await.unwrap();
+  assert!(status.iter().all(|m| m.applied_at.is_some() && m.success));
+  assert_eq!(status.last().unwrap().version, migrate::expected_version());
+
+  // The reader login can check the version but not write
+  let reader = PgPool::connect(&config.database.read_url()).await.unwrap();
+  migrate::check_schema_version(&reader).await.unwrap();
+  assert!(
+    reader
+      .execute("INSERT INTO vault_meta VALUES ('x', 'y')")
+      .await
+      .is_err()
+  );
+  reader.close().await;
+
+  migrate::down_to(&admin, 0).await.unwrap();
+  let (tables,): (i64,) = sqlx::query_as(
+    "SELECT count(*) FROM pg_tables WHERE schemaname = 'public' \
+     AND tablename <> '_sqlx_migrations'",
+  )
+  .fetch_one(&admin)
+  .await
+  .unwrap();
+  assert_eq!(tables, 0);
+  assert!(migrate::check_schema_version(&admin).await.is_err());
+
+  admin.close().await;
+  test_admin
+    .execute(format!("DROP DATABASE {} WITH (FORCE)", name).as_str())
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_migrate_up_from_baseline_schema() {
+  // A database from before encryption and versioning: one plaintext row per
+  // key
+  let test_admin = test_admin_pool().await;
+  let name = format!("testdb_{}", Uuid::new_v4().simple());
+  test_admin
+    .execute(format!("CREATE DATABASE {}", name).as_str())
+    .await
+    .unwrap();
+  let config = test_config(&name);
+  let admin = PgPool::connect(&config.database.admin_url().unwrap())
+    .await
+    .unwrap();
+  admin
+    .execute(
+      r#"
+      CREATE TABLE secrets (
+          project_key TEXT NOT NULL,
+          secret_key TEXT NOT NULL,
+          secret_value JSONB NOT NULL,
+          PRIMARY KEY (project_key, secret_key)
+      );
+      INSERT INTO secrets VALUES
+        ('legacy', 'db', '{"user": "app", "port": 5432}'),
+        ('legacy', 'token', '"abc"');
+      "#,
+    )
+    .await
+    .unwrap();
+
+  migrate::up(&admin, &config.database, &config.keys.master_key)
+    .await
+    .unwrap();
+  migrate::check_schema_version(&admin).await.unwrap();
+
+  // Each row is now version 1, sealed, with its metadata
+  let rows: Vec<(String, i32, i32, String)> = sqlx::query_as(
+    "SELECT secret_key, version, value_size, content_type FROM secrets \
+     WHERE project_key = 'legacy' ORDER BY secret_key",
+  )
+  .fetch_all(&admin)
+  .await
+  .unwrap();
+  assert_eq!(rows.len(), 2);
+  let mut values = Vec::new();
+  for (key, version, size, content_type) in rows {
+    assert_eq!(version, 1);
+    let sealed: SealedValue = sqlx::query_as(
+      "SELECT ciphertext, wrapped_key, key_id FROM secrets \
+       WHERE project_key = 'legacy' AND secret_key = $1",
+    )
+    .bind(&key)
+    .fetch_one(&admin)
+    .await
+    .unwrap();
+    let value = config
+      .keys
+      .master_key
+      .open("legacy", &key, &sealed)
+      .unwrap();
+    assert_eq!(size as usize, value.to_string().len());
+    values.push((key, value, content_type));
+  }
+  assert_eq!(
+    values,
+    vec![
+      (
+        "db".to_string(),
+        serde_json::json!({"user": "app", "port": 5432}),
+        "object".to_string()
+      ),
+      (
+        "token".to_string(),
+        serde_json::json!("abc"),
+        "string".to_string()
+      ),
+    ]
+  );
+  // No plaintext is left behind
+  let (plaintext,): (bool,) = sqlx::query_as(
+    "SELECT EXISTS (SELECT 1 FROM information_schema.columns \
+     WHERE column_name = 'secret_value')",
+  )
+  .fetch_one(&admin)
+  .await
+  .unwrap();
+  assert!(!plaintext);
+  let (projects,): (i64,) = sqlx::query_as(
+    "SELECT count(*) FROM projects WHERE project_key = 'legacy'",
+  )
+  .fetch_one(&admin)
+  .await
+  .unwrap();
+  assert_eq!(projects, 1);
+
+  // The old binary cannot read sealed values, so 0001 is not reverted and
+  // nothing else is either
+  let err = migrate::down_to(&admin, 0).await.unwrap_err();
+  assert!(err.contains("cannot be reverted"), "{}", err);
+  migrate::check_schema_version(&admin).await.unwrap();
+  let (kept,): (i64,) = sqlx::query_as("SELECT count(*) FROM secrets")
+    .fetch_one(&admin)
+    .await
+    .unwrap();
+  assert_eq!(kept, 2);
+  // Nor can the migration itself be made to revert it
+  migrate::down_to(&admin, 1).await.unwrap();
+  let err = admin
+    .execute(include_str!("../migrations/0001_initial_schema.down.sql"))
+    .await
+    .unwrap_err();
+  assert!(err.to_string().contains("cannot be reverted"), "{}", err);
+
+  admin.close().await;
+  test_admin
+    .execute(format!("DROP DATABASE {} WITH (FORCE)", name).as_str())
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_cli_client_against_server() {
+  let (app, _state) = create_test_app().await;
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let endpoint = format!("http://{}", listener.local_addr().unwrap());
+  tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+  let client_for = |api_key: &str| {
+    Client::new(&Profile {
+      endpoint: Some(endpoint.clone()),
+      api_key: Some(api_key.to_string()),
+      project: Some("test_project".to_string()),
+    })
+    .unwrap()
+  };
+  let client = client_for("test-api-key-write");
+
+  // keys are percent-encoded into the path
+  let value = serde_json::json!({"user": "app", "pw": "p@ss word"});
+  client.put("cli key", &value).await.unwrap();
+  assert_eq!(client.get("cli key").await.unwrap(), value);
+  let entries = client.search(Some("cli")).await.unwrap();
+  assert_eq!(entries, vec![("cli key".to_string(), value)]);
+
+  client.delete("cli key").await.unwrap();
+  let err = client.get("cli key").await.unwrap_err();
+  assert!(matches!(err, ClientError::NotFound(_)), "{}", err);
+  assert_eq!(err.exit_code(), 3);
+
+  let err = client_for("wrong-key").get("mykey").await.unwrap_err();
+  assert!(matches!(err, ClientError::Unauthorized(_)), "{}", err);
+  assert_eq!(err.exit_code(), 4);
+
+  let err = client.search(Some("a:(")).await.unwrap_err();
+  assert!(matches!(err, ClientError::Rejected(_)), "{}", err);
+  assert_eq!(err.exit_code(), 6);
+}
+
+/// Send `body` to the import endpoint, returning status and response text
+async fn import(app: &Router, query: &str, body: &str) -> (StatusCode, String) {
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri(format!("/projects/import_project/import?{}", query))
+        .header("x-api-key", "test-api-key-write")
+        .body(Body::from(body.to_string()))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let status = res.status();
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+async fn export(app: &Router, format: &str) -> String {
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri(format!("/projects/import_project/export?format={}", format))
+        .header("x-api-key", "test-api-key-read")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn test_import_conflicts_and_export_round_trip() {
+  let (app, _state) = create_test_app().await;
+
+  let dotenv = "# comment\nexport DB_USER=app\nDB_PASSWORD=\"p\\\"w\" # x\n";
+  let (status, body) = import(&app, "format=dotenv", dotenv).await;
+  assert_eq!(status, StatusCode::OK, "{}", body);
+  let summary: Value = serde_json::from_str(&body).unwrap();
+  assert_eq!(
+    summary["created"],
+    serde_json::json!(["DB_USER", "DB_PASSWORD"])
+  );
+
+  // The default policy rejects the whole import, including new keys
+  let yaml = "DB_USER: other\nconfig:\n  ports: [1, 2]\n";
+  let (status, body) = import(&app, "format=yaml", yaml).await;
+  assert_eq!(status, StatusCode::CONFLICT);
+  assert!(body.contains("DB_USER"), "{}", body);
+  assert!(!export(&app, "json").await.contains("config"));
+
+  let (status, body) = import(&app, "format=yaml&on_conflict=skip", yaml).await;
+  assert_eq!(status, StatusCode::OK, "{}", body);
+  let summary: Value = serde_json::from_str(&body).unwrap();
+  assert_eq!(summary["created"], serde_json::json!(["config"]));
+  assert_eq!(summary["skipped"], serde_json::json!(["DB_USER"]));
+
+  // Nested values survive an export and re-import unchanged
+  let exported = export(&app, "yaml").await;
+  let (status, body) =
+    import(&app, "format=yaml&on_conflict=overwrite", &exported).await;
+  assert_eq!(status, StatusCode::OK, "{}", body);
+  let json: Value = serde_json::from_str(&export(&app, "json").await).unwrap();
+  assert_eq!(
+    json,
+    serde_json::json!({
+      "DB_PASSWORD": "p\"w",
+      "DB_USER": "app",
+      "config": {"ports": [1, 2]},
+    })
+  );
+  assert_eq!(
+    export(&app, "dotenv").await,
+    "DB_PASSWORD=\"p\\\"w\"\nDB_USER=\"app\"\nconfig=\"{\\\"ports\\\":[1,2]}\"\n"
+  );
+
+  let (status, _) = import(&app, "format=json", "[1, 2]").await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+  let (status, _) = import(&app, "", "{}").await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+/// Send a JSON request as `api_key`, returning status and parsed body
+async fn call(
+  app: &Router,
+  method: &str,
+  uri: &str,
+  api_key: &str,
+  body: Option<Value>,
+) -> (StatusCode, Value) {
+  let request = Request::builder()
+    .method(method)
+    .uri(uri)
+    .header("x-api-key", api_key)
+    .header("content-type", "application/json");
+  let res = app
+    .clone()
+    .oneshot(
+      request
+        .body(body.map_or(Body::empty(), |b| Body::from(b.to_string())))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let status = res.status();
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn test_project_metadata_lifecycle() {
+  let (app, _state) = create_test_app().await;
+  let write = "test-api-key-write";
+  let admin = "test-api-key-admin";
+
+  let (status, project) = call(
+    &app,
+    "POST",
+    "/projects",
+    write,
+    Some(serde_json::json!({
+      "project_key": "proj_a",
+      "description": "Payments",
+      "labels": {"team": "billing"},
+    })),
+  )
+  .await;
+  assert_eq!(status, StatusCode::CREATED);
+  assert_eq!(project["owner"], "API_MASTER_KEY_WRITE");
+  let (status, _) = call(
+    &app,
+    "POST",
+    "/projects",
+    write,
+    Some(serde_json::json!({"project_key": "proj_a"})),
+  )
+  .await;
+  assert_eq!(status, StatusCode::CONFLICT);
+
+  // Writing a secret registers its project
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("PUT")
+        .uri("/secrets/pw")
+        .header("x-api-key", write)
+        .header("x-project-key", "proj_b")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"value":"x"}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+  let (_, projects) = call(&app, "GET", "/projects", admin, None).await;
+  let names: Vec<&str> = projects
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|p| p["project_key"].as_str().unwrap())
+    .collect();
+  assert_eq!(names, vec!["proj_a", "proj_b"]);
+  insert_token("proj-a-reader", "proj_a*", &["read"], None).await;
+  let (_, projects) =
+    call(&app, "GET", "/projects", "proj-a-reader", None).await;
+  assert_eq!(projects.as_array().unwrap().len(), 1);
+  // Listing is enough to see them
+  insert_token("proj-b-lister", "proj_b", &["list"], None).await;
+  let (status, projects) =
+    call(&app, "GET", "/projects", "proj-b-lister", None).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(projects[0]["project_key"], "proj_b");
+  assert_eq!(projects.as_array().unwrap().len(), 1);
+
+  let (status, project) = call(
+    &app,
+    "PATCH",
+    "/projects/proj_a",
+    write,
+    Some(serde_json::json!({"labels": {"tier": "1"}})),
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(project["description"], "Payments");
+  assert_eq!(project["labels"], serde_json::json!({"tier": "1"}));
+
+  // Deleting needs admin, and force while secrets remain
+  let (status, _) = call(&app, "DELETE", "/projects/proj_b", write, None).await;
+  assert_eq!(status, StatusCode::FORBIDDEN);
+  let (status, _) = call(&app, "DELETE", "/projects/proj_b", admin, None).await;
+  assert_eq!(status, StatusCode::CONFLICT);
+  let (status, _) =
+    call(&app, "DELETE", "/projects/proj_b?force=true", admin, None).await;
+  assert_eq!(status, StatusCode::NO_CONTENT);
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets/pw")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "proj_b")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+  let (status, _) = call(&app, "DELETE", "/projects/proj_a", admin, None).await;
+  assert_eq!(status, StatusCode::NO_CONTENT);
+  let (status, _) = call(
+    &app,
+    "PATCH",
+    "/projects/proj_a",
+    write,
+    Some(serde_json::json!({})),
+  )
+  .await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+/// One `/search` call against `page_project`
+async fn search_page(app: &Router, body: Value) -> (StatusCode, Value) {
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/search")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "page_project")
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let status = res.status();
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn page_keys(page: &Value) -> Vec<&str> {
+  page["items"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|item| item["secret_key"].as_str().unwrap())
+    .collect()
+}
+
+#[tokio::test]
+async fn test_search_pagination_and_sort() {
+  let (app, _state) = create_test_app().await;
+  // written out of key order, so update order differs from key order
+  for key in ["c", "a", "e", "b", "d"] {
+    let res = app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .method("PUT")
+          .uri(format!("/secrets/{}", key))
+          .header("x-api-key", "test-api-key-write")
+          .header("x-project-key", "page_project")
+          .header("content-type", "application/json")
+          .body(Body::from(r#"{"value":"v"}"#))
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+  }
+
+  let mut keys = Vec::new();
+  let mut body = serde_json::json!({"limit": 2, "total": true});
+  loop {
+    let (status, page) = search_page(&app, body.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", page);
+    assert_eq!(page["total"], 5);
+    keys.extend(page_keys(&page).iter().map(|k| k.to_string()));
+    match page["next_cursor"].as_str() {
+      Some(cursor) => body["cursor"] = cursor.into(),
+      None => break,
+    }
+  }
+  assert_eq!(keys, vec!["a", "b", "c", "d", "e"]);
+
+  // Past the last match the page is empty, but the total still counts
+  let (_, page) = search_page(&app, serde_json::json!({"limit": 4})).await;
+  let (status, page) = search_page(
+    &app,
+    serde_json::json!({
+      "query": "secret_key:[a TO d]",
+      "cursor": page["next_cursor"],
+      "total": true,
+    }),
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK, "{}", page);
+  assert!(page_keys(&page).is_empty());
+  assert_eq!(page["total"], 4);
+
+  // Values come with the page even when the query needed none
+  let (_, page) = search_page(
+    &app,
+    serde_json::json!({"query": "secret_key:b", "fields": "all"}),
+  )
+  .await;
+  assert_eq!(page["items"][0]["secret_value"], "v");
+
+  let (_, page) = search_page(
+    &app,
+    serde_json::json!({"sort": "updated_at", "order": "desc", "limit": 3}),
+  )
+  .await;
+  assert_eq!(page_keys(&page), vec!["d", "b", "e"]);
+  assert!(page.get("total").is_none());
+  let cursor = page["next_cursor"].clone();
+  let (_, page) = search_page(
+    &app,
+    serde_json::json!({"sort": "updated_at", "order": "desc", "cursor": cursor}),
+  )
+  .await;
+  assert_eq!(page_keys(&page), vec!["a", "c"]);
+  assert!(page["next_cursor"].is_null());
+
+  // A cursor only continues the sort it came from
+  let (status, _) =
+    search_page(&app, serde_json::json!({"cursor": cursor})).await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+  let (status, _) = search_page(&app, serde_json::json!({"limit": 0})).await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_search_scan_limit() {
+  let mut state = create_test_state().await;
+  let mut config = test_config(&TEST_DB.get().unwrap().name);
+  config.search_scan_limit = 2;
+  state.config = Arc::new(config);
+  let app = router(state);
+  for (key, value) in [("a", 1), ("b", 2), ("c", 1), ("d", 1), ("e", 2)] {
+    let res = app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .method("PUT")
+          .uri(format!("/secrets/{}", key))
+          .header("x-api-key", "test-api-key-write")
+          .header("x-project-key", "scan_project")
+          .header("content-type", "application/json")
+          .body(Body::from(format!(r#"{{"value":{{"n":{}}}}}"#, value)))
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+  }
+  let search = |body: Value| {
+    let app = app.clone();
+    async move {
+      let res = app
+        .oneshot(
+          Request::builder()
+            .method("POST")
+            .uri("/search")
+            .header("x-api-key", "test-api-key-read")
+            .header("x-project-key", "scan_project")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+      let status = res.status();
+      let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+      (
+        status,
+        serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null),
+      )
+    }
+  };
+
+  // Each request tests two secrets, so pages end early but carry on
+  let mut pages = Vec::new();
+  let mut body = serde_json::json!({"query": "n:1", "limit": 10});
+  loop {
+    let (status, page) = search(body.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", page);
+    pages.push(page_keys(&page).join(","));
+    match page["next_cursor"].as_str() {
+      Some(cursor) => body["cursor"] = cursor.into(),
+      None => break,
+    }
+  }
+  assert_eq!(pages, vec!["a", "c,d", ""]);
+
+  // Counting a query on values would open every secret
+  let (status, _) =
+    search(serde_json::json!({"query": "n:1", "total": true})).await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+  let (status, page) =
+    search(serde_json::json!({"query": "secret_key:[b TO *]", "total": true}))
+      .await;
+  assert_eq!(status, StatusCode::OK, "{}", page);
+  assert_eq!(page["total"], 4);
+  assert_eq!(page_keys(&page), vec!["b"]);
+  assert!(page["next_cursor"].is_string());
+}
+
+#[tokio::test]
+async fn test_key_listing_without_values() {
+  let (app, _state) = create_test_app().await;
+  for (key, value) in [
+    ("db/password", r#""hunter2""#),
+    ("db/user", r#""app""#),
+    ("other", r#"{"a":1}"#),
+  ] {
+    let res = app
+      .clone()
+      .oneshot(
+        Request::builder()
+          .method("PUT")
+          .uri(format!("/secrets/{}", key.replace('/', "%2F")))
+          .header("x-api-key", "test-api-key-write")
+          .header("x-project-key", "list_project")
+          .header("content-type", "application/json")
+          .body(Body::from(format!(r#"{{"value":{}}}"#, value)))
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+  }
+  insert_token("lister", "list_*", &["list"], None).await;
+  let send = |method: &str, uri: &str, body: &str| {
+    Request::builder()
+      .method(method)
+      .uri(uri)
+      .header("x-api-key", "lister")
+      .header("x-project-key", "list_project")
+      .header("content-type", "application/json")
+      .body(Body::from(body.to_string()))
+      .unwrap()
+  };
+
+  let res = app
+    .clone()
+    .oneshot(send("GET", "/secrets?prefix=db/", ""))
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  let items = page["items"].as_array().unwrap();
+  assert_eq!(items.len(), 2);
+  assert_eq!(items[0]["secret_key"], "db/password");
+  assert_eq!(items[0]["version"], 1);
+  assert_eq!(items[0]["size"], 9);
+  assert_eq!(items[0]["content_type"], "string");
+  assert!(items.iter().all(|item| item.get("secret_value").is_none()));
+  assert!(!String::from_utf8_lossy(&body).contains("hunter2"));
+
+  let res = app
+    .clone()
+    .oneshot(send("GET", "/secrets?limit=1&total=true", ""))
+    .await
+    .unwrap();
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(page["total"], 3);
+  assert!(page["next_cursor"].is_string());
+
+  // The total counts the whole listing, not what is left after the cursor
+  let mut cursor = page["next_cursor"].as_str().unwrap().to_string();
+  for _ in 0..2 {
+    let res = app
+      .clone()
+      .oneshot(send(
+        "GET",
+        &format!("/secrets?limit=1&total=true&cursor={}", cursor),
+        "",
+      ))
+      .await
+      .unwrap();
+    let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let page: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(page["items"].as_array().unwrap().len(), 1);
+    assert_eq!(page["total"], 3);
+    if let Some(next) = page["next_cursor"].as_str() {
+      cursor = next.to_string();
+    }
+  }
+
+  // Listing does not grant reading values, not even through search
+  let res = app
+    .clone()
+    .oneshot(send("GET", "/secrets/db%2Fpassword", ""))
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+  let res = app
+    .clone()
+    .oneshot(send("POST", "/search", r#"{"fields":"keys"}"#))
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+  // Readers can ask search for keys only
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/search")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "list_project")
+        .header("content-type", "application/json")
+        .body(Body::from(
+          r#"{"query":"secret_key:other","fields":"keys"}"#,
+        ))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(page["items"][0]["content_type"], "object");
+  assert!(page["items"][0].get("secret_value").is_none());
+}
+
+#[tokio::test]
+async fn test_migrate_fills_value_metadata() {
+  let (app, _state) = create_test_app().await;
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .method("PUT")
+        .uri("/secrets/sized")
+        .header("x-api-key", "test-api-key-write")
+        .header("x-project-key", "metadata_project")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"value":[1,2,3]}"#))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+  // A version written before the metadata columns existed
+  let admin = test_admin_pool().await;
+  admin
+    .execute(
+      "UPDATE secret_versions SET value_size = NULL, content_type = NULL \
+       WHERE project_key = 'metadata_project'",
+    )
+    .await
+    .unwrap();
+  let config = test_config(&TEST_DB.get().unwrap().name);
+  migrate::up(&admin, &config.database, &config.keys.master_key)
+    .await
+    .unwrap();
+
+  let res = app
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/secrets")
+        .header("x-api-key", "test-api-key-read")
+        .header("x-project-key", "metadata_project")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(res.status(), StatusCode::OK);
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  let page: Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(page["items"][0]["size"], 7);
+  assert_eq!(page["items"][0]["content_type"], "array");
+}
+
+/// Send a JSON request to `project` with the write key
+async fn call_in(
+  app: &Router,
+  method: &str,
+  uri: &str,
+  project: &str,
+  body: Option<Value>,
+) -> (StatusCode, Value) {
+  let request = Request::builder()
+    .method(method)
+    .uri(uri)
+    .header("x-api-key", "test-api-key-write")
+    .header("x-project-key", project)
+    .header("content-type", "application/json");
+  let res = app
+    .clone()
+    .oneshot(
+      request
+        .body(body.map_or(Body::empty(), |b| Body::from(b.to_string())))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let status = res.status();
+  let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+  (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn test_path_keys_and_subtree_operations() {
+  let (app, _state) = create_test_app().await;
+  for key in [
+    "db/prod/password",
+    "db/prod/user",
+    "db/staging/user",
+    "db/url",
+  ] {
+    let (status, _) = call_in(
+      &app,
+      "PUT",
+      &format!("/secrets/{}", key),
+      "tree_project",
+      Some(serde_json::json!({ "value": key })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+  }
+  let (status, value) = call_in(
+    &app,
+    "GET",
+    "/secrets/db/prod/password",
+    "tree_project",
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(value, "db/prod/password");
+  let (status, versions) = call_in(
+    &app,
+    "GET",
+    "/secrets/db/prod/password/versions",
+    "tree_project",
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(versions[0]["version"], 1);
+
+  // The routes' own suffixes cannot be keys
+  let (status, _) = call_in(
+    &app,
+    "PUT",
+    "/secrets/db/versions",
+    "tree_project",
+    Some(serde_json::json!({ "value": 1 })),
+  )
+  .await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+
+  let (status, listing) =
+    call_in(&app, "GET", "/tree/db", "tree_project", None).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(listing["secrets"], serde_json::json!(["db/url"]));
+  assert_eq!(
+    listing["paths"],
+    serde_json::json!(["db/prod", "db/staging"])
+  );
+  let (_, listing) = call_in(&app, "GET", "/tree", "tree_project", None).await;
+  assert_eq!(listing["paths"], serde_json::json!(["db"]));
+  let (status, _) =
+    call_in(&app, "GET", "/tree/nothing", "tree_project", None).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+
+  // Copy into another project, then move within this one
+  let (status, copied) = call_in(
+    &app,
+    "POST",
+    "/tree/db/prod",
+    "tree_project",
+    Some(serde_json::json!({
+      "operation": "copy", "to": "db", "project": "tree_other",
+    })),
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(
+    copied["keys"],
+    serde_json::json!(["db/password", "db/user"])
+  );
+  let (_, value) =
+    call_in(&app, "GET", "/secrets/db/password", "tree_other", None).await;
+  assert_eq!(value, "db/prod/password");
+
+  let move_to = |to: &str, overwrite: bool| {
+    Some(serde_json::json!({
+      "operation": "move", "to": to, "overwrite": overwrite,
+    }))
+  };
+  let (status, _) = call_in(
+    &app,
+    "POST",
+    "/tree/db/staging",
+    "tree_project",
+    move_to("db/prod", false),
+  )
+  .await;
+  assert_eq!(status, StatusCode::CONFLICT);
+  let (status, _) = call_in(
+    &app,
+    "POST",
+    "/tree/db",
+    "tree_project",
+    move_to("db/prod/old", false),
+  )
+  .await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+  let (status, moved) = call_in(
+    &app,
+    "POST",
+    "/tree/db/staging",
+    "tree_project",
+    move_to("db/prod", true),
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(moved["keys"], serde_json::json!(["db/prod/user"]));
+  let (_, value) =
+    call_in(&app, "GET", "/secrets/db/prod/user", "tree_project", None).await;
+  assert_eq!(value, "db/staging/user");
+  let (status, _) = call_in(
+    &app,
+    "GET",
+    "/secrets/db/staging/user",
+    "tree_project",
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+
+  // Deleting a subtree needs the path repeated
+  let (status, _) =
+    call_in(&app, "DELETE", "/tree/db/prod", "tree_project", None).await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+  let (status, deleted) = call_in(
+    &app,
+    "DELETE",
+    "/tree/db/prod?confirm=db/prod",
+    "tree_project",
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(
+    deleted["keys"],
+    serde_json::json!(["db/prod/password", "db/prod/user"])
+  );
+  let (_, listing) =
+    call_in(&app, "GET", "/tree/db", "tree_project", None).await;
+  assert_eq!(listing["secrets"], serde_json::json!(["db/url"]));
+  assert_eq!(listing["paths"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn test_expired_secrets_gone_and_reaped() {
+  let (app, state) = create_test_app().await;
+  let project = "expiry_project";
+  for (key, body) in [
+    ("temp", serde_json::json!({ "value": "t", "ttl": 3600 })),
+    (
+      "dated",
+      serde_json::json!({ "value": "d", "expires_at": "2999-01-01T00:00:00Z" }),
+    ),
+    ("kept", serde_json::json!({ "value": "k" })),
+  ] {
+    let (status, _) = call_in(
+      &app,
+      "PUT",
+      &format!("/secrets/{}", key),
+      project,
+      Some(body),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+  }
+  for body in [
+    serde_json::json!({ "value": 1, "ttl": 0 }),
+    serde_json::json!({ "value": 1, "expires_at": "2000-01-01T00:00:00Z" }),
+    serde_json::json!({ "value": 1, "ttl": 5, "expires_at": "2999-01-01T00:00:00Z" }),
+  ] {
+    let (status, _) =
+      call_in(&app, "PUT", "/secrets/bad", project, Some(body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+  }
+
+  let (_, page) = call_in(
+    &app,
+    "POST",
+    "/search",
+    project,
+    Some(serde_json::json!({})),
+  )
+  .await;
+  assert_eq!(page_keys(&page), vec!["dated", "kept", "temp"]);
+  assert_eq!(page["items"][0]["expires_at"], "2999-01-01T00:00:00Z");
+  assert!(page["items"][1].get("expires_at").is_none());
+
+  // Let `temp` run out
+  sqlx::query(
+    "UPDATE secret_versions SET expires_at = now() - interval '1 second' \
+     WHERE project_key = $1 AND secret_key = 'temp'",
+  )
+  .bind(project)
+  .execute(&test_admin_pool().await)
+  .await
+  .unwrap();
+  let (status, _) = call_in(&app, "GET", "/secrets/temp", project, None).await;
+  assert_eq!(status, StatusCode::GONE);
+  // Copies leave it behind, so there is nothing to copy
+  let (status, _) = call_in(
+    &app,
+    "POST",
+    "/tree/temp",
+    project,
+    Some(serde_json::json!({ "operation": "copy", "to": "copied" })),
+  )
+  .await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  let (status, _) =
+    call_in(&app, "GET", "/secrets/copied", project, None).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  let (_, page) = call_in(
+    &app,
+    "POST",
+    "/search",
+    project,
+    Some(serde_json::json!({})),
+  )
+  .await;
+  assert_eq!(page_keys(&page), vec!["dated", "kept"]);
+  let (_, page) = call_in(
+    &app,
+    "POST",
+    "/search",
+    project,
+    Some(serde_json::json!({ "include_expired": true })),
+  )
+  .await;
+  assert_eq!(page_keys(&page), vec!["dated", "kept", "temp"]);
+
+  // The reaper tombstones it, keeping the history
+  assert_eq!(keyvault::expiry::reap_expired(&state).await.unwrap(), 1);
+  assert_eq!(keyvault::expiry::reap_expired(&state).await.unwrap(), 0);
+  let (status, _) = call_in(&app, "GET", "/secrets/temp", project, None).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  let (_, versions) =
+    call_in(&app, "GET", "/secrets/temp/versions", project, None).await;
+  assert_eq!(versions[0]["deleted"], true);
+  assert_eq!(versions[0]["created_by"], keyvault::expiry::REAPER);
+
+  // Nor does a rollback bring it back
+  let (status, _) = call_in(
+    &app,
+    "POST",
+    "/secrets/temp/rollback",
+    project,
+    Some(serde_json::json!({ "version": 1 })),
+  )
+  .await;
+  assert_eq!(status, StatusCode::GONE);
+  let (_, after) =
+    call_in(&app, "GET", "/secrets/temp/versions", project, None).await;
+  assert_eq!(after, versions);
+}
+
+/// Send a write-key request to `project` with extra headers, returning the
+/// status and the ETag
+async fn send_conditional(
+  app: &Router,
+  method: &str,
+  uri: &str,
+  project: &str,
+  headers: &[(&str, &str)],
+  body: Option<Value>,
+) -> (StatusCode, Option<String>) {
+  let mut request = Request::builder()
+    .method(method)
+    .uri(uri)
+    .header("x-api-key", "test-api-key-write")
+    .header("x-project-key", project)
+    .header("content-type", "application/json");
+  for (name, value) in headers {
+    request = request.header(*name, *value);
+  }
+  let res = app
+    .clone()
+    .oneshot(
+      request
+        .body(body.map_or(Body::empty(), |b| Body::from(b.to_string())))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let etag = res
+    .headers()
+    .get("etag")
+    .map(|v| v.to_str().unwrap().to_string());
+  (res.status(), etag)
+}
+
+#[tokio::test]
+async fn test_etags_and_conditional_writes() {
+  let (app, _state) = create_test_app().await;
+  let project = "etag_project";
+  let value = |v: i32| Some(serde_json::json!({ "value": v }));
+
+  // Create-only
+  let (status, etag) = send_conditional(
+    &app,
+    "PUT",
+    "/secrets/counter",
+    project,
+    &[("if-none-match", "*")],
+    value(1),
+  )
+  .await;
+  assert_eq!(status, StatusCode::NO_CONTENT);
+  assert_eq!(etag.as_deref(), Some("\"1\""));
+  let (status, _) = send_conditional(
+    &app,
+    "PUT",
+    "/secrets/counter",
+    project,
+    &[("if-none-match", "*")],
+    value(2),
+  )
+  .await;
+  assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+  let (status, etag) =
+    send_conditional(&app, "GET", "/secrets/counter", project, &[], None).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(etag.as_deref(), Some("\"1\""));
+
+  // Two editors holding version 1: the second one loses
+  let (status, etag) = send_conditional(
+    &app,
+    "PUT",
+    "/secrets/counter",
+    project,
+    &[("if-match", "\"1\"")],
+    value(2),
+  )
+  .await;
+  assert_eq!(status, StatusCode::NO_CONTENT);
+  assert_eq!(etag.as_deref(), Some("\"2\""));
+  let (status, _) = send_conditional(
+    &app,
+    "PUT",
+    "/secrets/counter",
+    project,
+    &[("if-match", "\"1\"")],
+    value(3),
+  )
+  .await;
+  assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+  let (_, current) =
+    call_in(&app, "GET", "/secrets/counter", project, None).await;
+  assert_eq!(current, 2);
+
+  // If-Match: * needs the secret to exist
+  let (status, _) = send_conditional(
+    &app,
+    "PUT",
+    "/secrets/missing",
+    project,
+    &[("if-match", "*")],
+    value(1),
+  )
+  .await;
+  assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+  // POST takes the expected version in the body
+  let post = |expected: i32| {
+    Some(serde_json::json!({
+      "key": "counter", "value": 9, "expected_version": expected,
+    }))
+  };
+  let (status, _) =
+    send_conditional(&app, "POST", "/secrets", project, &[], post(1)).await;
+  assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+  let (status, etag) =
+    send_conditional(&app, "POST", "/secrets", project, &[], post(2)).await;
+  assert_eq!(status, StatusCode::NO_CONTENT);
+  assert_eq!(etag.as_deref(), Some("\"3\""));
+
+  // Deletes honor If-Match too
+  let (status, _) = send_conditional(
+    &app,
+    "DELETE",
+    "/secrets/counter",
+    project,
+    &[("if-match", "\"2\"")],
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+  // If-Match compares strongly, so a weak tag never matches
+  let (status, _) = send_conditional(
+    &app,
+    "DELETE",
+    "/secrets/counter",
+    project,
+    &[("if-match", "W/\"3\"")],
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+  let (status, _) = send_conditional(
+    &app,
+    "DELETE",
+    "/secrets/counter",
+    project,
+    &[("if-match", "W/\"1\", \"3\"")],
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::NO_CONTENT);
+  let (status, _) =
+    send_conditional(&app, "GET", "/secrets/counter", project, &[], None).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+
+  // If-None-Match lets a delete through only when there is nothing to delete
+  let (status, _) = send_conditional(
+    &app,
+    "DELETE",
+    "/secrets/counter",
+    project,
+    &[("if-none-match", "*")],
+    None,
+  )
+  .await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  let (_, versions) =
+    call_in(&app, "GET", "/secrets/counter/versions", project, None).await;
+  assert_eq!(versions.as_array().unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn test_search_wildcards() {
+  let (app, _state) = create_test_app().await;
+  let project = "wildcard_project";
+  for (key, value) in [
+    (
+      "db_primary",
+      serde_json::json!({"host": "db1.internal", "port": 5432}),
+    ),
+    ("db_replica", serde_json::json!({"host": "db2.internal"})),
+    ("dbx", serde_json::json!("postgres://dbx")),
+    ("cache", serde_json::json!({"hostname": "redis.internal"})),
+  ] {
+    let (status, _) = call_in(
+      &app,
+      "PUT",
+      &format!("/secrets/{}", key),
+      project,
+      Some(serde_json::json!({ "value": value })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+  }
+
+  let search = |query: &'static str| {
+    let app = app.clone();
+    async move {
+      let (status, page) = call_in(
+        &app,
+        "POST",
+        "/search",
+        project,
+        Some(serde_json::json!({ "query": query, "fields": "keys" })),
+      )
+      .await;
+      assert_eq!(status, StatusCode::OK, "{}: {}", query, page);
+      page_keys(&page)
+        .iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+    }
+  };
+  // `_` is literal, so `dbx` is no match
+  assert_eq!(
+    search("secret_key:db_*").await,
+    vec!["db_primary", "db_replica"]
+  );
+  assert_eq!(search("secret_key:db?").await, vec!["dbx"]);
+  assert_eq!(search("postgres*").await, vec!["dbx"]);
+  assert_eq!(
+    search("host:db?.internal").await,
+    vec!["db_primary", "db_replica"]
+  );
+  assert_eq!(search("host*:redis*").await, vec!["cache"]);
+  assert_eq!(search("-secret_key:db*").await, vec!["cache"]);
+}
+
+#[tokio::test]
+async fn test_search_nested_typed_fields() {
+  let (app, _state) = create_test_app().await;
+  let project = "nested_project";
+  for (key, value) in [
+    (
+      "svc",
+      serde_json::json!({
+        "config": {"db": {"port": 5432, "host": "db.internal"}},
+        "hosts": ["a", "b"],
+        "enabled": true,
+      }),
+    ),
+    (
+      "legacy",
+      serde_json::json!({
+        "config": {"db": {"port": "5432"}},
+        "hosts": ["b", "a"],
+        "enabled": "true",
+      }),
+    ),
+  ] {
+    let (status, _) = call_in(
+      &app,
+      "PUT",
+      &format!("/secrets/{}", key),
+      project,
+      Some(serde_json::json!({ "value": value })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+  }
+
+  let search = |query: &'static str| {
+    let app = app.clone();
+    async move {
+      let (status, page) = call_in(
+        &app,
+        "POST",
+        "/search",
+        project,
+        Some(serde_json::json!({ "query": query, "fields": "keys" })),
+      )
+      .await;
+      assert_eq!(status, StatusCode::OK, "{}: {}", query, page);
+      page_keys(&page)
+        .iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+    }
+  };
+  assert_eq!(search("config.db.port:5432").await, vec!["svc"]);
+  assert_eq!(search(r#"config.db.port:"5432""#).await, vec!["legacy"]);
+  assert_eq!(search("enabled:true").await, vec!["svc"]);
+  assert_eq!(search("hosts[0]:a").await, vec!["svc"]);
+  assert_eq!(search("hosts[1]:a").await, vec!["legacy"]);
+  assert_eq!(search("config.db.host:db.*").await, vec!["svc"]);
+  assert!(search("config.db.user:x").await.is_empty());
+}
+
+#[tokio::test]
+async fn test_search_ranges() {
+  let (app, _state) = create_test_app().await;
+  let project = "range_project";
+  for (key, value, expires_at) in [
+    (
+      "low",
+      serde_json::json!({"port": 80, "db": {"port": 5432}}),
+      None,
+    ),
+    (
+      "high",
+      serde_json::json!({"port": 8080}),
+      Some("2996-11-01T00:00:00Z"),
+    ),
+    (
+      "text",
+      serde_json::json!({"port": "8080"}),
+      Some("2997-06-01T00:00:00Z"),
+    ),
+  ] {
+    let mut body = serde_json::json!({ "value": value });
+    if let Some(at) = expires_at {
+      body["expires_at"] = at.into();
+    }
+    let (status, _) = call_in(
+      &app,
+      "PUT",
+      &format!("/secrets/{}", key),
+      project,
+      Some(body),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+  }
+
+  let search = |query: &'static str| {
+    let app = app.clone();
+    async move {
+      let (status, page) = call_in(
+        &app,
+        "POST",
+        "/search",
+        project,
+        Some(serde_json::json!({ "query": query, "fields": "keys" })),
+      )
+      .await;
+      assert_eq!(status, StatusCode::OK, "{}: {}", query, page);
+      page_keys(&page)
+        .iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+    }
+  };
+  // Numbers compare with numbers only
+  assert_eq!(search("port:[1 TO 8080]").await, vec!["high", "low"]);
+  assert_eq!(search("port:{80 TO 8080]").await, vec!["high"]);
+  assert_eq!(search("port:>=1024").await, vec!["high"]);
+  assert_eq!(search(r#"port:>"1""#).await, vec!["text"]);
+  assert_eq!(search("db.port:<6000").await, vec!["low"]);
+  assert_eq!(search("expires_at:<2996-12-01").await, vec!["high"]);
+  assert_eq!(search("-expires_at:<2996-12-01").await, vec!["low", "text"]);
+  assert_eq!(search("expires_at:[* TO *]").await, vec!["high", "text"]);
+  assert_eq!(search("updated_at:>2000-01-01").await.len(), 3);
+  assert_eq!(search("secret_key:[a TO m]").await, vec!["high", "low"]);
+
+  let (status, _) = call_in(
+    &app,
+    "POST",
+    "/search",
+    project,
+    Some(serde_json::json!({ "query": "updated_at:>soon" })),
+  )
+  .await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_search_field_existence() {
+  let (app, _state) = create_test_app().await;
+  let project = "exists_project";
+  for (key, value) in [
+    (
+      "owned",
+      serde_json::json!({"rotation_owner": "ops", "env": "prod", "db": {"port": 1}}),
+    ),
+    (
+      "bare",
+      serde_json::json!({"env": null, "hosts": ["a", "b"]}),
+    ),
+    ("list", serde_json::json!(["env", "rotation_owner"])),
+    ("text", serde_json::json!("env")),
+  ] {
+    let (status, _) = call_in(
+      &app,
+      "PUT",
+      &format!("/secrets/{}", key),
+      project,
+      Some(serde_json::json!({ "value": value })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+  }
+
+  let search = |query: &'static str| {
+    let app = app.clone();
+    async move {
+      let (status, page) = call_in(
+        &app,
+        "POST",
+        "/search",
+        project,
+        Some(serde_json::json!({ "query": query, "fields": "keys" })),
+      )
+      .await;
+      assert_eq!(status, StatusCode::OK, "{}: {}", query, page);
+      page_keys(&page)
+        .iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+    }
+  };
+  // Array elements and strings are not fields
+  assert_eq!(search("_exists_:rotation_owner").await, vec!["owned"]);
+  assert_eq!(
+    search("-_exists_:rotation_owner").await,
+    vec!["bare", "list", "text"]
+  );
+  // A null value still makes a field
+  assert_eq!(search("env:*").await, vec!["bare", "owned"]);
+  assert_eq!(search("_exists_:db.port").await, vec!["owned"]);
+  assert_eq!(search("hosts[1]:*").await, vec!["bare"]);
+  assert!(search("hosts[2]:*").await.is_empty());
+  assert_eq!(search("_exists_:rot*").await, vec!["owned"]);
+}
+
+#[tokio::test]
+async fn test_search_regexes() {
+  let (app, _state) = create_test_app().await;
+  let project = "regex_project";
+  for (key, value) in [
+    (
+      "svc-billing-prod",
+      serde_json::json!({"host": "db1.internal"}),
+    ),
+    (
+      "svc-billing-dev",
+      serde_json::json!({"host": "db2.example.com"}),
+    ),
+    ("svc-2-prod", serde_json::json!("https://example.com/a")),
+  ] {
+    let (status, _) = call_in(
+      &app,
+      "PUT",
+      &format!("/secrets/{}", key),
+      project,
+      Some(serde_json::json!({ "value": value })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+  }
+
+  let search = |query: &'static str| {
+    let app = app.clone();
+    async move {
+      call_in(
+        &app,
+        "POST",
+        "/search",
+        project,
+        Some(serde_json::json!({ "query": query, "fields": "keys" })),
+      )
+      .await
+    }
+  };
+  let keys = |page: &Value| {
+    page_keys(page)
+      .iter()
+      .map(|k| k.to_string())
+      .collect::<Vec<_>>()
+  };
+  for (query, expected) in [
+    ("secret_key:/^svc-[a-z]+-prod$/", vec!["svc-billing-prod"]),
+    (
+      "/^SVC-[a-z]+-/",
+      vec!["svc-billing-dev", "svc-billing-prod"],
+    ),
+    (r"/^https:\/\//", vec!["svc-2-prod"]),
+    (r"host:/^db\d\.internal$/", vec!["svc-billing-prod"]),
+    (
+      "-host*:/example/ secret_key:/prod$/",
+      vec!["svc-2-prod", "svc-billing-prod"],
+    ),
+  ] {
+    let (status, page) = search(query).await;
+    assert_eq!(status, StatusCode::OK, "{}: {}", query, page);
+    assert_eq!(keys(&page), expected, "{}", query);
+  }
+
+  // Rejected before they run
+  for query in ["/(a+)+b/", r"/a\/", "/a{2}{3}/"] {
+    let (status, page) = search(query).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}: {}", query, page);
+  }
+}
@@ -0,0 +1,39 @@
+use keyvault::bulk::{Format, parse_document, parse_dotenv};
+
+#[test]
+fn test_parse_dotenv() {
+  let text = "\
+# settings
+export A=plain value # trailing comment
+B='single $literal \\n'
+C=\"line\\none\"
+D=\"spans
+two lines\"
+E=
+";
+  let entries = parse_dotenv(text).unwrap();
+  let get = |key: &str| {
+    entries
+      .iter()
+      .find(|(k, _)| k == key)
+      .map(|(_, v)| v.as_str())
+  };
+  assert_eq!(get("A"), Some("plain value"));
+  assert_eq!(get("B"), Some("single $literal \\n"));
+  assert_eq!(get("C"), Some("line\none"));
+  assert_eq!(get("D"), Some("spans\ntwo lines"));
+  assert_eq!(get("E"), Some(""));
+
+  let err = parse_dotenv("A=1\nnot a pair\n").unwrap_err();
+  assert!(err.starts_with("line 2:"), "{}", err);
+  let err = parse_dotenv("A=\"open\n").unwrap_err();
+  assert!(err.contains("unterminated"), "{}", err);
+}
+
+#[test]
+fn test_parse_document_rejects_duplicates() {
+  let err = parse_document(Format::Yaml, "a: 1\n---\na: 2\n").unwrap_err();
+  assert!(err.contains("Duplicate key 'a'"), "{}", err);
+  let err = parse_document(Format::Dotenv, "A=1\nA=2\n").unwrap_err();
+  assert!(err.contains("Duplicate key 'A'"), "{}", err);
+}
@@ -0,0 +1,108 @@
+use serde_json::{Value, json};
+
+use keyvault::client::{
+  ClientError, OutputFormat, Profile, env_var_name, format_entries,
+  format_value, secrets_to_env, shell_quote,
+};
+
+const PROFILES: &str = r#"
+  [default]
+  endpoint = "http://localhost:4444"
+  api_key = "kv_default"
+  project = "billing"
+
+  [prod]
+  endpoint = "https://vault.example"
+  api_key = "kv_prod"
+"#;
+
+#[test]
+fn test_profile_selection_and_env_overrides() {
+  let profile =
+    Profile::from_sources(Some(PROFILES), "default", |_| None).unwrap();
+  assert_eq!(profile.endpoint.as_deref(), Some("http://localhost:4444"));
+  assert_eq!(profile.project.as_deref(), Some("billing"));
+
+  let profile = Profile::from_sources(Some(PROFILES), "prod", |name| {
+    (name == "KEYVAULT_PROJECT").then(|| "payroll".to_string())
+  })
+  .unwrap();
+  assert_eq!(profile.api_key.as_deref(), Some("kv_prod"));
+  assert_eq!(profile.project.as_deref(), Some("payroll"));
+
+  // A missing default profile is fine when the environment has everything
+  let profile = Profile::from_sources(None, "default", |name| {
+    Some(format!("from-{}", name))
+  })
+  .unwrap();
+  assert_eq!(profile.api_key.as_deref(), Some("from-KEYVAULT_API_KEY"));
+
+  let err =
+    Profile::from_sources(Some(PROFILES), "staging", |_| None).unwrap_err();
+  assert!(matches!(err, ClientError::Config(_)));
+  assert_eq!(err.exit_code(), 1);
+}
+
+#[test]
+fn test_env_var_names() {
+  assert_eq!(env_var_name("db/password"), "DB_PASSWORD");
+  assert_eq!(env_var_name("api-key.v2"), "API_KEY_V2");
+  assert_eq!(env_var_name("2fa"), "_2FA");
+}
+
+#[test]
+fn test_output_formats() {
+  let value = json!("it's secret");
+  assert_eq!(format_value(OutputFormat::Raw, "pw", &value), "it's secret");
+  assert_eq!(
+    format_value(OutputFormat::Shell, "db/pw", &value),
+    r"export DB_PW='it'\''s secret'"
+  );
+  assert_eq!(shell_quote("$HOME `x`"), "'$HOME `x`'");
+
+  let entries: Vec<(String, Value)> = vec![
+    ("a".to_string(), json!({"port": 5432})),
+    ("b".to_string(), json!("two")),
+  ];
+  assert_eq!(
+    format_entries(OutputFormat::Raw, &entries),
+    "a\t{\"port\":5432}\nb\ttwo"
+  );
+  let object: Value =
+    serde_json::from_str(&format_entries(OutputFormat::Json, &entries))
+      .unwrap();
+  assert_eq!(object, json!({"a": {"port": 5432}, "b": "two"}));
+}
+
+#[test]
+fn test_secrets_to_env() {
+  let entries: Vec<(String, Value)> = vec![
+    (
+      "db".to_string(),
+      json!({"host": "pg", "tls": {"mode": "require"}}),
+    ),
+    ("token".to_string(), json!("abc")),
+  ];
+  assert_eq!(
+    secrets_to_env(&entries, "APP_", true).unwrap(),
+    vec![
+      ("APP_DB_HOST".to_string(), "pg".to_string()),
+      ("APP_DB_TLS_MODE".to_string(), "require".to_string()),
+      ("APP_TOKEN".to_string(), "abc".to_string()),
+    ]
+  );
+  assert_eq!(
+    secrets_to_env(&entries, "", false).unwrap()[0],
+    (
+      "DB".to_string(),
+      r#"{"host":"pg","tls":{"mode":"require"}}"#.to_string()
+    )
+  );
+
+  let entries: Vec<(String, Value)> = vec![
+    ("db".to_string(), json!({"host": "pg"})),
+    ("db/host".to_string(), json!("other")),
+  ];
+  let err = secrets_to_env(&entries, "", true).unwrap_err();
+  assert!(err.contains("DB_HOST"), "{}", err);
+}
@@ -1,316 +1,688 @@
-use keyvault::lucene_parser::query_to_sql;
-
-macro_rules! assert_sql_eq {
-  ($raw:expr, $expected:expr) => {{
-    match query_to_sql($raw) {
-      Ok(sql) => {
-        use keyvault::lucene_parser::{QueryParser, Rule};
-        use pest::Parser;
-        use pest_ascii_tree::print_ascii_tree;
-
-        // On success, print the parse tree
-        match QueryParser::parse(Rule::expression, $raw) {
-          Ok(pairs) => print_ascii_tree(Ok(pairs)),
-          Err(e) => eprintln!("⚠️ Could not parse for tree: {e}"),
-        }
-        assert_eq!(sql, $expected, "Query: '{}'", $raw);
-      }
-      Err(e) => {
-        eprintln!("❌ query_to_sql failed for query '{}': {}", $raw, e);
-
-        // -------- headless trace via pest_debugger ----------
-        use pest_debugger::DebuggerContext;
-
-        let mut ctx = DebuggerContext::default();
-
-        let grammar = include_str!("../src/grammar.pest"); // point to your .pest
-        let _ = ctx.load_grammar_direct("lucene", grammar);
-        let _ = ctx.load_input_direct($raw.to_string());
-
-        // ⚠️  DO NOT set breakpoints; just stream the events
-        let (tx, rx) = std::sync::mpsc::sync_channel(1_000);
-        if ctx.run("expression", tx).is_ok() {
-            for event in rx {      // iter ends when parse finishes
-                eprintln!("{event:?}");
-            }
-        }
-        // -----------------------------------------------------
-
-        panic!("query_to_sql failed for query '{}'", $raw);
-      }
-    }
-  }};
-  ($raw:expr, $expected:expr,) => {
-    assert_sql_eq!($raw, $expected)
+use chrono::{DateTime, Utc};
+use keyvault::lucene_filter::{Candidate, Filter, query_to_filter};
+use keyvault::lucene_parser::{MAX_REGEX_LEN, QueryParseError};
+use serde_json::{Value, json};
+
+const UPDATED_AT: &str = "2026-01-15T00:00:00Z";
+
+fn time(raw: &str) -> DateTime<Utc> {
+  DateTime::parse_from_rfc3339(raw).unwrap().into()
+}
+
+fn filter(raw: &str) -> Filter {
+  match query_to_filter(raw) {
+    Ok(filter) => filter,
+    Err(e) => panic!("query_to_filter failed for query '{}': {}", raw, e),
+  }
+}
+
+/// The error for `raw`, which must not compile.
+fn error(raw: &str) -> QueryParseError {
+  match query_to_filter(raw) {
+    Ok(_) => panic!("query '{}' compiled", raw),
+    Err(e) => e,
+  }
+}
+
+/// Whether `raw` matches the secret `key` holding `value`.
+fn matches(raw: &str, key: &str, value: Value) -> bool {
+  let candidate = Candidate {
+    key,
+    value: Some(&value),
+    updated_at: time(UPDATED_AT),
+    expires_at: None,
   };
+  filter(raw).matches(&candidate).expect(raw)
 }
 
+/// Whether `raw` matches a secret with these times.
+fn matches_times(
+  raw: &str,
+  updated_at: &str,
+  expires_at: Option<&str>,
+) -> bool {
+  let candidate = Candidate {
+    key: "k",
+    value: Some(&Value::Null),
+    updated_at: time(updated_at),
+    expires_at: expires_at.map(time),
+  };
+  filter(raw).matches(&candidate).expect(raw)
+}
+
+/// What `raw` says of the secret `key` before its value is opened.
+fn without_value(raw: &str, key: &str) -> Option<bool> {
+  let candidate = Candidate {
+    key,
+    value: None,
+    updated_at: time(UPDATED_AT),
+    expires_at: None,
+  };
+  filter(raw).matches(&candidate)
+}
 
 #[test]
 fn test_empty_query() {
-  assert_sql_eq!("", "TRUE");
-  assert_sql_eq!("   ", "TRUE");
+  for raw in ["", "   "] {
+    assert!(matches(raw, "k", json!(null)));
+    assert_eq!(without_value(raw, "k"), Some(true));
+  }
 }
 
 #[test]
 fn test_simple_key_value_includes() {
   let raw = "something:wild";
-  // Top-level generic k:v uses combo query, wrapped in parens
-  assert_sql_eq!(
+  // The field of an object value
+  assert!(matches(raw, "k", json!({"something": "wild"})));
+  assert!(matches(
     raw,
-    "(secret_key ILIKE '%something%' AND secret_value::text ILIKE '%wild%' OR \
-     secret_value @> '{\"something\": \"wild\"}')"
-  );
+    "k",
+    json!({"Something": 1, "something": "wild"})
+  ));
+  // Or the key and the value's text
+  assert!(matches(raw, "my_something", json!("wilderness")));
+  assert!(!matches(raw, "k", json!({"something": "wilder"})));
+  assert!(!matches(raw, "something", json!("tame")));
 }
 
 #[test]
 fn test_simple_key_value_excludes() {
   let raw = "-something:wild";
-  // NOT wraps the operand in parens
-  assert_sql_eq!(
-    raw,
-    "NOT (secret_key ILIKE '%something%' AND secret_value::text ILIKE \
-     '%wild%' OR secret_value @> '{\"something\": \"wild\"}')"
-  );
+  assert!(!matches(raw, "k", json!({"something": "wild"})));
+  assert!(!matches(raw, "my_something", json!("wilderness")));
+  assert!(matches(raw, "k", json!({"something": "tame"})));
 }
 
 #[test]
 fn test_schema_field_key_includes() {
   let raw = "secret_key:test_value";
-  // Top-level schema field (no combo query needed)
-  assert_sql_eq!(
-    raw,
-    "secret_key ILIKE '%test\\_value%'" // Value escaped for LIKE
-  );
+  assert!(matches(raw, "my_TEST_value_1", json!(null)));
+  // `_` is not a wildcard
+  assert!(!matches(raw, "testXvalue", json!(null)));
+  // The value is not searched
+  assert!(!matches(raw, "k", json!("test_value")));
 }
 
 #[test]
 fn test_schema_field_value_includes() {
-  // Test multi-word value after special field: first word applies to the field,
-  // subsequent words become ANDed keyword searches.
+  // The first word applies to the field, the next is a term of its own
   let raw = "secret_value:some data";
-  assert_sql_eq!(
-    raw,
-    // Expected SQL based on desired logic:
-    "secret_value::text ILIKE '%some%' AND (secret_key ILIKE '%data%' OR \
-     secret_value::text ILIKE '%data%')"
-  );
+  assert!(matches(raw, "data", json!("something")));
+  assert!(matches(raw, "k", json!({"some": "data"})));
+  assert!(!matches(raw, "k", json!("something")));
+  assert!(!matches(raw, "some_data", json!(null)));
 }
 
 #[test]
 fn test_schema_field_key_excludes() {
   let raw = "-secret_key:test_initial_value";
-  // NOT wraps the operand in parens
-  assert_sql_eq!(
-    raw,
-    "NOT secret_key ILIKE '%test\\_initial\\_value%'" // Escaped value
-  );
+  assert!(!matches(raw, "test_initial_value", json!(null)));
+  assert!(matches(raw, "test_value", json!("test_initial_value")));
 }
 
 #[test]
 fn test_implicit_and() {
-  let raw = "foo:bar baz:qux"; // Implicit AND
-  // Nested generic k:v use simple @>
-  assert_sql_eq!(
-    raw,
-    "(secret_key ILIKE '%foo%' AND secret_value::text ILIKE '%bar%' OR \
-     secret_value @> '{\"foo\": \"bar\"}') AND (secret_key ILIKE '%baz%' AND \
-     secret_value::text ILIKE '%qux%' OR secret_value @> '{\"baz\": \"qux\"}')"
-  );
+  let raw = "foo:bar baz:qux";
+  assert!(matches(raw, "k", json!({"foo": "bar", "baz": "qux"})));
+  assert!(!matches(raw, "k", json!({"foo": "bar"})));
 }
 
 #[test]
 fn test_explicit_and() {
   let raw = "foo:bar AND baz:qux";
-  assert_sql_eq!(
-    raw,
-    "(secret_key ILIKE '%foo%' AND secret_value::text ILIKE '%bar%' OR \
-     secret_value @> '{\"foo\": \"bar\"}') AND (secret_key ILIKE '%baz%' AND \
-     secret_value::text ILIKE '%qux%' OR secret_value @> '{\"baz\": \"qux\"}')"
-  );
+  assert!(matches(raw, "k", json!({"foo": "bar", "baz": "qux"})));
+  assert!(matches(raw, "foo_baz", json!("bar qux")));
+  assert!(!matches(raw, "k", json!({"baz": "qux"})));
 }
 
 #[test]
 fn test_multiple_and() {
   let raw = "foo AND bar baz:qux";
-  // Nested generic k:v use simple @>
-  assert_sql_eq!(
-    raw,
-    "(secret_key ILIKE '%foo%' OR secret_value::text ILIKE '%foo%') AND \
-     (secret_key ILIKE '%bar%' OR secret_value::text ILIKE '%bar%') AND \
-     (secret_key ILIKE '%baz%' AND secret_value::text ILIKE '%qux%' OR \
-     secret_value @> '{\"baz\": \"qux\"}')"
-  );
+  assert!(matches(raw, "foo", json!({"bar": 1, "baz": "qux"})));
+  assert!(!matches(raw, "foo", json!({"baz": "qux"})));
+  assert!(!matches(raw, "foo_bar", json!({"baz": "quux"})));
 }
 
 #[test]
 fn test_multiple_or() {
   let raw = "foo:bar OR baz:qux";
-  // OR group is top-level here
-  // Nested generic k:v use simple @>
-  assert_sql_eq!(
-    raw,
-    "(secret_key ILIKE '%foo%' AND secret_value::text ILIKE '%bar%' OR \
-     secret_value @> '{\"foo\": \"bar\"}') OR (secret_key ILIKE '%baz%' AND \
-     secret_value::text ILIKE '%qux%' OR secret_value @> '{\"baz\": \"qux\"}')"
-  );
+  assert!(matches(raw, "k", json!({"foo": "bar"})));
+  assert!(matches(raw, "k", json!({"baz": "qux"})));
+  assert!(!matches(raw, "k", json!({"foo": "qux", "baz": "bar"})));
 }
 
 #[test]
 fn test_single_term_includes() {
   let raw = "term";
-  // Term search is wrapped in parens
-  assert_sql_eq!(
-    raw,
-    "(secret_key ILIKE '%term%' OR secret_value::text ILIKE '%term%')"
-  );
+  assert!(matches(raw, "my_TERM", json!(null)));
+  // Anywhere in the value's JSON text, names included
+  assert!(matches(raw, "k", json!("long-term")));
+  assert!(matches(raw, "k", json!({"terms": 1})));
+  assert!(!matches(raw, "k", json!("other")));
 }
 
 #[test]
 fn test_single_term_excludes() {
   let raw = "-term";
-  // NOT wraps the term search parens
-  assert_sql_eq!(
-    raw,
-    "NOT (secret_key ILIKE '%term%' OR secret_value::text ILIKE '%term%')"
-  );
+  assert!(!matches(raw, "term", json!(null)));
+  assert!(!matches(raw, "k", json!(["term"])));
+  assert!(matches(raw, "k", json!("other")));
 }
 
 #[test]
 fn test_grouped_and_or() {
   let raw = "(foo:bar OR baz:qux) AND something:wild";
-  // The OR group gets wrapped. `something:wild` is nested within AND, so simple @> is used.
-  assert_sql_eq!(
+  assert!(matches(
     raw,
-    "((secret_key ILIKE '%foo%' AND secret_value::text ILIKE '%bar%' OR \
-     secret_value @> '{\"foo\": \"bar\"}') OR (secret_key ILIKE '%baz%' AND \
-     secret_value::text ILIKE '%qux%' OR secret_value @> '{\"baz\": \
-     \"qux\"}')) AND (secret_key ILIKE '%something%' AND secret_value::text \
-     ILIKE '%wild%' OR secret_value @> '{\"something\": \"wild\"}')"
-  );
+    "k",
+    json!({"baz": "qux", "something": "wild"})
+  ));
+  assert!(!matches(raw, "k", json!({"baz": "qux"})));
+  assert!(!matches(raw, "k", json!({"something": "wild"})));
 }
 
 #[test]
 fn test_quoted_phrase() {
   let raw = "\"hello world\"";
-  // Phrase search wrapped in parens
-  assert_sql_eq!(
-    raw,
-    "(secret_key ILIKE '%hello world%' OR secret_value::text ILIKE '%hello \
-     world%')"
-  );
+  assert!(matches(raw, "k", json!("say hello world")));
+  assert!(matches(raw, "Hello World", json!(null)));
+  assert!(!matches(raw, "k", json!("hello, world")));
 }
 
 #[test]
 fn test_key_value_with_quoted_spaces() {
   let raw = "\"first name\":\"last name\"";
-  // Top-level generic k:v with quotes -> combo query, correctly parsed key/value
-  assert_sql_eq!(
-    raw,
-    // Key/Value escaped for ILIKE, Key/Value escaped for JSON
-    "(secret_key ILIKE '%first name%' AND secret_value::text ILIKE '%last \
-     name%' OR secret_value @> '{\"first name\": \"last name\"}')"
-  );
+  assert!(matches(raw, "k", json!({"first name": "last name"})));
+  assert!(!matches(raw, "k", json!({"first": "last name"})));
 }
 
 #[test]
 fn test_key_value_with_escaped_quotes_in_value() {
+  // The value is unescaped once
   let raw = r#"message:"{\"ok\": true}""#;
-  // Top-level generic k:v -> combo query, value needs correct escaping for JSON and LIKE
-  assert_sql_eq!(
-    raw,
-    // LIKE needs single-escaped \, JSON needs double-escaped \"
-    r#"(secret_key ILIKE '%message%' AND secret_value::text ILIKE '%{"ok": true}%' OR secret_value @> '{"message": "{\"ok\": true}"}')"#
-  );
+  assert!(matches(raw, "k", json!({"message": r#"{"ok": true}"#})));
+  assert!(!matches(raw, "k", json!({"message": {"ok": true}})));
 }
 
 #[test]
 fn test_nested_grouping() {
   let raw = "(a:b OR (c:d AND e:f))";
-  // Nested k:v use simple @>
-  // Inner OR group gets wrapped. Inner AND group doesn't need wrapping by default.
-  assert_sql_eq!(
-    raw,
-    "((secret_key ILIKE '%a%' AND secret_value::text ILIKE '%b%' OR \
-     secret_value @> '{\"a\": \"b\"}') OR ((secret_key ILIKE '%c%' AND \
-     secret_value::text ILIKE '%d%' OR secret_value @> '{\"c\": \"d\"}') AND \
-     (secret_key ILIKE '%e%' AND secret_value::text ILIKE '%f%' OR \
-     secret_value @> '{\"e\": \"f\"}')))"
-  );
+  assert!(matches(raw, "k", json!({"a": "b"})));
+  assert!(matches(raw, "k", json!({"c": "d", "e": "f"})));
+  assert!(!matches(raw, "k", json!({"c": "d"})));
 }
 
 #[test]
 fn test_double_nested_grouping_with_or() {
   let raw =
     "(foo:bar OR baz:qux) AND (alpha:beta OR gamma:delta) OR (i:j AND k:l)";
-  let expected_sql = concat!(
-    "((secret_key ILIKE '%foo%' AND secret_value::text ILIKE '%bar%' OR \
-     secret_value @> '{\"foo\": \"bar\"}')",
-    " OR ",
-    "(secret_key ILIKE '%baz%' AND secret_value::text ILIKE '%qux%' OR \
-     secret_value @> '{\"baz\": \"qux\"}'))",
-    " AND ",
-    "((secret_key ILIKE '%alpha%' AND secret_value::text ILIKE '%beta%' OR \
-     secret_value @> '{\"alpha\": \"beta\"}')",
-    " OR ",
-    "(secret_key ILIKE '%gamma%' AND secret_value::text ILIKE '%delta%' OR \
-     secret_value @> '{\"gamma\": \"delta\"}'))",
-    " OR ",
-    "((secret_key ILIKE '%i%' AND secret_value::text ILIKE '%j%' OR \
-     secret_value @> '{\"i\": \"j\"}')",
-    " AND ",
-    "(secret_key ILIKE '%k%' AND secret_value::text ILIKE '%l%' OR \
-     secret_value @> '{\"k\": \"l\"}'))"
-  );
-
-  assert_sql_eq!(raw, expected_sql);
+  assert!(matches(raw, "x", json!({"foo": "bar", "gamma": "delta"})));
+  assert!(matches(raw, "x", json!({"i": "j", "k": "l"})));
+  assert!(!matches(raw, "x", json!({"foo": "bar", "i": "j"})));
 }
 
 #[test]
 fn test_not_with_or() {
   let raw = "-(a:b OR c:d)";
-  // NOT wraps the generated OR group's parentheses
-  assert_sql_eq!(
-    raw,
-    "NOT ((secret_key ILIKE '%a%' AND secret_value::text ILIKE '%b%' OR \
-     secret_value @> '{\"a\": \"b\"}') OR (secret_key ILIKE '%c%' AND \
-     secret_value::text ILIKE '%d%' OR secret_value @> '{\"c\": \"d\"}'))"
-  );
+  assert!(!matches(raw, "k", json!({"c": "d"})));
+  assert!(matches(raw, "k", json!({"a": "d", "c": "b"})));
 }
 
 #[test]
 fn test_not_with_and() {
   let raw = "-(a:b AND c:d)";
-  // NOT wraps the AND group
-  assert_sql_eq!(
-    raw,
-    "NOT ((secret_key ILIKE '%a%' AND secret_value::text ILIKE '%b%' OR \
-     secret_value @> '{\"a\": \"b\"}') AND (secret_key ILIKE '%c%' AND \
-     secret_value::text ILIKE '%d%' OR secret_value @> '{\"c\": \"d\"}'))"
-  );
+  assert!(!matches(raw, "k", json!({"a": "b", "c": "d"})));
+  assert!(matches(raw, "k", json!({"a": "b"})));
 }
 
 #[test]
 fn test_mixed_not_and_or() {
   let raw = "-a:b AND (c:d OR -e:f)";
-  // NOT applied to a:b, nested NOT applied to e:f, OR group wrapped
-  assert_sql_eq!(
-    raw,
-    "NOT (secret_key ILIKE '%a%' AND secret_value::text ILIKE '%b%' OR \
-     secret_value @> '{\"a\": \"b\"}') AND ((secret_key ILIKE '%c%' AND \
-     secret_value::text ILIKE '%d%' OR secret_value @> '{\"c\": \"d\"}') OR \
-     NOT (secret_key ILIKE '%e%' AND secret_value::text ILIKE '%f%' OR \
-     secret_value @> '{\"e\": \"f\"}'))"
-  );
+  assert!(matches(raw, "k", json!({"c": "d", "e": "f"})));
+  assert!(matches(raw, "k", json!({})));
+  assert!(!matches(raw, "k", json!({"a": "b"})));
+  assert!(!matches(raw, "k", json!({"e": "f"})));
 }
 
 #[test]
 fn test_invalid_syntax() {
   // Test cases that should fail parsing
-  assert!(query_to_sql("a:").is_err());
-  assert!(query_to_sql(":b").is_err());
-  assert!(query_to_sql("(").is_err());
-  assert!(query_to_sql("a AND").is_err());
-  assert!(query_to_sql("\"unterminated").is_err());
-  assert!(query_to_sql("a:b OR AND c:d").is_err()); // adjacent operators
+  assert!(query_to_filter("a:").is_err());
+  assert!(query_to_filter(":b").is_err());
+  assert!(query_to_filter("(").is_err());
+  assert!(query_to_filter("a AND").is_err());
+  assert!(query_to_filter("\"unterminated").is_err());
+  assert!(query_to_filter("a:b OR AND c:d").is_err()); // adjacent operators
+}
+
+// ---------- adversarial input is only ever data ----------
+
+#[test]
+fn test_adversarial_term() {
+  // Apostrophes and unicode are valid term characters
+  assert!(matches("O'Brien'--", "o'brien'--x", json!(null)));
+  assert!(matches("pässwörd_日本", "k", json!({"pässwörd_日本": 1})));
+  assert!(!matches("pässwörd_日本", "pässwörd-日本", json!(null)));
+  // Anything outside the term alphabet is a syntax error
+  assert!(query_to_filter("a';DROP").is_err());
+  assert!(query_to_filter("a\\b").is_err());
+}
+
+#[test]
+fn test_adversarial_phrase() {
+  assert!(matches(r#""a' OR 1=1 --""#, "a' or 1=1 --", json!(null)));
+  assert!(!matches(r#""a' OR 1=1 --""#, "a", json!(1)));
+  // Escaped quote and backslash are unescaped once; `%` and `_` are literal
+  let raw = r#""it\"s 100% \\ done_'""#;
+  assert!(matches(raw, r#"it"s 100% \ done_'"#, json!(null)));
+  assert!(!matches(raw, r#"it"s 1000 \ done_'"#, json!(null)));
+  assert!(!matches(raw, r#"it"s 100% \ doneX'"#, json!(null)));
+  let raw = "\"'; DELETE FROM secrets; -- ünïcödé 🔑\"";
+  assert!(matches(
+    raw,
+    "k",
+    json!("'; delete from secrets; -- ÜNÏCÖDÉ 🔑")
+  ));
+}
+
+#[test]
+fn test_adversarial_key_value() {
+  assert!(matches(
+    r#""k'ey":"v' OR '1'='1""#,
+    "k",
+    json!({"k'ey": "v' OR '1'='1"})
+  ));
+  // Quotes and backslashes inside the value stay data
+  assert!(matches(
+    r#""a\\\"b":"}', '{\"x\": 1}""#,
+    "k",
+    json!({r#"a\"b"#: r#"}', '{"x": 1}"#})
+  ));
+  assert!(matches("secret_key:'ключ'", "'КЛЮЧ'", json!(null)));
+  assert!(!matches("secret_key:'ключ'", "ключ", json!(null)));
+  // Backslashes stay literal
+  assert!(matches(r#"secret_value:"\\'; --""#, "k", json!(r"\'; --")));
+  assert!(!matches(r#"secret_value:"\\'; --""#, "k", json!("'; --")));
+}
+
+#[test]
+fn test_wildcard_term() {
+  // A wildcard term must match the whole key or value
+  assert!(matches("db*", "DB_host", json!(null)));
+  assert!(matches("db*", "k", json!("dbname")));
+  assert!(!matches("db*", "my_db", json!("a db")));
+  assert!(!matches("db*", "k", json!({"db": 1})));
+  assert!(matches("ho?t_*", "host_1", json!(null)));
+  assert!(!matches("ho?t_*", "hoost_1", json!(null)));
+  assert!(!matches("ho?t_*", "host", json!(null)));
+  // Quoted, `*` and `?` are literal
+  assert!(matches(r#""db*""#, "x_db*", json!(null)));
+  assert!(!matches(r#""db*""#, "db_host", json!(null)));
+}
+
+#[test]
+fn test_wildcard_schema_fields() {
+  assert!(matches("secret_key:db_*", "db_main", json!(null)));
+  assert!(!matches("secret_key:db_*", "dbxmain", json!(null)));
+  assert!(!matches("-secret_key:*_tmp", "cache_TMP", json!(null)));
+  assert!(matches("-secret_key:*_tmp", "cache_tmp2", json!(null)));
+  assert!(matches(
+    "secret_value:postgres*",
+    "k",
+    json!("postgres://db")
+  ));
+  assert!(!matches(
+    "secret_value:postgres*",
+    "k",
+    json!({"postgres": 1})
+  ));
+  assert!(!matches("secret_value:postgres*", "postgres", json!(null)));
+  assert!(matches(r#"secret_key:"db_*""#, "x_db_*", json!(null)));
+  assert!(!matches(r#"secret_key:"db_*""#, "db_main", json!(null)));
+}
+
+#[test]
+fn test_wildcard_field_and_value() {
+  let raw = "env:prod*";
+  assert!(matches(raw, "k", json!({"env": "production"})));
+  assert!(matches(raw, "k", json!({"env": "PROD"})));
+  assert!(matches(raw, "env", json!("prod-1")));
+  assert!(!matches(raw, "k", json!({"env": "staging", "x": "prod"})));
+  let raw = "db_*:primary";
+  assert!(matches(raw, "k", json!({"DB_main": "primary"})));
+  assert!(matches(raw, "db_main", json!("the primary")));
+  assert!(!matches(raw, "k", json!({"db_main": "primary-2"})));
+  assert!(!matches(raw, "k", json!({"dbmain": "primary"})));
+}
+
+#[test]
+fn test_typed_field_values() {
+  assert!(matches("port:5432", "k", json!({"port": 5432})));
+  assert!(matches("port:5432", "k", json!({"port": 5432.0})));
+  assert!(!matches("port:5432", "k", json!({"port": "5432"})));
+  let raw = "enabled:true ratio:-0.5";
+  assert!(matches(raw, "k", json!({"enabled": true, "ratio": -0.5})));
+  assert!(!matches(
+    raw,
+    "k",
+    json!({"enabled": "true", "ratio": -0.5})
+  ));
+  // Quoted values and things that are not JSON numbers stay strings
+  for (raw, value) in [
+    (r#"port:"5432""#, "5432"),
+    ("zip:01234", "01234"),
+    ("v:True", "True"),
+  ] {
+    let field = raw.split(':').next().unwrap();
+    assert!(matches(raw, "k", json!({ field: value })), "{}", raw);
+  }
+  assert!(!matches("zip:01234", "k", json!({"zip": 1234})));
+  assert!(!matches("v:True", "k", json!({"v": true})));
+}
+
+#[test]
+fn test_nested_field_paths() {
+  let raw = "config.db.port:5432";
+  assert!(matches(raw, "k", json!({"config": {"db": {"port": 5432}}})));
+  assert!(!matches(raw, "k", json!({"config": {"port": 5432}})));
+  assert!(matches("hosts[0]:a", "k", json!({"hosts": ["a", "b"]})));
+  assert!(!matches("hosts[0]:b", "k", json!({"hosts": ["a", "b"]})));
+  let raw = "servers[1][2].tls.enabled:false";
+  let value = json!({"servers": [[], [0, 1, {"tls": {"enabled": false}}]]});
+  assert!(matches(raw, "k", value));
+  // Field names are data, whatever they hold
+  assert!(matches("a.b'c:x", "k", json!({"a": {"b'c": "x"}})));
+}
+
+#[test]
+fn test_literal_dotted_field_names() {
+  // Quoted names and names with empty segments are top-level fields
+  assert!(matches(r#""a.b":x"#, "k", json!({"a.b": "x"})));
+  assert!(!matches(r#""a.b":x"#, "k", json!({"a": {"b": "x"}})));
+  assert!(matches(".env:x", "k", json!({".env": "x"})));
+  // Paths name nested fields under `secret_key` and `secret_value` too
+  assert!(matches(
+    "secret_value.x:1",
+    "k",
+    json!({"secret_value": {"x": 1}})
+  ));
+  assert!(!matches("secret_value.x:1", "k", json!({"x": 1})));
+  let raw = "db.host:*.internal";
+  assert!(matches(raw, "k", json!({"db": {"host": "a.INTERNAL"}})));
+  assert!(!matches(raw, "k", json!({"db": {"host": "a.internal.io"}})));
+}
+
+#[test]
+fn test_field_ranges() {
+  let raw = "port:[1000 TO 2000]";
+  for port in [1000, 1500, 2000] {
+    assert!(matches(raw, "k", json!({ "port": port })), "{}", port);
+  }
+  assert!(!matches(raw, "k", json!({"port": 999})));
+  assert!(!matches(raw, "k", json!({"port": 2000.5})));
+  // Numbers compare with numbers only
+  assert!(!matches(raw, "k", json!({"port": "1500"})));
+  let raw = "config.db.port:{1000 TO 2000]";
+  assert!(!matches(
+    raw,
+    "k",
+    json!({"config": {"db": {"port": 1000}}})
+  ));
+  assert!(matches(raw, "k", json!({"config": {"db": {"port": 2000}}})));
+  // Open ends and quoted bounds, which compare as strings
+  let raw = r#"name:{* TO "m"}"#;
+  assert!(matches(raw, "k", json!({"name": "alice"})));
+  assert!(!matches(raw, "k", json!({"name": "m"})));
+  assert!(!matches(raw, "k", json!({"name": 1})));
+  // Fully open, anything there
+  let raw = "tags[0]:[* TO *]";
+  assert!(matches(raw, "k", json!({"tags": [null]})));
+  assert!(!matches(raw, "k", json!({"tags": []})));
+  assert!(!matches(raw, "k", json!({})));
+  // Array fields match when an element is in range
+  assert!(matches("ports:[1 TO 9]", "k", json!({"ports": [20, 5]})));
+  assert!(matches("secret_value:[1 TO 9]", "k", json!(5)));
+  assert!(!matches("secret_value:[1 TO 9]", "k", json!(10)));
+}
+
+#[test]
+fn test_field_comparisons() {
+  for (raw, inside, outside) in [
+    ("port:>1024", 1025, 1024),
+    ("port:>=1024", 1024, 1023),
+    ("port:<1024", 1023, 1024),
+    ("port:<=1024", 1024, 1025),
+  ] {
+    assert!(matches(raw, "k", json!({ "port": inside })), "{}", raw);
+    assert!(!matches(raw, "k", json!({ "port": outside })), "{}", raw);
+  }
+  assert!(matches("ratio:<=1.5", "k", json!({"ratio": 1})));
+  assert!(!matches("ratio:<=1.5", "k", json!({"ratio": 1.6})));
+  let raw = "expires:<2026-12-01";
+  assert!(matches(raw, "k", json!({"expires": "2026-11-30"})));
+  assert!(!matches(raw, "k", json!({"expires": "2026-12-01"})));
+  // Combines like any other clause
+  let raw = "-port:<1024 OR env:prod";
+  assert!(matches(raw, "k", json!({"port": 8080})));
+  assert!(matches(raw, "k", json!({"port": 80, "env": "prod"})));
+  assert!(!matches(raw, "k", json!({"port": 80})));
+}
+
+#[test]
+fn test_metadata_ranges() {
+  let raw = r#"updated_at:[2026-01-01 TO "2026-02-01T12:00:00+02:00"}"#;
+  assert!(matches_times(raw, "2026-01-01T00:00:00Z", None));
+  assert!(matches_times(raw, "2026-02-01T09:59:59Z", None));
+  assert!(!matches_times(raw, "2026-02-01T10:00:00Z", None));
+  assert!(!matches_times(raw, "2025-12-31T23:59:59Z", None));
+  let raw = "expires_at:<2026-12-01";
+  assert!(matches_times(raw, UPDATED_AT, Some("2026-11-30T00:00:00Z")));
+  assert!(!matches_times(
+    raw,
+    UPDATED_AT,
+    Some("2026-12-01T00:00:00Z")
+  ));
+  // Secrets that never expire are in no range
+  assert!(!matches_times(raw, UPDATED_AT, None));
+  assert!(matches_times(
+    "expires_at:[* TO *]",
+    UPDATED_AT,
+    Some(UPDATED_AT)
+  ));
+  assert!(!matches_times("expires_at:[* TO *]", UPDATED_AT, None));
+  let raw = "secret_key:[a TO m}";
+  assert!(matches(raw, "a", json!(null)));
+  assert!(matches(raw, "lima", json!(null)));
+  assert!(!matches(raw, "m", json!(null)));
+}
+
+#[test]
+fn test_invalid_ranges() {
+  for raw in [
+    "port:[1 TO]",
+    "port:[1 2]",
+    "port:> 5",
+    "port:=>5",
+    ">5",
+    "port:[1 TO 2",
+  ] {
+    assert!(
+      matches!(error(raw), QueryParseError::SyntaxError(_)),
+      "{}",
+      raw
+    );
+  }
+  // Bad times and wildcard fields point at the offending part
+  let err = error("updated_at:>yesterday").to_string();
+  assert!(err.contains("'yesterday' is not a date"), "{}", err);
+  assert!(err.contains("1:12"), "{}", err);
+  let err = error("a b*:[1 TO 2]").to_string();
+  assert!(err.contains("without wildcards"), "{}", err);
+  assert!(err.contains("1:3"), "{}", err);
+}
+
+#[test]
+fn test_field_exists() {
+  for raw in ["_exists_:rotation_owner", "rotation_owner:*"] {
+    assert!(
+      matches(raw, "k", json!({"rotation_owner": null})),
+      "{}",
+      raw
+    );
+    assert!(!matches(raw, "k", json!({"owner": 1})), "{}", raw);
+    assert!(!matches(raw, "rotation_owner", json!("x")), "{}", raw);
+  }
+  // Quoted names are top-level fields
+  assert!(!matches(r#"-_exists_:"a.b""#, "k", json!({"a.b": 1})));
+  assert!(matches(r#"-_exists_:"a.b""#, "k", json!({"a": {"b": 1}})));
+  let raw = "_exists_:config.db.port";
+  assert!(matches(raw, "k", json!({"config": {"db": {"port": 1}}})));
+  assert!(!matches(raw, "k", json!({"config": {"db": {}}})));
+  assert!(matches("-hosts[1]:*", "k", json!({"hosts": ["a"]})));
+  assert!(!matches("-hosts[1]:*", "k", json!({"hosts": ["a", "b"]})));
+  assert!(matches(
+    "_exists_:rotation_*",
+    "k",
+    json!({"Rotation_by": 1})
+  ));
+  assert!(!matches("_exists_:rotation_*", "k", json!({"rotation": 1})));
+  // A quoted `*` is a value, and the columns always exist
+  assert!(matches(r#"env:"*""#, "k", json!({"env": "*"})));
+  assert!(!matches(r#"env:"*""#, "k", json!({"env": "x"})));
+  assert!(matches("secret_key:*", "anything", json!(null)));
+  // `_exists_` needs a field; elsewhere it is an ordinary name
+  assert!(query_to_filter("_exists_:").is_err());
+  assert!(matches("_exists_x:1", "k", json!({"_exists_x": 1})));
+  assert!(!matches("_exists_x:1", "k", json!({"x": 1})));
+}
+
+#[test]
+fn test_regex_terms() {
+  let raw = "/^svc-[a-z]+-prod$/";
+  assert!(matches(raw, "SVC-api-prod", json!(null)));
+  assert!(matches(raw, "k", json!("svc-db-prod")));
+  assert!(!matches(raw, "svc-api-prod-2", json!({"k": "svc-db-prod"})));
+  // `\/` reads as a slash
+  let raw = r"-/^https?:\/\/(www\.)?example/ db";
+  assert!(matches(raw, "db", json!("ftp://example.com")));
+  assert!(!matches(raw, "db", json!("https://www.example.com")));
+  assert!(!matches(raw, "k", json!("ftp://example.com")));
+}
+
+#[test]
+fn test_regex_fields() {
+  let raw = "secret_key:/^svc-[a-z]+-prod$/";
+  assert!(matches(raw, "svc-api-prod", json!(null)));
+  assert!(!matches(raw, "k", json!("svc-api-prod")));
+  // Numbers are matched as their text
+  let raw = r"secret_value:/\d{4}/";
+  assert!(matches(raw, "k", json!("pin 1234")));
+  assert!(matches(raw, "k", json!(12345)));
+  assert!(!matches(raw, "k", json!(123)));
+  let raw = "config.db.host:/^db[0-9]\\./";
+  assert!(matches(
+    raw,
+    "k",
+    json!({"config": {"db": {"host": "db1.io"}}})
+  ));
+  assert!(!matches(
+    raw,
+    "k",
+    json!({"config": {"db": {"host": "dbx.io"}}})
+  ));
+  let raw = "host*:/internal$/";
+  assert!(matches(raw, "k", json!({"hostname": "a.internal"})));
+  assert!(!matches(raw, "k", json!({"name": "a.internal"})));
+}
+
+/// The error for `raw`, which must point at `column` of the query.
+fn regex_error(raw: &str, column: usize) -> String {
+  let err = error(raw);
+  assert!(matches!(err, QueryParseError::SyntaxError(_)), "{}", raw);
+  let err = err.to_string();
+  assert!(err.contains(&format!("1:{}", column)), "{}: {}", raw, err);
+  err
+}
+
+#[test]
+fn test_invalid_regexes() {
+  assert!(regex_error("a:/[a-z/", 4).contains("unclosed character class"));
+  assert!(regex_error("/(ab/", 2).contains("unclosed group"));
+  assert!(regex_error("/a)/", 3).contains("unopened group"));
+  assert!(regex_error("/*a/", 2).contains("Invalid regular expression"));
+  // Patterns that can run away
+  assert!(regex_error("/^(a+)+$/", 3).contains("exponential"));
+  assert!(regex_error("k:/(\\w+\\.)*com/", 4).contains("exponential"));
+  assert!(regex_error("/x(a|b*){2}/", 3).contains("exponential"));
+  assert!(regex_error("/a{1,1000}/", 3).contains("at most 100"));
+  // Counts multiply when nested; the error marks the outer repetition
+  let err = regex_error("/((a{1,100}){1,100}){1,100}/", 2);
+  assert!(err.contains("multiply to at most 1000"), "{}", err);
+  let outer = "((a{1,100}){1,100}){1,100}";
+  let marker = format!("^{}^", "-".repeat(outer.len() - 2));
+  assert!(err.contains(&marker), "{}", err);
+  assert!(
+    regex_error("/x(a{1,10}){1,10}(b{1,60}){1,20}/", 18).contains("multiply")
+  );
+  assert!(query_to_filter("/(a{1,10}){1,100}b{1,100}/").is_ok());
+  assert!(regex_error("/a{2}{3}/", 6).contains("Repeat a group"));
+  assert!(query_to_filter("/(a{2}){3}/").is_ok());
+  let long = format!("/{}/", "a".repeat(MAX_REGEX_LEN + 1));
+  assert!(regex_error(&long, 1).contains("at most 256 characters"));
+  assert!(query_to_filter(&format!("/{}/", "a".repeat(MAX_REGEX_LEN))).is_ok());
+  // Syntax Postgres lacks or reads differently
+  assert!(regex_error(r"/\bword/", 2).contains("Assertions"));
+  assert!(regex_error(r"/\p{Greek}/", 2).contains("Unicode classes"));
+  assert!(regex_error("/(?i)abc/", 2).contains("Inline flags"));
+  assert!(regex_error("/(?P<n>a)/", 2).contains("Named groups"));
+  assert!(regex_error("/[a-z&&[^m]]/", 3).contains("Class set"));
+  assert!(regex_error(r"/(a)\1/", 5).contains("backreferences"));
+  // Empty and unterminated patterns do not parse at all
+  assert!(query_to_filter("//").is_err());
+  assert!(query_to_filter("a:/x").is_err());
+}
+
+#[test]
+fn test_needs_value() {
+  // Conditions on keys and times decide without the value
+  assert_eq!(without_value("secret_key:db*", "db_main"), Some(true));
+  assert_eq!(without_value("secret_key:db*", "cache"), Some(false));
+  assert_eq!(without_value("updated_at:<2026-01-01", "k"), Some(false));
+  assert_eq!(without_value("secret_key:[a TO b]", "c"), Some(false));
+  // A term on the key settles OR, a miss settles AND
+  assert_eq!(without_value("db", "db_main"), Some(true));
+  assert_eq!(without_value("db", "cache"), None);
+  assert_eq!(
+    without_value("secret_key:db* AND port:1", "cache"),
+    Some(false)
+  );
+  assert_eq!(without_value("secret_key:db* AND port:1", "db_main"), None);
+  assert_eq!(without_value("-port:1", "k"), None);
+  assert_eq!(
+    without_value("-(secret_key:db* OR port:1)", "db_main"),
+    Some(false)
+  );
+}
+
+#[test]
+fn test_reads_values() {
+  for raw in [
+    "",
+    "secret_key:db*",
+    "-secret_key:/^a/",
+    "updated_at:>2026-01-01 OR expires_at:[* TO *]",
+    "secret_key:[a TO m}",
+  ] {
+    assert!(!filter(raw).reads_values(), "{}", raw);
+  }
+  for raw in [
+    "db",
+    "port:1",
+    "secret_value:x",
+    "_exists_:a",
+    "secret_key:db* AND -/x/",
+    "port:[1 TO 2]",
+  ] {
+    assert!(filter(raw).reads_values(), "{}", raw);
+  }
 }
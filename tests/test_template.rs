@@ -0,0 +1,50 @@
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+use keyvault::template::Template;
+
+fn secrets() -> HashMap<String, Value> {
+  HashMap::from([
+    ("db/password".to_string(), json!("s3cr3t")),
+    (
+      "db".to_string(),
+      json!({"user": "app", "hosts": ["a", "b"], "tls": {"on": true}}),
+    ),
+  ])
+}
+
+#[test]
+fn test_render_secrets_and_json_paths() {
+  let template = Template::parse(
+    "password = \"{{ secret \"db/password\" }}\"\n\
+     user = {{secret \"db\"|json_path \"$.user\"}}\n\
+     replica = {{ secret \"db\" | json_path \"$.hosts[1]\" }}\n\
+     tls = {{ secret \"db\" | json_path \"$['tls']\" }}\n",
+  )
+  .unwrap();
+  assert_eq!(template.keys(), vec!["db", "db/password"]);
+  assert_eq!(
+    template.render(&secrets()).unwrap(),
+    "password = \"s3cr3t\"\nuser = app\nreplica = b\ntls = {\"on\":true}\n"
+  );
+}
+
+#[test]
+fn test_errors_point_at_the_template() {
+  let err = Template::parse("a\nb = {{ secert \"x\" }}").unwrap_err();
+  assert_eq!((err.line, err.column), (2, 8));
+  assert!(err.message.contains("'secert'"), "{}", err);
+
+  let err =
+    Template::parse("{{ secret \"x\" | json_path \"user\" }}").unwrap_err();
+  assert!(err.message.contains("must start with '$'"), "{}", err);
+
+  let err = Template::parse("{{ secret \"x\" ").unwrap_err();
+  assert!(err.message.contains("'}}'"), "{}", err);
+
+  let template =
+    Template::parse("x\n  {{ secret \"db\" | json_path \"$.pass\" }}").unwrap();
+  let err = template.render(&secrets()).unwrap_err();
+  assert_eq!((err.line, err.column), (2, 3));
+  assert!(err.message.contains("matches nothing"), "{}", err);
+}